@@ -0,0 +1,533 @@
+//! Adaptive-bitrate (ABR) video streaming over `TcpSender`. The client downloads chunks one at a
+//! time, and an ABR algorithm picks the bitrate of each chunk based on its buffer and past
+//! throughput
+
+use crate::config::{AbrConfig, VideoConfig};
+use crate::simulator::*;
+use crate::tracer::{TraceElem, Tracer};
+use crate::transport::TcpApp;
+
+use failure::{format_err, Error};
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Information available to an ABR algorithm when it picks the bitrate of the next chunk
+pub struct AbrInput<'b> {
+    /// Available bitrates (in bytes per second), in increasing order
+    pub bitrates: &'b [f64],
+    pub chunk_dur: Time,
+    /// Seconds of video in the client's buffer
+    pub buffer: f64,
+    /// Index of the bitrate of the previous chunk
+    pub last_bitrate: Option<usize>,
+    /// Throughput (in bytes per second) with which each chunk was downloaded, oldest first
+    pub throughputs: &'b [f64],
+    /// Number of chunks left, including this one. `None` if the video never ends
+    pub chunks_left: Option<u64>,
+}
+
+pub trait Abr: CloneAbr + SaveState {
+    /// Returns the index of the bitrate for the next chunk
+    fn choose(&mut self, input: &AbrInput) -> usize;
+}
+
+/// Lets us clone `Box<dyn Abr>`. Implemented for every `Abr` that is `Clone`
+pub trait CloneAbr {
+    fn clone_box(&self) -> Box<dyn Abr>;
+}
+
+impl<T: Abr + Clone + 'static> CloneAbr for T {
+    fn clone_box(&self) -> Box<dyn Abr> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn Abr> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// Index of the highest bitrate that is at most `rate`. The lowest bitrate if there is none
+fn highest_below(bitrates: &[f64], rate: f64) -> usize {
+    bitrates.iter().rposition(|b| *b <= rate).unwrap_or(0)
+}
+
+/// Harmonic mean of the last `window` values. `None` if there aren't any
+fn harmonic_mean(vals: &[f64], window: usize) -> Option<f64> {
+    let vals = &vals[vals.len().saturating_sub(window)..];
+    if vals.is_empty() {
+        None
+    } else {
+        Some(vals.len() as f64 / vals.iter().map(|x| 1. / x).sum::<f64>())
+    }
+}
+
+/// BBA: picks the bitrate based on the buffer occupancy alone
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BufferBased {
+    reservoir: Time,
+    cushion: Time,
+}
+
+impl_save_state!(BufferBased, RateBased, Mpc);
+
+impl Abr for BufferBased {
+    fn choose(&mut self, input: &AbrInput) -> usize {
+        let (min, max) = (input.bitrates[0], input.bitrates[input.bitrates.len() - 1]);
+        let frac = (input.buffer - self.reservoir.secs()) / self.cushion.secs();
+        if frac <= 0. {
+            0
+        } else if frac >= 1. {
+            input.bitrates.len() - 1
+        } else {
+            highest_below(input.bitrates, min + frac * (max - min))
+        }
+    }
+}
+
+/// Picks the bitrate based on recently observed throughput alone
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RateBased {
+    window: usize,
+    safety: f64,
+}
+
+impl Abr for RateBased {
+    fn choose(&mut self, input: &AbrInput) -> usize {
+        match harmonic_mean(input.throughputs, self.window) {
+            Some(tpt) => highest_below(input.bitrates, self.safety * tpt),
+            None => 0,
+        }
+    }
+}
+
+/// RobustMPC (Yin et al., SIGCOMM 2015). Predicts throughput as the harmonic mean of the last few
+/// chunks, discounted by the recent maximum prediction error, and picks the first bitrate of the
+/// sequence that maximizes QoE over the horizon
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Mpc {
+    horizon: usize,
+    rebuf_penalty: f64,
+    switch_penalty: f64,
+    /// The throughput we predicted for the last chunk
+    last_prediction: Option<f64>,
+    /// Relative errors of recent predictions
+    errors: VecDeque<f64>,
+}
+
+impl Mpc {
+    /// Number of past chunks over which throughput and errors are estimated
+    const WINDOW: usize = 5;
+
+    /// Best QoE achievable over the next `depth` chunks. Returns the QoE and the first bitrate
+    fn search(
+        &self,
+        input: &AbrInput,
+        tpt: f64,
+        depth: usize,
+        buffer: f64,
+        last: Option<usize>,
+    ) -> (f64, usize) {
+        let mut best = (f64::NEG_INFINITY, 0);
+        for (i, bitrate) in input.bitrates.iter().enumerate() {
+            let download_time = bitrate * input.chunk_dur.secs() / tpt;
+            let rebuf = (download_time - buffer).max(0.);
+            let new_buffer = (buffer - download_time).max(0.) + input.chunk_dur.secs();
+            let switch = last.map_or(0., |last| (bitrate - input.bitrates[last]).abs());
+            let mut qoe = bitrate - self.rebuf_penalty * rebuf - self.switch_penalty * switch;
+            if depth > 1 {
+                qoe += self.search(input, tpt, depth - 1, new_buffer, Some(i)).0;
+            }
+            if qoe > best.0 {
+                best = (qoe, i);
+            }
+        }
+        best
+    }
+}
+
+impl Abr for Mpc {
+    fn choose(&mut self, input: &AbrInput) -> usize {
+        // Record how wrong our last prediction was
+        if let (Some(pred), Some(actual)) = (self.last_prediction, input.throughputs.last()) {
+            self.errors.push_back((pred - actual).abs() / actual);
+            if self.errors.len() > Self::WINDOW {
+                self.errors.pop_front();
+            }
+        }
+
+        let tpt = if let Some(tpt) = harmonic_mean(input.throughputs, Self::WINDOW) {
+            tpt
+        } else {
+            return 0;
+        };
+        self.last_prediction = Some(tpt);
+        let max_error = self.errors.iter().cloned().fold(0., f64::max);
+        let tpt = tpt / (1. + max_error);
+
+        let depth = match input.chunks_left {
+            Some(left) => std::cmp::min(self.horizon as u64, left) as usize,
+            None => self.horizon,
+        };
+        self.search(
+            input,
+            tpt,
+            std::cmp::max(depth, 1),
+            input.buffer,
+            input.last_bitrate,
+        )
+        .1
+    }
+}
+
+/// A chunk that is being downloaded
+#[derive(Clone, Serialize, Deserialize)]
+struct Chunk {
+    /// Index of its bitrate
+    bitrate: usize,
+    /// When it was requested
+    start: Time,
+    /// Number of packets in it
+    num_pkts: u64,
+}
+
+/// A video streaming client that tells `TcpSender` which chunks to send
+#[derive(Clone)]
+pub struct VideoClient<'a> {
+    config: VideoConfig,
+    abr: Box<dyn Abr>,
+    /// Number of bytes in a packet
+    pkt_size: u64,
+    /// Total number of packets in all chunks requested so far
+    send_limit: SeqNum,
+    /// The chunk currently being downloaded
+    downloading: Option<Chunk>,
+    num_chunks_requested: u64,
+    /// Index of the bitrate of the last chunk requested
+    last_bitrate: Option<usize>,
+    /// Throughput with which each chunk was downloaded
+    throughputs: Vec<f64>,
+    /// Seconds of video in the buffer as of `last_update`
+    buffer: f64,
+    last_update: Time,
+    /// Whether the video is playing
+    playing: bool,
+    /// If playback has stalled (or not started yet), when that happened
+    stall_start: Option<Time>,
+    /// Whether playback has ever started
+    started: bool,
+    tracer: &'a Tracer<'a>,
+}
+
+impl<'a> VideoClient<'a> {
+    pub fn new(config: VideoConfig, pkt_size: u64, tracer: &'a Tracer) -> Result<Self, Error> {
+        if config.bitrates.is_empty() {
+            return Err(format_err!("Video must have at least one bitrate"));
+        }
+        if config.bitrates.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format_err!("Video bitrates must be in increasing order"));
+        }
+        if config.chunk_dur == Time::ZERO {
+            return Err(format_err!("Video chunk duration must be positive"));
+        }
+        let abr: Box<dyn Abr> = match config.abr {
+            AbrConfig::BufferBased { reservoir, cushion } => {
+                Box::new(BufferBased { reservoir, cushion })
+            }
+            AbrConfig::RateBased { window, safety } => Box::new(RateBased { window, safety }),
+            AbrConfig::Mpc {
+                horizon,
+                rebuf_penalty,
+                switch_penalty,
+            } => Box::new(Mpc {
+                horizon,
+                rebuf_penalty,
+                switch_penalty,
+                last_prediction: None,
+                errors: VecDeque::new(),
+            }),
+        };
+        Ok(Self {
+            config,
+            abr,
+            pkt_size,
+            send_limit: 0,
+            downloading: None,
+            num_chunks_requested: 0,
+            last_bitrate: None,
+            throughputs: Vec::new(),
+            buffer: 0.,
+            last_update: Time::ZERO,
+            playing: false,
+            stall_start: None,
+            started: false,
+            tracer,
+        })
+    }
+
+    /// Whether all chunks of the video have been requested
+    fn all_requested(&self) -> bool {
+        match self.config.num_chunks {
+            Some(num_chunks) => self.num_chunks_requested >= num_chunks,
+            None => false,
+        }
+    }
+
+    /// Play the video from `last_update` till `now`, and note if the buffer ran out
+    fn advance(&mut self, now: Time) {
+        let dt = (now - self.last_update).secs();
+        let last_update = self.last_update;
+        self.last_update = now;
+        if !self.playing {
+            return;
+        }
+        if self.buffer > dt {
+            self.buffer -= dt;
+            return;
+        }
+        self.playing = false;
+        // If the whole video has been downloaded, it simply ended
+        if self.downloading.is_some() || !self.all_requested() {
+            self.stall_start = Some(last_update + Time::from_micros((self.buffer * 1e6) as u64));
+        }
+        self.buffer = 0.;
+    }
+}
+
+impl<'a> SaveState for VideoClient<'a> {
+    save_fields!(
+        send_limit,
+        downloading,
+        num_chunks_requested,
+        last_bitrate,
+        throughputs,
+        buffer,
+        last_update,
+        playing,
+        stall_start,
+        started;
+        abr
+    );
+}
+
+impl<'a> TcpApp for VideoClient<'a> {
+    fn send_limit(&self) -> SeqNum {
+        self.send_limit
+    }
+
+    fn on_delivered(&mut self, obj_id: NetObjId, now: Time, cum_ack: SeqNum) -> Option<Time> {
+        if self.downloading.is_none() || cum_ack < self.send_limit {
+            return None;
+        }
+        // The chunk has been downloaded
+        let chunk = self.downloading.take().unwrap();
+        self.advance(now);
+        self.buffer += self.config.chunk_dur.secs();
+        let bytes = chunk.num_pkts * self.pkt_size;
+        self.throughputs
+            .push(bytes as f64 / std::cmp::max(now - chunk.start, Time::from_micros(1)).secs());
+        let bitrate = self.config.bitrates[chunk.bitrate];
+        self.tracer
+            .log(obj_id, now, TraceElem::VideoChunk(bitrate as u64));
+
+        if !self.playing
+            && (self.buffer >= self.config.startup_buffer.secs() || self.all_requested())
+        {
+            self.playing = true;
+            if let Some(stall_start) = self.stall_start.take() {
+                let elem = if self.started {
+                    TraceElem::VideoRebuffer(now - stall_start)
+                } else {
+                    TraceElem::VideoStartup(now - stall_start)
+                };
+                self.tracer.log(obj_id, now, elem);
+            }
+            self.started = true;
+        }
+
+        if self.all_requested() {
+            return None;
+        }
+        // Request the next chunk once there is space for it in the buffer
+        let space = self.config.max_buffer.secs() - self.config.chunk_dur.secs();
+        if self.playing && self.buffer > space {
+            Some(now + Time::from_micros(((self.buffer - space) * 1e6) as u64))
+        } else {
+            Some(now)
+        }
+    }
+
+    fn on_wakeup(&mut self, obj_id: NetObjId, now: Time) -> Option<Time> {
+        if self.num_chunks_requested == 0 {
+            // The session starts now
+            self.last_update = now;
+            self.stall_start = Some(now);
+        }
+        self.advance(now);
+        if self.downloading.is_some() || self.all_requested() {
+            return None;
+        }
+
+        let bitrate = self.abr.choose(&AbrInput {
+            bitrates: &self.config.bitrates,
+            chunk_dur: self.config.chunk_dur,
+            buffer: self.buffer,
+            last_bitrate: self.last_bitrate,
+            throughputs: &self.throughputs,
+            chunks_left: self
+                .config
+                .num_chunks
+                .map(|n| n - self.num_chunks_requested),
+        });
+        if self.last_bitrate.is_some() && self.last_bitrate != Some(bitrate) {
+            self.tracer.log(obj_id, now, TraceElem::VideoBitrateSwitch);
+        }
+        self.last_bitrate = Some(bitrate);
+
+        let bytes = self.config.bitrates[bitrate] * self.config.chunk_dur.secs();
+        let num_pkts = std::cmp::max(1, (bytes / self.pkt_size as f64).ceil() as u64);
+        self.send_limit += num_pkts;
+        self.num_chunks_requested += 1;
+        self.downloading = Some(Chunk {
+            bitrate,
+            start: now,
+            num_pkts,
+        });
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const BITRATES: [f64; 4] = [100_000., 200_000., 300_000., 400_000.];
+
+    fn input<'b>(buffer: f64, last_bitrate: Option<usize>, throughputs: &'b [f64]) -> AbrInput<'b> {
+        AbrInput {
+            bitrates: &BITRATES,
+            chunk_dur: Time::from_secs(4),
+            buffer,
+            last_bitrate,
+            throughputs,
+            chunks_left: None,
+        }
+    }
+
+    fn mpc(horizon: usize, rebuf_penalty: f64, switch_penalty: f64) -> Mpc {
+        Mpc {
+            horizon,
+            rebuf_penalty,
+            switch_penalty,
+            last_prediction: None,
+            errors: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn buffer_based_choices() {
+        let mut bba = BufferBased {
+            reservoir: Time::from_secs(5),
+            cushion: Time::from_secs(10),
+        };
+        assert_eq!(bba.choose(&input(2., None, &[])), 0);
+        assert_eq!(bba.choose(&input(20., None, &[])), 3);
+        // Halfway through the cushion maps to 250 KB/s
+        assert_eq!(bba.choose(&input(10., None, &[])), 1);
+    }
+
+    #[test]
+    fn rate_based_choices() {
+        let mut rb = RateBased {
+            window: 2,
+            safety: 0.9,
+        };
+        assert_eq!(rb.choose(&input(0., None, &[])), 0);
+        // Only the last two chunks count, and 0.9 * 300 KB/s only fits 200 KB/s
+        assert_eq!(rb.choose(&input(0., None, &[1e6, 300_000., 300_000.])), 1);
+        assert_eq!(rb.choose(&input(0., None, &[1e6, 1e6])), 3);
+    }
+
+    #[test]
+    fn mpc_choices() {
+        let tpt = [250_000.];
+        // With one chunk in the buffer, anything above the throughput rebuffers
+        assert_eq!(mpc(1, 1e6, 0.).choose(&input(4., None, &tpt)), 1);
+        // With plenty of buffer, the highest bitrate doesn't
+        assert_eq!(mpc(3, 1e6, 0.).choose(&input(30., None, &tpt)), 3);
+        // Unless switching costs too much
+        assert_eq!(mpc(3, 1e6, 10.).choose(&input(30., Some(0), &tpt)), 0);
+        // Looking further ahead, it is worth starting low to build up the buffer for later
+        assert_eq!(mpc(3, 1e6, 0.).choose(&input(4., None, &tpt)), 0);
+
+        // The throughput was half of what was predicted, so the prediction (267 KB/s) is halved
+        // from now on
+        let mut abr = mpc(1, 1e6, 0.);
+        assert_eq!(abr.choose(&input(30., None, &[400_000.])), 3);
+        assert_eq!(abr.choose(&input(4., None, &[400_000., 200_000.])), 0);
+    }
+
+    #[test]
+    fn startup_and_rebuffer_stats() {
+        let config: Config = serde_yaml::from_str(
+            "pkt_size: 1000
+sim_dur: null
+random_seed: 0
+log:
+  out_terminal: png
+  out_file: out.png
+  cwnd: Ignore
+  rtt: Ignore
+  sender_losses: Ignore
+  timeouts: Ignore
+  link_rates: Ignore
+  stats_intervals: [[0, ~]]
+  stats_file: null
+  link_bucket_size: 1s
+topo:
+  link:
+    Const: 1000000
+  bufsize: Infinite
+",
+        )
+        .unwrap();
+        let tracer = Tracer::new(&config);
+        let video = VideoConfig {
+            bitrates: vec![100_000., 200_000.],
+            chunk_dur: Time::from_secs(1),
+            num_chunks: Some(4),
+            max_buffer: Time::from_secs(10),
+            startup_buffer: Time::from_secs(2),
+            abr: AbrConfig::RateBased {
+                window: 1,
+                safety: 1.,
+            },
+        };
+        let mut client = VideoClient::new(video, 1000, &tracer).unwrap();
+        // Each chunk is 100 packets. The first two take 1s each, so playback starts at 2s. The
+        // third takes 3s, so the buffer runs out at 4s, and playback resumes when the fourth
+        // arrives at 6s
+        let secs = Time::from_secs;
+        let deliveries = [(secs(1), Some(secs(1))), (secs(2), Some(secs(2)))];
+        assert_eq!(client.on_wakeup(0, secs(0)), None);
+        for (i, (now, wakeup)) in deliveries.iter().enumerate() {
+            let cum_ack = 100 * (i as u64 + 1);
+            assert_eq!(client.send_limit(), cum_ack);
+            assert_eq!(client.on_delivered(0, *now, cum_ack), *wakeup);
+            client.on_wakeup(0, *now);
+        }
+        assert_eq!(client.on_delivered(0, secs(5), 300), Some(secs(5)));
+        client.on_wakeup(0, secs(5));
+        assert_eq!(client.on_delivered(0, secs(6), 400), None);
+
+        let stats = &tracer.stats()["sender_stats"]["0"][0];
+        assert_eq!(stats["num_video_chunks"], 4);
+        assert_eq!(stats["startup_delay"], 2_000_000);
+        assert_eq!(stats["num_rebuffers"], 1);
+        assert_eq!(stats["rebuffer_time"], 2_000_000);
+        assert_eq!(stats["num_bitrate_switches"], 0);
+    }
+}
@@ -1,17 +1,22 @@
-use crate::config::{Config, LinkTraceConfig};
-use crate::random::RandomVariable;
+use crate::config::{
+    CellularLinkConfig, Config, LinkEventsConfig, LinkTraceConfig, MarkovLinkState,
+    OutageQueuePolicy, PathSwitchConfig, RandomOutagesConfig, WifiConfig,
+};
+use crate::random::{rng_stream, RandomVariable, RngStream};
 use crate::simulator::*;
+use crate::trace::{Trace, TraceFormat};
 use crate::tracer::{TraceElem, Tracer};
 
 // External dependencies.
 use failure::{format_err, Error};
 use fnv::FnvHashMap;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::path::Path;
-use std::rc::Rc;
 
 /// A router with configurable routes
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Router {
     /// Address of this router
     addr: Addr,
@@ -43,9 +48,11 @@ impl Router {
     }
 }
 
+impl_save_state!(Router, Delay, Aggregator);
+
 impl NetObj for Router {
-    fn init(&mut self, _: NetObjId, _: Time) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(Vec::new())
+    fn init(&mut self, _: NetObjId, _: Time, _: &mut Actions) -> Result<(), Error> {
+        Ok(())
     }
 
     fn push(
@@ -53,15 +60,17 @@ impl NetObj for Router {
         _obj_id: NetObjId,
         _from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
         if pkt.dest == self.addr {
             // Weird, let's just print it
             println!("Packet {:?} received at router.", pkt);
-            return Ok(Vec::new());
+            return Ok(());
         }
         if let Some(port) = self.routes.get(&pkt.dest) {
-            Ok(vec![(now, self.ports[*port], Action::Push(pkt))])
+            out.push((now, self.ports[*port], Action::Push(pkt)));
+            Ok(())
         } else {
             Err(format_err!(
                 "Packet's destination address '{:?}' does not exist in routing table",
@@ -76,19 +85,34 @@ impl NetObj for Router {
         _: NetObjId,
         _: Time,
         _: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        _: &mut Actions,
+    ) -> Result<(), Error> {
         unreachable!()
     }
+
+    fn add_route_to(&mut self, dest: Addr, next: NetObjId) -> Result<(), Error> {
+        let port = match self.ports.iter().position(|port| *port == next) {
+            Some(port) => port,
+            None => self.add_port(next),
+        };
+        self.add_route(dest, port);
+        Ok(())
+    }
 }
 
-/// Link speed as a function of time.
-pub enum LinkTrace<'c> {
+/// Link speed as a function of time. `pkt_size` is the size of the packets the link delivers
+#[derive(Clone, Serialize, Deserialize)]
+pub enum LinkTrace {
     /// A constant link rate in bytes per second
     #[allow(dead_code)]
-    Const { rate: f64, config: &'c Config },
+    Const { rate: f64, pkt_size: u64 },
     /// Inter-send time (in microseconds) is sampled from the given distribution.
     #[allow(dead_code)]
-    Random { intersend: RandomVariable },
+    Random {
+        intersend: RandomVariable,
+        /// Boxed since it is much larger than the other variants
+        rng: Box<RngStream>,
+    },
     /// A piecewise-constant link rate. Give the rate and duration for which it applies in bytes
     /// per second. Loops after it reaches the end.
     #[allow(dead_code)]
@@ -98,140 +122,577 @@ pub enum LinkTrace<'c> {
         cur_id: usize,
         /// When to switch to the next rate
         next_switch: Time,
-        config: &'c Config,
+        /// Transmit times are rounded to the microsecond. These many bytes of capacity were given
+        /// early by the last one due to rounding, and are owed by the next one (negative if it
+        /// was late)
+        owed: f64,
+        pkt_size: u64,
     },
     #[allow(dead_code)]
     /// A mahimahi-like trace (it also handles floating-point values)
-    Mahimahi { trace: Vec<Time>, next_id: usize },
+    Mahimahi {
+        trace: Trace,
+        /// The next opportunity to return, in the loop of the trace that started at `loop_start`
+        next_id: usize,
+        loop_start: Time,
+    },
+    /// Markov-modulated link rate. The rate changes with the state, even in the middle of a
+    /// packet
+    Markov {
+        states: Vec<MarkovLinkState>,
+        cur_state: usize,
+        /// The state to move to at `next_switch`
+        next_state: usize,
+        next_switch: Time,
+        /// Capacity owed due to rounding transmit times, as in `Piecewise`
+        owed: f64,
+        rng: Box<RngStream>,
+        pkt_size: u64,
+    },
+    /// Sprout's model of a cellular link. The rate changes every tick and packets are delivered
+    /// as a Poisson process
+    Cellular {
+        params: CellularLinkConfig,
+        /// The current rate in bytes per second. 0 means we are in an outage
+        rate: f64,
+        /// When the rate changes next (or, in an outage, when it ends)
+        next_update: Time,
+        rng: Box<RngStream>,
+        pkt_size: u64,
+    },
 }
 
-impl<'c> LinkTrace<'c> {
+impl LinkTrace {
     // New link with constant rate.
     #[allow(dead_code)]
-    pub fn new_const(rate: f64, config: &'c Config) -> Self {
-        Self::Const { rate, config }
+    pub fn new_const(rate: f64, config: &Config) -> Self {
+        Self::Const {
+            rate,
+            pkt_size: config.pkt_size,
+        }
     }
 
     // New link with link rate following a random distirbution.
     #[allow(dead_code)]
-    pub fn new_random(intersend: RandomVariable) -> Self {
-        Self::Random { intersend }
+    pub fn new_random(intersend: RandomVariable, rng: RngStream) -> Self {
+        Self::Random {
+            intersend,
+            rng: Box::new(rng),
+        }
     }
 
     /// A piecewise constant link rate trace. Give a list of rates along with how long they should
     /// last. Loops after it reaches the end
     #[allow(dead_code)]
-    pub fn new_piecewise(rates: &[(f64, Time)], config: &'c Config) -> Self {
+    pub fn new_piecewise(rates: &[(f64, Time)], config: &Config) -> Self {
         assert!(!rates.is_empty());
         Self::Piecewise {
             next_switch: rates[0].1,
             rates: rates.to_vec(),
             cur_id: 0,
-            config,
+            owed: 0.,
+            pkt_size: config.pkt_size,
         }
     }
 
     /// Create a trace reading from a mahimahi-like trace file (also supports floating point)
     #[allow(dead_code)]
     pub fn new_mahimahi_from_file(tracefile: &std::path::Path) -> Result<Self, Error> {
-        // Read the trace file
-        use std::fs::File;
-        use std::io::{BufRead, BufReader};
-        let file = BufReader::new(File::open(tracefile)?);
-        let mut trace = Vec::new();
-        let mut last_ts = Time::from_micros(0);
-        for line in file.lines() {
-            let line = line?;
-            let ts = Time::from_micros((line.parse::<f64>()? * 1000.) as u64);
-            if ts < last_ts {
-                return Err(format_err!(
-                    "Error: tracefile is not monotonic at line {}",
-                    trace.len()
-                ));
-            }
-            trace.push(ts);
-            last_ts = ts;
+        let fname = tracefile
+            .to_str()
+            .ok_or_else(|| format_err!("invalid file name {:?}", tracefile))?;
+        Self::new_from_trace_file(fname, TraceFormat::Mahimahi, 1500)
+    }
+
+    /// Create a trace reading from a trace file in any of the supported formats. `pkt_size` is the
+    /// size of packets the link delivers
+    pub fn new_from_trace_file(
+        fname: &str,
+        format: TraceFormat,
+        pkt_size: u64,
+    ) -> Result<Self, Error> {
+        Self::new_trace(Trace::from_file(fname, format, pkt_size)?)
+    }
+
+    /// Play back the given trace, which loops after its duration
+    pub fn new_trace(trace: Trace) -> Result<Self, Error> {
+        if trace.opportunities.is_empty() || trace.duration == Time::ZERO {
+            return Err(format_err!(
+                "trace must have a delivery opportunity and last longer than 0"
+            ));
+        }
+        Ok(Self::Mahimahi {
+            trace,
+            next_id: 0,
+            loop_start: Time::ZERO,
+        })
+    }
+
+    /// A Markov-modulated link that starts in the given state
+    pub fn new_markov(
+        states: &[MarkovLinkState],
+        initial_state: usize,
+        rng: RngStream,
+        config: &Config,
+    ) -> Self {
+        assert!(initial_state < states.len());
+        let mut rng = Box::new(rng);
+        let (next_state, next_switch) =
+            markov_transition(&states[initial_state], Time::ZERO, &mut rng);
+        Self::Markov {
+            states: states.to_vec(),
+            cur_state: initial_state,
+            next_state,
+            next_switch,
+            owed: 0.,
+            rng,
+            pkt_size: config.pkt_size,
         }
+    }
 
-        Ok(Self::Mahimahi { trace, next_id: 0 })
+    /// A cellular-like link following Sprout's model
+    pub fn new_cellular(params: &CellularLinkConfig, rng: RngStream, config: &Config) -> Self {
+        Self::Cellular {
+            rate: params.initial_rate,
+            next_update: params.tick,
+            params: params.clone(),
+            rng: Box::new(rng),
+            pkt_size: config.pkt_size,
+        }
     }
 
-    /// Produces a LinkTrace from LinkTraceConfig and a separately provided Config
-    pub fn from_config(link_config: &LinkTraceConfig, config: &'c Config) -> Result<Self, Error> {
+    /// Produces a LinkTrace from LinkTraceConfig and a separately provided Config. `name` is that
+    /// of the link, which identifies its random number stream
+    pub fn from_config(
+        link_config: &LinkTraceConfig,
+        name: &str,
+        config: &Config,
+    ) -> Result<Self, Error> {
         Ok(match link_config {
             LinkTraceConfig::Const(rate) => Self::new_const(*rate, config),
-            LinkTraceConfig::Random(rate) => Self::new_random(*rate),
+            LinkTraceConfig::Random(rate) => {
+                Self::new_random(rate.clone(), rng_stream(config.random_seed, name))
+            }
             LinkTraceConfig::Piecewise(rates) => Self::new_piecewise(rates, config),
             LinkTraceConfig::MahimahiFile(fname) => Self::new_mahimahi_from_file(Path::new(fname))?,
+            LinkTraceConfig::TraceFile { file, format } => {
+                Self::new_from_trace_file(file, *format, config.pkt_size)?
+            }
+            LinkTraceConfig::Markov {
+                states,
+                initial_state,
+            } => Self::new_markov(
+                states,
+                *initial_state,
+                rng_stream(config.random_seed, name),
+                config,
+            ),
+            LinkTraceConfig::Cellular(params) => {
+                Self::new_cellular(params, rng_stream(config.random_seed, name), config)
+            }
         })
     }
 
+    /// Give the first transmit time when the link starts using this trace at `now`. Traces start
+    /// playing from their beginning then, while other links can transmit right away
+    fn first_tx(&mut self, now: Time) -> Time {
+        match self {
+            Self::Piecewise {
+                rates,
+                cur_id,
+                next_switch,
+                owed,
+                ..
+            } => {
+                *cur_id = 0;
+                *next_switch = now + rates[0].1;
+                *owed = 0.;
+                self.next_tx(now)
+            }
+            Self::Mahimahi {
+                next_id,
+                loop_start,
+                ..
+            } => {
+                *next_id = 0;
+                *loop_start = now;
+                self.next_tx(now)
+            }
+            _ => now,
+        }
+    }
+
     /// Give the next scheduled transmit time assuming full-sized packets are used. Expects `now`
     /// to be non-decreasing
     fn next_tx(&mut self, now: Time) -> Time {
         match self {
-            Self::Const { rate, config } => Time::from_micros(
-                (now.micros() as f64 + (1_000_000. * config.pkt_size as f64 / *rate)) as u64,
+            Self::Const { rate, pkt_size } => Time::from_micros(
+                (now.micros() as f64 + (1_000_000. * *pkt_size as f64 / *rate)) as u64,
             ),
-            Self::Random { intersend } => now + Time::from_micros(intersend.sample() as u64),
+            Self::Random { intersend, rng } => {
+                now + Time::from_micros(intersend.sample(rng) as u64)
+            }
             Self::Piecewise {
                 rates,
                 cur_id,
                 next_switch,
-                config,
+                owed,
+                pkt_size,
             } => {
-                if now > *next_switch {
+                while now >= *next_switch {
+                    *cur_id = (*cur_id + 1) % rates.len();
+                    *next_switch = *next_switch + rates[*cur_id].1;
+                }
+                // Integrate the rate from `now` over as many segments as it takes for a packet's
+                // worth of bytes to be delivered. Times are in microseconds
+                let mut need = *pkt_size as f64 + *owed;
+                let mut t = now.micros() as f64;
+                loop {
+                    let rate = rates[*cur_id].0 / 1e6;
+                    let end = next_switch.micros() as f64;
+                    if rate > 0. && t + need / rate <= end {
+                        let exact = t + need / rate;
+                        *owed = (exact - exact.round()) * rate;
+                        return Time::from_micros(exact.round() as u64);
+                    }
+                    need -= rate * (end - t);
+                    t = end;
                     *cur_id = (*cur_id + 1) % rates.len();
                     *next_switch = *next_switch + rates[*cur_id].1;
                 }
-                let rate = rates[*cur_id].0;
-                Time::from_micros(
-                    (now.micros() as f64 + (1_000_000. * config.pkt_size as f64 / rate)) as u64,
-                )
             }
-            Self::Mahimahi { trace, next_id } => {
-                let prev_id = if *next_id > 0 { *next_id - 1 } else { 0 };
-                let next_tx_time = now + trace[*next_id] - trace[prev_id];
-                *next_id = (*next_id + 1) % trace.len();
-                next_tx_time
+            Self::Mahimahi {
+                trace,
+                next_id,
+                loop_start,
+            } => {
+                // Skip opportunities that were missed, e.g. during an outage
+                loop {
+                    let next_tx_time = *loop_start + trace.opportunities[*next_id];
+                    *next_id += 1;
+                    if *next_id == trace.opportunities.len() {
+                        *next_id = 0;
+                        *loop_start = *loop_start + trace.duration;
+                    }
+                    if next_tx_time >= now {
+                        return next_tx_time;
+                    }
+                }
+            }
+            Self::Markov {
+                states,
+                cur_state,
+                next_state,
+                next_switch,
+                owed,
+                rng,
+                pkt_size,
+            } => {
+                // Move through the states whose time has come
+                while now >= *next_switch {
+                    *cur_state = *next_state;
+                    (*next_state, *next_switch) =
+                        markov_transition(&states[*cur_state], *next_switch, rng);
+                }
+                // Like for `Piecewise`, integrate the rate over as many states as it takes for a
+                // packet's worth of bytes to be delivered. Times are in microseconds
+                let mut need = *pkt_size as f64 + *owed;
+                let mut t = now.micros() as f64;
+                loop {
+                    let rate = states[*cur_state].rate / 1e6;
+                    let end = next_switch.micros() as f64;
+                    if rate > 0. && (*next_switch == Time::MAX || t + need / rate <= end) {
+                        let exact = t + need / rate;
+                        *owed = (exact - exact.round()) * rate;
+                        return Time::from_micros(exact.round() as u64);
+                    }
+                    if *next_switch == Time::MAX {
+                        // Stuck in a state with zero rate
+                        return Time::MAX;
+                    }
+                    need -= rate * (end - t);
+                    t = end;
+                    *cur_state = *next_state;
+                    (*next_state, *next_switch) =
+                        markov_transition(&states[*cur_state], *next_switch, rng);
+                }
+            }
+            Self::Cellular {
+                params,
+                rate,
+                next_update,
+                rng,
+                pkt_size,
+            } => {
+                let mut t = now;
+                loop {
+                    while t >= *next_update {
+                        let step = RandomVariable::Normal {
+                            mean: 0.,
+                            std_dev: params.volatility * params.tick.secs().sqrt(),
+                        }
+                        .sample(rng);
+                        if *rate <= 0. {
+                            // Leaving an outage. The walk starts again from 0
+                            *rate = step.abs();
+                        } else {
+                            *rate += step;
+                        }
+                        // Reflect off the maximum rate
+                        if *rate > params.max_rate {
+                            *rate = 2. * params.max_rate - *rate;
+                        }
+                        if *rate <= 0. {
+                            *rate = 0.;
+                            let outage =
+                                RandomVariable::Exponential(params.outage_escape_rate).sample(rng);
+                            *next_update = *next_update + Time::from_micros((outage * 1e6) as u64);
+                        } else {
+                            *next_update = *next_update + params.tick;
+                        }
+                    }
+                    if *rate <= 0. && params.volatility <= 0. {
+                        // The rate can never change
+                        return Time::MAX;
+                    }
+                    // Packets are delivered as a Poisson process. Since it is memoryless, if the
+                    // rate changes before the next delivery, we can just sample again from then
+                    if *rate > 0. {
+                        let pkts_per_us = *rate / *pkt_size as f64 / 1e6;
+                        let gap = RandomVariable::Exponential(pkts_per_us).sample(rng);
+                        let next_tx_time = t + Time::from_micros(gap as u64);
+                        if next_tx_time < *next_update {
+                            return next_tx_time;
+                        }
+                    }
+                    t = *next_update;
+                }
             }
         }
     }
 }
 
+/// Sample which state a Markov-modulated link in `state` (since `now`) moves to next, and when.
+/// The time is `Time::MAX` if it never leaves
+fn markov_transition(state: &MarkovLinkState, now: Time, rng: &mut RngStream) -> (usize, Time) {
+    let mut res = (0, Time::MAX);
+    for (next, rate) in &state.transitions {
+        if *rate <= 0. {
+            continue;
+        }
+        let after = RandomVariable::Exponential(*rate).sample(rng);
+        let after = now + Time::from_micros((after * 1e6) as u64);
+        if after < res.1 {
+            res = (*next, after);
+        }
+    }
+    res
+}
+
 /// Size of a Buffer.
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema)]
 pub enum BufferSize {
     Finite(usize),
+    #[default]
     Infinite,
 }
 
+/// A change in a link's state. See `LinkEventsConfig`
+#[derive(Clone, Serialize, Deserialize)]
+enum LinkEvent {
+    OutageStart,
+    OutageEnd,
+    /// Switch to a path with this link trace and delay (if they change)
+    PathSwitch(Option<LinkTrace>, Option<Time>),
+}
+
+/// The outages and path switches of a `Link`, which it goes through in order of time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LinkEvents {
+    /// Events at fixed times, sorted by time. Those before `next_id` have happened
+    scheduled: Vec<(Time, LinkEvent)>,
+    next_id: usize,
+    /// Name of the link, which identifies the random number streams of its path switches
+    name: String,
+    num_path_switches: usize,
+    /// Parameters of random outages, along with the random number stream for them
+    random: Option<(RandomOutagesConfig, Box<RngStream>)>,
+    /// When the next random outage starts (or, if one is in progress, ends)
+    next_random: Time,
+    in_random_outage: bool,
+    /// Number of outages in progress. They may overlap
+    num_outages: usize,
+    policy: OutageQueuePolicy,
+}
+
+impl LinkEvents {
+    /// Produces the events from config. `name` is that of the link, which identifies its random
+    /// number streams
+    pub fn from_config(
+        events: &LinkEventsConfig,
+        name: &str,
+        config: &Config,
+    ) -> Result<Self, Error> {
+        let mut scheduled = Vec::new();
+        for (start, dur) in &events.outages {
+            scheduled.push((*start, LinkEvent::OutageStart));
+            scheduled.push((*start + *dur, LinkEvent::OutageEnd));
+        }
+        for (i, switch) in events.path_switches.iter().enumerate() {
+            scheduled.extend(Self::path_switch_events(switch, name, i, config)?);
+        }
+        // Stable, so events at the same time happen in the order above
+        scheduled.sort_by_key(|(time, _)| *time);
+
+        let mut random = None;
+        let mut next_random = Time::MAX;
+        if let Some(random_outages) = &events.random_outages {
+            let mut rng = Box::new(rng_stream(config.random_seed, &format!("{}-outages", name)));
+            next_random = Time::from_micros(random_outages.interval.sample(&mut rng) as u64);
+            random = Some((random_outages.clone(), rng));
+        }
+
+        Ok(Self {
+            scheduled,
+            next_id: 0,
+            name: name.to_string(),
+            num_path_switches: events.path_switches.len(),
+            random,
+            next_random,
+            in_random_outage: false,
+            num_outages: 0,
+            policy: events.outage_queue,
+        })
+    }
+
+    /// The events that make up the `i`th path switch of link `name`
+    fn path_switch_events(
+        switch: &PathSwitchConfig,
+        name: &str,
+        i: usize,
+        config: &Config,
+    ) -> Result<Vec<(Time, LinkEvent)>, Error> {
+        let link_trace = match &switch.link {
+            Some(link) => Some(LinkTrace::from_config(
+                link,
+                &format!("{}-switch{}", name, i),
+                config,
+            )?),
+            None => None,
+        };
+        let mut events = Vec::new();
+        if switch.interruption > Time::ZERO {
+            events.push((switch.time, LinkEvent::OutageStart));
+            events.push((switch.time + switch.interruption, LinkEvent::OutageEnd));
+        }
+        events.push((switch.time, LinkEvent::PathSwitch(link_trace, switch.delay)));
+        Ok(events)
+    }
+
+    /// Add a path switch after those given in the config. It must not be before the events that
+    /// have already happened
+    fn add_path_switch(&mut self, switch: &PathSwitchConfig, config: &Config) -> Result<(), Error> {
+        let events = Self::path_switch_events(switch, &self.name, self.num_path_switches, config)?;
+        self.num_path_switches += 1;
+        for (time, event) in events {
+            // After the events at the same time, as if it had been in the config
+            let pos =
+                self.next_id + self.scheduled[self.next_id..].partition_point(|(t, _)| *t <= time);
+            self.scheduled.insert(pos, (time, event));
+        }
+        Ok(())
+    }
+
+    /// When the next event happens, if there is one
+    fn next_time(&self) -> Option<Time> {
+        let scheduled = self.scheduled.get(self.next_id).map(|(time, _)| *time);
+        let random = if self.random.is_some() {
+            Some(self.next_random)
+        } else {
+            None
+        };
+        match (scheduled, random) {
+            (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Returns the next event if it happens at or before `now`, and updates whether we are in an
+    /// outage
+    fn pop(&mut self, now: Time) -> Option<LinkEvent> {
+        let next_scheduled = self.scheduled.get(self.next_id).map(|(time, _)| *time);
+        let event = if next_scheduled.is_some_and(|t| t <= now && t <= self.next_random) {
+            self.next_id += 1;
+            self.scheduled[self.next_id - 1].1.clone()
+        } else if let Some((random, rng)) = &mut self.random {
+            if self.next_random > now {
+                return None;
+            }
+            let (sample, event) = if self.in_random_outage {
+                (random.interval.sample(rng), LinkEvent::OutageEnd)
+            } else {
+                (random.duration.sample(rng), LinkEvent::OutageStart)
+            };
+            self.in_random_outage = !self.in_random_outage;
+            self.next_random = self.next_random + Time::from_micros(sample as u64);
+            event
+        } else {
+            return None;
+        };
+        match event {
+            LinkEvent::OutageStart => self.num_outages += 1,
+            LinkEvent::OutageEnd => self.num_outages -= 1,
+            LinkEvent::PathSwitch(..) => {}
+        }
+        Some(event)
+    }
+
+    pub fn in_outage(&self) -> bool {
+        self.num_outages > 0
+    }
+}
+
 /// A link whose rate can be configured with LinkTrace
 #[allow(dead_code)]
+#[derive(Clone)]
 pub struct Link<'a> {
     /// This tells us of transmit opportunities
-    link_trace: LinkTrace<'a>,
+    link_trace: LinkTrace,
     /// Maximum number of packets that can be buffered.
     bufsize: BufferSize,
     /// The next hop which will receve packets
     next: NetObjId,
     /// The packets currently in the link (either queued or being served)
-    buffer: VecDeque<Rc<Packet>>,
+    buffer: VecDeque<Packet>,
     /// To trace link events
     tracer: &'a Tracer<'a>,
     config: &'a Config,
+    /// Outages and path switches
+    events: LinkEvents,
+    /// Delay added to packets after they are transmitted, which depends on the current path
+    delay: Time,
+    /// When the last packet transmitted reaches the next hop. Packets never arrive before it, so
+    /// that they stay in order when a path switch lowers the delay
+    last_arrival: Time,
+    /// Transmit opportunities are events with this uid. It changes whenever the pending
+    /// opportunity is cancelled (e.g. due to an outage), so that we ignore it when it fires
+    tx_uid: u64,
 }
 
+/// Uid of the events for `LinkEvents`. Those for transmit opportunities count up from 0
+const LINK_EVENT_UID: u64 = u64::MAX;
+
 #[allow(dead_code)]
 impl<'a> Link<'a> {
     /// Link rate in bytes/sec and buffer size in packets (if `None`, buffer is infinite)
     pub fn new(
-        link_trace: LinkTrace<'a>,
+        link_trace: LinkTrace,
         bufsize: BufferSize,
         next: NetObjId,
         tracer: &'a Tracer,
         config: &'a Config,
+        events: LinkEvents,
     ) -> Self {
         Self {
             link_trace,
@@ -240,17 +701,65 @@ impl<'a> Link<'a> {
             buffer: Default::default(),
             tracer,
             config,
+            events,
+            delay: Time::ZERO,
+            last_arrival: Time::ZERO,
+            tx_uid: 0,
+        }
+    }
+
+    /// Apply the link events that happen at or before `now`
+    fn link_events(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) {
+        while let Some(event) = self.events.pop(now) {
+            match event {
+                LinkEvent::OutageStart => {
+                    // Cancel the pending transmit opportunity
+                    self.tx_uid += 1;
+                    if let OutageQueuePolicy::Flush = self.events.policy {
+                        self.buffer.clear();
+                    }
+                }
+                LinkEvent::OutageEnd => {
+                    if !self.events.in_outage() {
+                        let next_tx_time = self.link_trace.next_tx(now);
+                        out.push((next_tx_time, obj_id, Action::Event(self.tx_uid)));
+                    }
+                }
+                LinkEvent::PathSwitch(link_trace, delay) => {
+                    if let Some(link_trace) = link_trace {
+                        // The new trace starts now, and the pending transmit opportunity was for the
+                        // old one
+                        self.link_trace = link_trace;
+                        let next_tx_time = self.link_trace.first_tx(now);
+                        if !self.events.in_outage() {
+                            self.tx_uid += 1;
+                            out.push((next_tx_time, obj_id, Action::Event(self.tx_uid)));
+                        }
+                    }
+                    if let Some(delay) = delay {
+                        self.delay = delay;
+                    }
+                }
+            }
+        }
+        if let Some(time) = self.events.next_time() {
+            out.push((time, obj_id, Action::Event(LINK_EVENT_UID)));
         }
     }
 }
 
+impl<'a> SaveState for Link<'a> {
+    save_fields!(link_trace, buffer, events, delay, last_arrival, tx_uid);
+}
+
 impl<'a> NetObj for Link<'a> {
-    fn init(
-        &mut self,
-        obj_id: NetObjId,
-        now: Time,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(vec![(now, obj_id, Action::Event(0))])
+    fn init(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) -> Result<(), Error> {
+        let first_tx_time = self.link_trace.first_tx(now);
+        out.push((first_tx_time, obj_id, Action::Event(self.tx_uid)));
+        if let Some(time) = self.events.next_time() {
+            out.push((time, obj_id, Action::Event(LINK_EVENT_UID)));
+        }
+        Ok(())
     }
 
     fn push(
@@ -258,18 +767,24 @@ impl<'a> NetObj for Link<'a> {
         obj_id: NetObjId,
         _from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        pkt: Packet,
+        _out: &mut Actions,
+    ) -> Result<(), Error> {
         self.tracer
             .log(obj_id, now, TraceElem::LinkIngress(pkt.src, pkt.size));
+        if let OutageQueuePolicy::Flush = self.events.policy {
+            if self.events.in_outage() {
+                return Ok(());
+            }
+        }
         if let BufferSize::Finite(size) = self.bufsize {
             if self.buffer.len() >= size {
-                return Ok(Vec::new());
+                return Ok(());
             }
         }
 
         self.buffer.push_back(pkt);
-        Ok(Vec::new())
+        Ok(())
     }
 
     fn event(
@@ -278,19 +793,29 @@ impl<'a> NetObj for Link<'a> {
         from: NetObjId,
         now: Time,
         uid: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        assert_eq!(uid, 0);
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        if uid == LINK_EVENT_UID {
+            // There may be more than one of these pending if a path switch was added
+            if self.events.next_time() == Some(now) {
+                self.link_events(obj_id, now, out);
+            }
+            return Ok(());
+        }
+        if uid != self.tx_uid {
+            // This transmit opportunity was cancelled
+            return Ok(());
+        }
 
         // Schedule the next transmission
         let next_tx_time = self.link_trace.next_tx(now);
-        let next_tx = (next_tx_time, obj_id, Action::Event(0));
+        out.push((next_tx_time, obj_id, Action::Event(self.tx_uid)));
 
         self.tracer.log(from, now, TraceElem::LinkTxOpportunity);
 
         // If there are packets, then transmit it. We are allowed to transmit config.pkt_size bytes
         // of data
         let mut num_txed = 0;
-        let mut res = vec![next_tx];
         while let Some(pkt) = self.buffer.front() {
             assert!(pkt.size <= self.config.pkt_size);
             if num_txed + pkt.size > self.config.pkt_size {
@@ -299,13 +824,43 @@ impl<'a> NetObj for Link<'a> {
             let pkt = self.buffer.pop_front().unwrap();
             num_txed += pkt.size;
             self.tracer.log(from, now, TraceElem::LinkEgress(pkt.size));
-            res.push((now, self.next, Action::Push(pkt)));
+            self.last_arrival = std::cmp::max(now + self.delay, self.last_arrival);
+            out.push((self.last_arrival, self.next, Action::Push(pkt)));
         }
-        Ok(res)
+        Ok(())
+    }
+
+    fn add_path_switch(
+        &mut self,
+        obj_id: NetObjId,
+        now: Time,
+        switch: &PathSwitchConfig,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        if switch.time < now {
+            return Err(format_err!(
+                "Path switch at {} is in the past (it is {} now)",
+                switch.time,
+                now
+            ));
+        }
+        self.events.add_path_switch(switch, self.config)?;
+        out.push((switch.time, obj_id, Action::Event(LINK_EVENT_UID)));
+        Ok(())
+    }
+
+    fn state(&mut self) -> serde_json::Value {
+        serde_json::json!({
+            "queue_pkts": self.buffer.len(),
+            "queue_bytes": self.buffer.iter().map(|p| p.size).sum::<u64>(),
+            "outage": self.events.in_outage(),
+            "delay": self.delay,
+        })
     }
 }
 
 /// Delays packets by some amount.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Delay {
     /// The delay by which packets are delayed (either constant or random)
     delay: Time,
@@ -320,12 +875,8 @@ impl Delay {
 }
 
 impl NetObj for Delay {
-    fn init(
-        &mut self,
-        _obj_id: NetObjId,
-        _now: Time,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(Vec::new())
+    fn init(&mut self, _obj_id: NetObjId, _now: Time, _out: &mut Actions) -> Result<(), Error> {
+        Ok(())
     }
 
     fn push(
@@ -333,10 +884,12 @@ impl NetObj for Delay {
         _obj_id: NetObjId,
         _from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
         let deque_time = now + self.delay;
-        Ok(vec![(deque_time, self.next, Action::Push(pkt))])
+        out.push((deque_time, self.next, Action::Push(pkt)));
+        Ok(())
     }
 
     fn event(
@@ -345,16 +898,19 @@ impl NetObj for Delay {
         _: NetObjId,
         _: Time,
         _: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(Vec::new())
+        _: &mut Actions,
+    ) -> Result<(), Error> {
+        Ok(())
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Aggregator {
     /// Time between send intervals. Unlike a link which limits the number of packets packets per
     /// second, `Aggregator` operates at infinite capacity since it can send an arbitrary number of
     /// packets at every opportunity
     intersend: RandomVariable,
+    rng: RngStream,
     /// The next transmit opportunity
     next_opp: Time,
     /// The next hop
@@ -365,9 +921,10 @@ impl Aggregator {
     /// Intersend is the time between send intervals (in microseconds). Unlike a link which limits
     /// the number of packets packets per second, `Aggregator` operates at infinite capacity since it
     /// can send an arbitrary number of packets at every opportunity
-    pub fn new(intersend: RandomVariable, next: NetObjId) -> Self {
+    pub fn new(intersend: RandomVariable, rng: RngStream, next: NetObjId) -> Self {
         Self {
             intersend,
+            rng,
             next_opp: Time::ZERO,
             next,
         }
@@ -375,12 +932,8 @@ impl Aggregator {
 }
 
 impl NetObj for Aggregator {
-    fn init(
-        &mut self,
-        _obj_id: NetObjId,
-        _now: Time,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(Vec::new())
+    fn init(&mut self, _obj_id: NetObjId, _now: Time, _out: &mut Actions) -> Result<(), Error> {
+        Ok(())
     }
 
     fn push(
@@ -388,8 +941,9 @@ impl NetObj for Aggregator {
         _obj_id: NetObjId,
         _from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
         while self.next_opp <= now {
             // Special case: means we don't want any aggregation at all. If we don't handle this
             // separately, `cnt` will reach 10 and produce an error
@@ -401,7 +955,7 @@ impl NetObj for Aggregator {
                 // Get the intersend time from the random variable
                 let mut intersend;
                 loop {
-                    intersend = self.intersend.sample();
+                    intersend = self.intersend.sample(&mut self.rng);
                     if intersend > 0. {
                         break;
                     }
@@ -420,7 +974,8 @@ impl NetObj for Aggregator {
             }
         }
         // Simulator will preserve order. Events that are registered later will be acted on later
-        Ok(vec![(self.next_opp, self.next, Action::Push(pkt))])
+        out.push((self.next_opp, self.next, Action::Push(pkt)));
+        Ok(())
     }
 
     fn event(
@@ -429,7 +984,811 @@ impl NetObj for Aggregator {
         _: NetObjId,
         _: Time,
         _: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(Vec::new())
+        _: &mut Actions,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A Wi-Fi channel with frame aggregation, shared by data and acks. See `WifiConfig`. Like
+/// `Link`, it traces the packets that arrive at it and those it sends, so drops show up as the
+/// difference
+#[derive(Clone)]
+pub struct Wifi<'a> {
+    config: WifiConfig,
+    rng: RngStream,
+    /// Packets waiting to be sent: data in `DATA_QUEUE` and acks in `ACK_QUEUE`
+    queues: [VecDeque<Packet>; 2],
+    /// Next hop of data packets
+    next: NetObjId,
+    /// Acks are sent to the next hop for their destination
+    ack_routes: FnvHashMap<Addr, NetObjId>,
+    /// Whether the channel is being contended for or is in use
+    busy: bool,
+    /// The queue that won the current contention
+    winner: usize,
+    /// What is left of the backoff of a queue that lost the last contention it took part in
+    backoff_left: [Option<Time>; 2],
+    tracer: &'a Tracer<'a>,
+}
+
+const DATA_QUEUE: usize = 0;
+const ACK_QUEUE: usize = 1;
+/// Event uids
+const WIFI_CONTENTION_DONE: u64 = 0;
+const WIFI_AGGREGATE_DONE: u64 = 1;
+
+impl<'a> Wifi<'a> {
+    /// Data packets are sent to `next`, and acks according to `add_ack_route`
+    pub fn new(config: WifiConfig, rng: RngStream, next: NetObjId, tracer: &'a Tracer) -> Self {
+        Self {
+            config,
+            rng,
+            queues: Default::default(),
+            next,
+            ack_routes: Default::default(),
+            busy: false,
+            winner: DATA_QUEUE,
+            backoff_left: [None; 2],
+            tracer,
+        }
+    }
+
+    /// Packets sent to `dest` are acks, and go to `next`
+    pub fn add_ack_route(&mut self, dest: Addr, next: NetObjId) {
+        self.ack_routes.insert(dest, next);
+    }
+
+    /// Contend for the channel if there is anything to send. As in 802.11, a queue that loses
+    /// resumes its backoff the next time instead of sampling a new one, so it doesn't starve
+    fn contend(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) {
+        let mut backoffs = [Time::MAX; 2];
+        for (i, backoff) in backoffs.iter_mut().enumerate() {
+            if !self.queues[i].is_empty() {
+                let (contention, rng) = (&self.config.contention, &mut self.rng);
+                *backoff = self.backoff_left[i]
+                    .take()
+                    .unwrap_or_else(|| Time::from_micros(contention.sample(rng) as u64));
+            }
+        }
+        self.winner = if backoffs[ACK_QUEUE] < backoffs[DATA_QUEUE] {
+            ACK_QUEUE
+        } else {
+            DATA_QUEUE
+        };
+        let backoff = backoffs[self.winner];
+        let loser = 1 - self.winner;
+        if backoffs[loser] != Time::MAX {
+            self.backoff_left[loser] = Some(backoffs[loser] - backoff);
+        }
+        if backoff != Time::MAX {
+            self.busy = true;
+            out.push((now + backoff, obj_id, Action::Event(WIFI_CONTENTION_DONE)));
+        }
+    }
+
+    fn airtime(&self, bytes: u64) -> Time {
+        Time::from_micros((bytes as f64 * 1e6 / self.config.phy_rate) as u64)
+    }
+}
+
+impl<'a> SaveState for Wifi<'a> {
+    save_fields!(rng, queues, ack_routes, busy, winner, backoff_left);
+}
+
+impl<'a> NetObj for Wifi<'a> {
+    fn init(&mut self, _obj_id: NetObjId, _now: Time, _out: &mut Actions) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        obj_id: NetObjId,
+        _from: NetObjId,
+        now: Time,
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        self.tracer
+            .log(obj_id, now, TraceElem::LinkIngress(pkt.src, pkt.size));
+        let queue = if self.ack_routes.contains_key(&pkt.dest) {
+            &mut self.queues[ACK_QUEUE]
+        } else {
+            &mut self.queues[DATA_QUEUE]
+        };
+        if let BufferSize::Finite(size) = self.config.bufsize {
+            if queue.len() >= size {
+                return Ok(());
+            }
+        }
+        queue.push_back(pkt);
+        if !self.busy {
+            self.contend(obj_id, now, out);
+        }
+        Ok(())
+    }
+
+    fn event(
+        &mut self,
+        obj_id: NetObjId,
+        _from: NetObjId,
+        now: Time,
+        uid: u64,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        match uid {
+            WIFI_CONTENTION_DONE => {
+                // Send as many packets as fit in one aggregate, which includes those that arrived
+                // while contending. They all arrive when it ends
+                let mut bytes = 0;
+                let mut pkts = Vec::new();
+                while let Some(pkt) = self.queues[self.winner].front() {
+                    if !pkts.is_empty()
+                        && (pkts.len() >= self.config.max_aggregate
+                            || self.airtime(bytes + pkt.size) > self.config.max_airtime)
+                    {
+                        break;
+                    }
+                    bytes += pkt.size;
+                    pkts.push(self.queues[self.winner].pop_front().unwrap());
+                }
+                let end = now + self.config.overhead + self.airtime(bytes);
+                for pkt in pkts {
+                    let next = if self.winner == ACK_QUEUE {
+                        self.ack_routes[&pkt.dest]
+                    } else {
+                        self.next
+                    };
+                    self.tracer
+                        .log(obj_id, now, TraceElem::LinkEgress(pkt.size));
+                    out.push((end, next, Action::Push(pkt)));
+                }
+                out.push((end, obj_id, Action::Event(WIFI_AGGREGATE_DONE)));
+            }
+            WIFI_AGGREGATE_DONE => {
+                self.busy = false;
+                self.contend(obj_id, now, out);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Routes acks, like `add_ack_route`
+    fn add_route_to(&mut self, dest: Addr, next: NetObjId) -> Result<(), Error> {
+        self.add_ack_route(dest, next);
+        Ok(())
+    }
+
+    fn state(&mut self) -> serde_json::Value {
+        serde_json::json!({
+            "data_queue_pkts": self.queues[DATA_QUEUE].len(),
+            "ack_queue_pkts": self.queues[ACK_QUEUE].len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::TransportHeader;
+
+    fn config(pkt_size: u64) -> Config {
+        serde_yaml::from_str(&format!(
+            "pkt_size: {}
+sim_dur: null
+random_seed: 0
+log:
+  out_terminal: png
+  out_file: out.png
+  cwnd: Ignore
+  rtt: Ignore
+  sender_losses: Ignore
+  timeouts: Ignore
+  link_rates: Ignore
+  stats_intervals: []
+  stats_file: null
+  link_bucket_size: 1s
+topo:
+  link:
+    Const: 1500
+  bufsize: Infinite
+",
+            pkt_size
+        ))
+        .unwrap()
+    }
+
+    /// Transmit opportunities before `till` when the link starts using `trace` at time 0
+    fn opportunities(trace: &mut LinkTrace, till: Time) -> Vec<Time> {
+        let mut res = Vec::new();
+        let mut t = trace.first_tx(Time::ZERO);
+        while t < till {
+            res.push(t);
+            t = trace.next_tx(t);
+        }
+        res
+    }
+
+    fn millis(times: &[u64]) -> Vec<Time> {
+        times.iter().map(|t| Time::from_millis(*t)).collect()
+    }
+
+    #[test]
+    fn piecewise_capacity_matches_integral() {
+        // Segments are much shorter than the time to send a packet, so capacity must carry over
+        // between them. Each loop of 1250us delivers 300 + 0 + 750 bytes
+        let config = config(1500);
+        let rates = vec![
+            (1_000_000., Time::from_micros(300)),
+            (0., Time::from_micros(700)),
+            (3_000_000., Time::from_micros(250)),
+        ];
+        let mut trace = LinkTrace::new_piecewise(&rates, &config);
+        let opps = opportunities(&mut trace, Time::from_secs(10) + Time::from_micros(1));
+        assert_eq!(opps.len(), 8000 * 1050 / 1500);
+        assert_eq!(*opps.last().unwrap(), Time::from_secs(10));
+    }
+
+    #[test]
+    fn piecewise_rounding_does_not_accumulate() {
+        // A packet takes 1000.4us to send, so opportunities can't all be a whole number of
+        // microseconds apart. Rounding must not change the rate over a long run
+        let config = config(1500);
+        let rates = vec![(1500. / 1000.4e-6, Time::from_secs(1000))];
+        let mut trace = LinkTrace::new_piecewise(&rates, &config);
+        let opps = opportunities(&mut trace, Time::from_secs(100));
+        assert_eq!(opps[9999], Time::from_micros(10_004_000));
+        assert_eq!(opps.len(), 99_960);
+        for (i, t) in opps.iter().enumerate() {
+            let exact = (i + 1) as f64 * 1000.4;
+            assert!((t.micros() as f64 - exact).abs() <= 0.5 + 1e-6);
+        }
+    }
+
+    #[test]
+    fn piecewise_rate_change_applies_mid_packet() {
+        // Half of the first packet is sent at the first rate, and the other half at the second
+        let config = config(1500);
+        let rates = vec![
+            (1500., Time::from_millis(500)),
+            (15000., Time::from_millis(500)),
+        ];
+        let mut trace = LinkTrace::new_piecewise(&rates, &config);
+        assert_eq!(
+            opportunities(&mut trace, Time::from_millis(1700)),
+            millis(&[550, 650, 750, 850, 950, 1500, 1600])
+        );
+    }
+
+    #[test]
+    fn markov_delivers_stationary_mean_rate() {
+        // States last 5ms on average, about as long as it takes to send 5 packets at the higher
+        // rate, so many packets straddle a change in rate. The stationary distribution is
+        // (0.5, 0.25, 0.25), so the mean rate is 0.5 * 1.5 + 0.25 * 3 = 1.5MB/s
+        let config = config(1500);
+        let states = vec![
+            MarkovLinkState {
+                rate: 1_500_000.,
+                transitions: vec![(1, 100.), (2, 100.)],
+            },
+            MarkovLinkState {
+                rate: 0.,
+                transitions: vec![(0, 200.)],
+            },
+            MarkovLinkState {
+                rate: 3_000_000.,
+                transitions: vec![(0, 200.)],
+            },
+        ];
+        let mut trace = LinkTrace::new_markov(&states, 0, rng_stream(1, "link"), &config);
+        let opps = opportunities(&mut trace, Time::from_secs(200));
+        let rate = opps.len() as f64 * 1500. / 200.;
+        assert!((rate - 1_500_000.).abs() < 0.01 * 1_500_000., "{}", rate);
+    }
+
+    #[test]
+    fn markov_rate_change_applies_mid_packet() {
+        // The link never leaves the second state, which has no capacity, so after the first
+        // packet, which is sent when the link starts, it delivers exactly the bytes it could
+        // before switching to it
+        let config = config(1500);
+        let states = vec![
+            MarkovLinkState {
+                rate: 1_000_000.,
+                transitions: vec![(1, 1.)],
+            },
+            MarkovLinkState {
+                rate: 0.,
+                transitions: vec![],
+            },
+        ];
+        let mut trace = LinkTrace::new_markov(&states, 0, rng_stream(1, "link"), &config);
+        let switch = match &trace {
+            LinkTrace::Markov { next_switch, .. } => *next_switch,
+            _ => unreachable!(),
+        };
+        let opps = opportunities(&mut trace, Time::MAX);
+        assert_eq!(opps.len() as u64 - 1, switch.micros() / 1500);
+        assert!(*opps.last().unwrap() <= switch);
+    }
+
+    #[test]
+    fn mahimahi_keeps_opportunities_at_the_same_time() {
+        // The trace loops every 10ms, so the opportunity at 10ms coincides with the one at the
+        // start of the next loop
+        let mut trace = LinkTrace::new_trace(Trace {
+            opportunities: millis(&[0, 0, 5, 10]),
+            duration: Time::from_millis(10),
+        })
+        .unwrap();
+        assert_eq!(
+            opportunities(&mut trace, Time::from_millis(25)),
+            millis(&[0, 0, 5, 10, 10, 10, 15, 20, 20, 20])
+        );
+    }
+
+    #[test]
+    fn mahimahi_starts_with_the_link_and_skips_missed_opportunities() {
+        let mut trace = LinkTrace::new_trace(Trace {
+            opportunities: millis(&[1, 5]),
+            duration: Time::from_millis(10),
+        })
+        .unwrap();
+        // E.g. after a path switch at 7ms
+        assert_eq!(trace.first_tx(Time::from_millis(7)), Time::from_millis(8));
+        // E.g. after an outage
+        assert_eq!(trace.next_tx(Time::from_millis(20)), Time::from_millis(22));
+        assert_eq!(trace.next_tx(Time::from_millis(22)), Time::from_millis(28));
+    }
+
+    #[test]
+    fn trace_capacity_matches_integral() {
+        // 1.25 packets per ms for 2ms, then nothing for 1ms, then 0.5 packets per ms for 1ms. That
+        // is 3 packets every 4ms, the last of which is split across the gap
+        let file = "# time,rate\ntime,rate\n0,1250000\n2,0\n\n3,500000\n4,0\n";
+        let trace = Trace::read(file.as_bytes(), TraceFormat::TimeRate, 1000).unwrap();
+        let mut trace = LinkTrace::new_trace(trace).unwrap();
+        let opps = opportunities(&mut trace, Time::from_secs(4) + Time::from_micros(1));
+        assert_eq!(opps.len(), 3000);
+        let micros: Vec<u64> = opps[..6].iter().map(|t| t.micros()).collect();
+        assert_eq!(micros, [800, 1600, 4000, 4800, 5600, 8000]);
+    }
+
+    /// Pushes a packet of the given size to `to` at each of the given times. Packets are numbered
+    /// in order with their `seq_num`
+    #[derive(Clone)]
+    struct Source {
+        to: NetObjId,
+        addr: Addr,
+        pkts: Vec<(Time, u64)>,
+    }
+
+    impl SaveState for Source {}
+
+    impl NetObj for Source {
+        fn init(&mut self, _: NetObjId, _: Time, out: &mut Actions) -> Result<(), Error> {
+            for (seq_num, (time, size)) in self.pkts.iter().enumerate() {
+                let pkt = Packet {
+                    uid: out.new_pkt_id(),
+                    sent_time: *time,
+                    size: *size,
+                    dest: self.addr,
+                    src: self.addr,
+                    ptype: TransportHeader::Datagram {
+                        seq_num: seq_num as SeqNum,
+                    },
+                };
+                out.push((*time, self.to, Action::Push(pkt)));
+            }
+            Ok(())
+        }
+
+        fn push(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            _: Time,
+            _: Packet,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        fn event(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            _: Time,
+            _: u64,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            unreachable!()
+        }
+    }
+
+    /// Records the time each packet arrives, along with its `seq_num`
+    #[derive(Clone, Default)]
+    struct Sink(std::rc::Rc<std::cell::RefCell<Vec<(Time, SeqNum)>>>);
+
+    impl SaveState for Sink {}
+
+    impl NetObj for Sink {
+        fn init(&mut self, _: NetObjId, _: Time, _: &mut Actions) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn push(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            now: Time,
+            pkt: Packet,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            if let TransportHeader::Datagram { seq_num } = pkt.ptype {
+                self.0.borrow_mut().push((now, seq_num));
+            }
+            Ok(())
+        }
+
+        fn event(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            _: Time,
+            _: u64,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            unreachable!()
+        }
+    }
+
+    /// Send full-sized packets at the given times through a link with the given rate and events,
+    /// and return when each arrives after it, till `till`
+    fn run_link(
+        link: LinkTraceConfig,
+        events: LinkEventsConfig,
+        pkts: &[Time],
+        till: Time,
+    ) -> Vec<(Time, SeqNum)> {
+        let config = config(1500);
+        let tracer = Tracer::new(&config);
+        let mut sched = Scheduler::default();
+        let sink = Sink::default();
+        let sink_id = sched.register_obj(Box::new(sink.clone()));
+        // The source comes before the link, so that packets pushed at the time of a transmit
+        // opportunity are there for it
+        let addr = sched.next_addr();
+        let link_id = sched.num_objs() + 1;
+        let pkts = pkts.iter().map(|t| (*t, 1500)).collect();
+        sched.register_obj(Box::new(Source {
+            to: link_id,
+            addr,
+            pkts,
+        }));
+        let link = Link::new(
+            LinkTrace::from_config(&link, "link", &config).unwrap(),
+            BufferSize::Infinite,
+            sink_id,
+            &tracer,
+            &config,
+            LinkEvents::from_config(&events, "link", &config).unwrap(),
+        );
+        assert_eq!(sched.register_obj(Box::new(link)), link_id);
+        sched.simulate(Some(till)).unwrap();
+        let arrivals = sink.0.borrow().clone();
+        arrivals
+    }
+
+    /// A link that sends a packet every millisecond
+    const ONE_PER_MS: LinkTraceConfig = LinkTraceConfig::Const(1_500_000.);
+
+    fn arrival_times(arrivals: &[(Time, SeqNum)]) -> Vec<Time> {
+        arrivals.iter().map(|(t, _)| *t).collect()
+    }
+
+    fn millis_range(ranges: &[std::ops::RangeInclusive<u64>]) -> Vec<Time> {
+        ranges
+            .iter()
+            .flat_map(|r| r.clone().map(Time::from_millis))
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_outages() {
+        // The link is down from 10ms till the second outage ends at 40ms, even though the first
+        // ends at 30ms
+        let events = LinkEventsConfig {
+            outages: vec![
+                (Time::from_millis(10), Time::from_millis(20)),
+                (Time::from_millis(20), Time::from_millis(20)),
+            ],
+            ..Default::default()
+        };
+        let arrivals = run_link(
+            ONE_PER_MS,
+            events,
+            &[Time::ZERO; 100],
+            Time::from_millis(60),
+        );
+        assert_eq!(arrival_times(&arrivals), millis_range(&[0..=9, 41..=60]));
+    }
+
+    #[test]
+    fn outage_queue_policies() {
+        // 30 packets are queued when the outage starts, one arrives during it and one after it
+        let mut pkts = vec![Time::ZERO; 30];
+        pkts.push(Time::from_millis(15));
+        pkts.push(Time::from_millis(50));
+        let events = |outage_queue| LinkEventsConfig {
+            outages: vec![(Time::from_millis(10), Time::from_millis(10))],
+            outage_queue,
+            ..Default::default()
+        };
+
+        let held = run_link(
+            ONE_PER_MS,
+            events(OutageQueuePolicy::Hold),
+            &pkts,
+            Time::from_millis(100),
+        );
+        assert_eq!(
+            arrival_times(&held),
+            millis_range(&[0..=9, 21..=41, 50..=50])
+        );
+        assert!(held
+            .iter()
+            .enumerate()
+            .all(|(i, (_, seq))| i as u64 == *seq));
+
+        let flushed = run_link(
+            ONE_PER_MS,
+            events(OutageQueuePolicy::Flush),
+            &pkts,
+            Time::from_millis(100),
+        );
+        assert_eq!(arrival_times(&flushed), millis_range(&[0..=9, 50..=50]));
+        assert_eq!(flushed.last().unwrap().1, 31);
+    }
+
+    #[test]
+    fn random_outages() {
+        // With constant "random" times, outages last 5ms and start 10ms after the last one ended
+        let events = LinkEventsConfig {
+            random_outages: Some(RandomOutagesConfig {
+                interval: RandomVariable::Const(10_000.),
+                duration: RandomVariable::Const(5_000.),
+            }),
+            ..Default::default()
+        };
+        let arrivals = run_link(
+            ONE_PER_MS,
+            events,
+            &[Time::ZERO; 100],
+            Time::from_millis(50),
+        );
+        assert_eq!(
+            arrival_times(&arrivals),
+            millis_range(&[0..=9, 16..=24, 31..=39, 46..=50])
+        );
+    }
+
+    #[test]
+    fn path_switch_with_interruption() {
+        // At 20ms, the link goes dark for 5ms, and then sends twice as fast with 10ms of delay
+        let events = LinkEventsConfig {
+            path_switches: vec![PathSwitchConfig {
+                time: Time::from_millis(20),
+                link: Some(LinkTraceConfig::Const(3_000_000.)),
+                delay: Some(Time::from_millis(10)),
+                interruption: Time::from_millis(5),
+            }],
+            ..Default::default()
+        };
+        let arrivals = run_link(
+            ONE_PER_MS,
+            events,
+            &[Time::ZERO; 100],
+            Time::from_millis(40),
+        );
+        let mut expected = millis_range(&[0..=19]);
+        expected.extend((0..10).map(|i| Time::from_micros(35_500 + 500 * i)));
+        assert_eq!(arrival_times(&arrivals), expected);
+    }
+
+    #[test]
+    fn path_switch_to_lower_delay_keeps_order() {
+        // Packets sent at 10-19ms take 10ms, and those after 20ms none, so the ones sent at
+        // 20-29ms wait for the last of the earlier ones to arrive at 29ms
+        let switch = |time, delay| PathSwitchConfig {
+            time: Time::from_millis(time),
+            link: None,
+            delay: Some(Time::from_millis(delay)),
+            interruption: Time::ZERO,
+        };
+        let events = LinkEventsConfig {
+            path_switches: vec![switch(10, 10), switch(20, 0)],
+            ..Default::default()
+        };
+        let arrivals = run_link(
+            ONE_PER_MS,
+            events,
+            &[Time::ZERO; 100],
+            Time::from_millis(40),
+        );
+        let mut expected = millis_range(&[0..=9, 20..=29]);
+        expected.extend([Time::from_millis(29); 10]);
+        expected.extend(millis_range(&[30..=40]));
+        assert_eq!(arrival_times(&arrivals), expected);
+        assert!(arrivals
+            .iter()
+            .enumerate()
+            .all(|(i, (_, seq))| i as u64 == *seq));
+    }
+
+    /// Send data and acks of the given sizes at the given times through a Wi-Fi channel, and
+    /// return when each arrives after it, till `till`
+    fn run_wifi(
+        wifi: WifiConfig,
+        data: &[(Time, u64)],
+        acks: &[(Time, u64)],
+        till: Time,
+    ) -> (Vec<(Time, SeqNum)>, Vec<(Time, SeqNum)>) {
+        let config = config(1500);
+        let tracer = Tracer::new(&config);
+        let mut sched = Scheduler::default();
+        let (data_sink, ack_sink) = (Sink::default(), Sink::default());
+        let data_sink_id = sched.register_obj(Box::new(data_sink.clone()));
+        let ack_sink_id = sched.register_obj(Box::new(ack_sink.clone()));
+        let (data_addr, ack_addr) = (sched.next_addr(), sched.next_addr());
+        let wifi_id = sched.num_objs() + 2;
+        sched.register_obj(Box::new(Source {
+            to: wifi_id,
+            addr: data_addr,
+            pkts: data.to_vec(),
+        }));
+        sched.register_obj(Box::new(Source {
+            to: wifi_id,
+            addr: ack_addr,
+            pkts: acks.to_vec(),
+        }));
+        let mut wifi = Wifi::new(wifi, rng_stream(0, "wifi"), data_sink_id, &tracer);
+        wifi.add_ack_route(ack_addr, ack_sink_id);
+        assert_eq!(sched.register_obj(Box::new(wifi)), wifi_id);
+        sched.simulate(Some(till)).unwrap();
+        let arrivals = (data_sink.0.borrow().clone(), ack_sink.0.borrow().clone());
+        arrivals
+    }
+
+    /// A channel that sends a full-sized packet in 1ms, with 1ms of overhead per aggregate and
+    /// no contention
+    fn slow_wifi() -> WifiConfig {
+        WifiConfig {
+            phy_rate: 1_500_000.,
+            overhead: Time::from_millis(1),
+            contention: RandomVariable::Const(0.),
+            max_aggregate: 64,
+            max_airtime: Time::from_secs(1),
+            bufsize: BufferSize::Infinite,
+        }
+    }
+
+    #[test]
+    fn wifi_aggregate_bounded_by_max_aggregate() {
+        let wifi = WifiConfig {
+            max_aggregate: 4,
+            ..slow_wifi()
+        };
+        let (data, _) = run_wifi(wifi, &[(Time::ZERO, 1500); 10], &[], Time::from_secs(1));
+        let mut expected = vec![Time::from_millis(5); 4];
+        expected.extend([Time::from_millis(10); 4]);
+        expected.extend([Time::from_millis(13); 2]);
+        assert_eq!(arrival_times(&data), expected);
+    }
+
+    #[test]
+    fn wifi_aggregate_bounded_by_max_airtime() {
+        let wifi = WifiConfig {
+            max_airtime: Time::from_millis(3),
+            ..slow_wifi()
+        };
+        let (data, _) = run_wifi(wifi, &[(Time::ZERO, 1500); 7], &[], Time::from_secs(1));
+        let mut expected = vec![Time::from_millis(4); 3];
+        expected.extend([Time::from_millis(8); 3]);
+        expected.push(Time::from_millis(10));
+        assert_eq!(arrival_times(&data), expected);
+    }
+
+    #[test]
+    fn wifi_sends_packets_longer_than_max_airtime() {
+        let wifi = WifiConfig {
+            max_airtime: Time::from_millis(3),
+            ..slow_wifi()
+        };
+        let (data, _) = run_wifi(wifi, &[(Time::ZERO, 6000); 2], &[], Time::from_secs(1));
+        assert_eq!(
+            arrival_times(&data),
+            vec![Time::from_millis(5), Time::from_millis(10)]
+        );
+    }
+
+    #[test]
+    fn wifi_acks_and_data_alternate() {
+        // The first data packet starts a contention that only data takes part in. After that both
+        // queues always have packets and draw the same backoff: data wins the tie, and acks then
+        // win right away with what is left of theirs
+        let wifi = WifiConfig {
+            contention: RandomVariable::Const(100.),
+            max_aggregate: 2,
+            ..slow_wifi()
+        };
+        let pkts = [(Time::ZERO, 1500); 6];
+        let (data, acks) = run_wifi(wifi, &pkts, &pkts, Time::from_secs(1));
+        let ends = |micros: [u64; 3]| -> Vec<Time> {
+            micros
+                .iter()
+                .flat_map(|t| [Time::from_micros(*t); 2])
+                .collect()
+        };
+        assert_eq!(arrival_times(&data), ends([3100, 6200, 12300]));
+        assert_eq!(arrival_times(&acks), ends([9200, 15300, 18400]));
+    }
+
+    #[test]
+    fn wifi_drops_when_full() {
+        let wifi = WifiConfig {
+            bufsize: BufferSize::Finite(2),
+            ..slow_wifi()
+        };
+        let (data, _) = run_wifi(wifi, &[(Time::ZERO, 1500); 5], &[], Time::from_secs(1));
+        assert_eq!(
+            data,
+            vec![(Time::from_millis(3), 0), (Time::from_millis(3), 1)]
+        );
+    }
+
+    #[test]
+    fn flow_added_and_retired_mid_run() {
+        let mut sched = Scheduler::default();
+        let router_addr = sched.next_addr();
+        let router_id = sched.register_obj(Box::new(Router::new(router_addr)));
+        sched.run_until(Time::from_millis(10)).unwrap();
+
+        // The flow arrives at 10ms and sends a packet every millisecond till 30ms
+        let addr = sched.next_addr();
+        let sink = Sink::default();
+        let sink_id = sched.register_obj(Box::new(sink.clone()));
+        sched.register_obj(Box::new(Source {
+            to: router_id,
+            addr,
+            pkts: millis_range(&[10..=30])
+                .into_iter()
+                .map(|t| (t, 1500))
+                .collect(),
+        }));
+        sched.add_route(router_id, addr, sink_id).unwrap();
+        sched.run_until(Time::from_millis(20)).unwrap();
+
+        // It leaves at 20ms. Its packets still in flight are dropped
+        for obj_id in sink_id..sched.num_objs() {
+            sched.retire_obj(obj_id);
+        }
+        sched.simulate(None).unwrap();
+        assert_eq!(arrival_times(&sink.0.borrow()), millis_range(&[10..=20]));
+        assert!(sched.obj_state(sink_id).is_none());
+        assert!(sched.add_route(sink_id, addr, router_id).is_err());
+    }
+
+    #[test]
+    fn cellular_stuck_at_zero_rate_never_sends() {
+        let config = config(1500);
+        let params = CellularLinkConfig {
+            initial_rate: 0.,
+            volatility: 0.,
+            ..Default::default()
+        };
+        let mut trace = LinkTrace::new_cellular(&params, rng_stream(1, "link"), &config);
+        assert_eq!(trace.next_tx(Time::ZERO), Time::MAX);
     }
 }
@@ -1,10 +1,12 @@
-use crate::simulator::{PktId, SeqNum, Time};
+use crate::simulator::{impl_save_state, PktId, SeqNum, Time};
 use crate::transport::CongestionControl;
 
+use serde::{Deserialize, Serialize};
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 
 /// Constant cwnd and intersend time
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Const {
     cwnd: u64,
     intersend: Time,
@@ -16,6 +18,8 @@ impl Const {
     }
 }
 
+impl_save_state!(Const);
+
 impl CongestionControl for Const {
     fn on_ack(&mut self, _: Time, _: SeqNum, _: PktId, _: Time, _: u64) {}
 
@@ -32,6 +36,7 @@ impl CongestionControl for Const {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AIMD {
     cwnd: f64,
     /// The last packet we sent
@@ -56,6 +61,8 @@ impl Default for AIMD {
     }
 }
 
+impl_save_state!(AIMD);
+
 impl CongestionControl for AIMD {
     fn on_ack(&mut self, _now: Time, cum_ack: SeqNum, _ack_uid: PktId, _rtt: Time, num_lost: u64) {
         if self.cwnd + 1. >= self.ss_thresh.unwrap_or(std::f64::MAX) {
@@ -102,6 +109,7 @@ impl CongestionControl for AIMD {
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct InstantCC {
     cwnd: f64,
     rtt_min: Time,
@@ -130,6 +138,8 @@ impl Default for InstantCC {
 }
 
 // ATT account number 4361 5082 2804
+impl_save_state!(InstantCC);
+
 impl CongestionControl for InstantCC {
     fn on_ack(&mut self, _now: Time, cum_ack: SeqNum, _ack_uid: PktId, rtt: Time, num_lost: u64) {
         // What is the maximum multiplicative increase in cwnd per RTT
@@ -228,6 +238,7 @@ impl CongestionControl for InstantCC {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OscInstantCC {
     /// Latest time we are aware of (may not be the best way to determine this)
     now: Time,
@@ -278,6 +289,8 @@ impl OscInstantCC {
     }
 }
 
+impl_save_state!(OscInstantCC);
+
 impl CongestionControl for OscInstantCC {
     fn on_ack(&mut self, now: Time, cum_ack: SeqNum, _ack_uid: PktId, rtt: Time, num_lost: u64) {
         assert!(self.now <= now);
@@ -368,6 +381,7 @@ impl CongestionControl for OscInstantCC {
 }
 
 /// The algorithm designed in `analysis/stable_linear_tcp_design.ipynb`
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StableLinearCC {
     /// Configuration parameters
     alpha: f64,
@@ -396,6 +410,8 @@ impl StableLinearCC {
     }
 }
 
+impl_save_state!(StableLinearCC);
+
 impl CongestionControl for StableLinearCC {
     fn on_ack(&mut self, _now: Time, cum_ack: SeqNum, _ack_uid: PktId, rtt: Time, num_lost: u64) {
         // Primitive packet loss handling
@@ -468,6 +484,7 @@ impl CongestionControl for StableLinearCC {
 }
 
 /// A congestion control that estimates the BDP and transmits above the BDP
+#[derive(Clone, Serialize, Deserialize)]
 pub struct IncreaseBdpCC {
     /// Estimate of the propagation delay
     min_rtt: Time,
@@ -493,6 +510,8 @@ impl Default for IncreaseBdpCC {
     }
 }
 
+impl_save_state!(IncreaseBdpCC);
+
 impl CongestionControl for IncreaseBdpCC {
     fn on_ack(&mut self, now: Time, cum_ack: SeqNum, _ack_uid: PktId, rtt: Time, _num_lost: u64) {
         self.num_acks_since_marker += 1;
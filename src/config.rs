@@ -1,43 +1,124 @@
 //! Global configuration
-use crate::base::BufferSize;
+use crate::base::{BufferSize, LinkTrace};
 use crate::random::RandomVariable;
 use crate::simulator::Time;
+use crate::trace::TraceFormat;
 use crate::transport::TcpSenderTxLength;
 
+use failure::{format_err, Error};
+use jsonschema::paths::PathChunk;
 // For random links.
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
     /// Number of bytes in data packets
+    #[serde(with = "crate::units::bytes")]
+    #[schemars(with = "crate::units::ByteSize")]
     pub pkt_size: u64,
     /// How long should we simulate (if not given, simulate till no more events occur)
     pub sim_dur: Option<Time>,
     pub log: ConfigLog,
     pub topo: ConfigTopo,
-    // Random seed for reproducibility.
-    pub random_seed: u8,
+    /// Master seed for reproducibility. Each random element of the simulation derives its own
+    /// stream of random numbers from this and its name
+    pub random_seed: u64,
 }
 
 /// Configure a `LinkTrace` for use in `Link`
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub enum LinkTraceConfig {
     /// Constant link rate in bytes per second
-    Const(f64),
+    Const(
+        #[serde(with = "crate::units::rate")]
+        #[schemars(with = "crate::units::Rate")]
+        f64,
+    ),
     /// Random link with link rate as samples from the given stationary distribution.
     Random(RandomVariable),
     /// A piecewise-constant link rate. Give the rate and duration for which it applies in bytes
-    /// per second. Loops after it reaches the end.
-    Piecewise(Vec<(f64, Time)>),
+    /// per second. Loops after it reaches the end. A rate of 0 means the link sends nothing
+    Piecewise(
+        #[serde(with = "crate::units::rate_schedule")]
+        #[schemars(with = "Vec<(crate::units::Rate, Time)>")]
+        Vec<(f64, Time)>,
+    ),
     /// File containing a mahimahi-like trace (it also handles floating-point values)
     MahimahiFile(String),
+    /// Trace file in any of the formats in `TraceFormat`, possibly gzip-compressed
+    TraceFile {
+        file: String,
+        #[serde(default)]
+        format: TraceFormat,
+    },
+    /// Markov-modulated link: the rate is that of the current state, and the link moves between
+    /// states as a continuous-time Markov chain. Starts in state `initial_state`
+    Markov {
+        states: Vec<MarkovLinkState>,
+        #[serde(default)]
+        initial_state: usize,
+    },
+    /// Cellular-like link as modeled by Sprout: the rate does a random walk and packets are
+    /// delivered as a Poisson process at that rate
+    Cellular(CellularLinkConfig),
+}
+
+/// A state of a `LinkTraceConfig::Markov` link
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MarkovLinkState {
+    /// Link rate in bytes per second while in this state. May be 0 to model an outage
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub rate: f64,
+    /// List of (state, rate) pairs: the link moves to `state` after an exponentially distributed
+    /// time with the given rate (per second). If there are several, whichever happens first wins
+    pub transitions: Vec<(usize, f64)>,
+}
+
+/// Parameters of a `LinkTraceConfig::Cellular` link. The defaults are the ones Sprout uses,
+/// assuming 1500 byte packets
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct CellularLinkConfig {
+    /// Link rate at the start, in bytes per second
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub initial_rate: f64,
+    /// The rate never exceeds this
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub max_rate: f64,
+    /// Standard deviation of the change in rate over one second (it grows with the square root
+    /// of time, as in Brownian motion)
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub volatility: f64,
+    /// Once the rate falls to 0, the link stays in an outage until an event of this rate (per
+    /// second) happens
+    pub outage_escape_rate: f64,
+    /// How often the rate changes
+    pub tick: Time,
+}
+
+impl Default for CellularLinkConfig {
+    fn default() -> Self {
+        Self {
+            initial_rate: 750_000.,
+            max_rate: 1_500_000.,
+            volatility: 300_000.,
+            outage_escape_rate: 1.,
+            tick: Time::from_millis(20),
+        }
+    }
 }
 
 /// Congestion control class
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 #[allow(dead_code)]
 pub enum CCConfig {
-    Const { cwnd: u64, intersend: u64 },
+    Const { cwnd: u64, intersend: Time },
     AIMD,
     InstantCC,
     OscInstantCC { k: f64, omega: f64 },
@@ -47,8 +128,170 @@ pub enum CCConfig {
     Copa2,
 }
 
+/// How `TcpSender` detects lost packets, in addition to retransmission timeouts
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+pub enum LossDetectionConfig {
+    /// Mark a packet as lost once these many packets sent after it have been acked (dupacks)
+    DupAck(u64),
+    /// RACK-TLP (RFC 8985): mark a packet as lost if it was sent sufficiently earlier than a
+    /// packet that was acked, and send tail loss probes. `reo_wnd` is the reordering window as a
+    /// fraction of the minimum RTT (RFC 8985 uses 1/4)
+    RackTlp { reo_wnd: f64 },
+}
+
+impl Default for LossDetectionConfig {
+    fn default() -> Self {
+        Self::DupAck(3)
+    }
+}
+
+/// Parameters for computing the retransmission timeout (RTO) in `TcpSender`. The default `min`,
+/// `max` and `initial` follow RFC 6298. `karn` and `reset_backoff` default to false, which keeps
+/// the simulator's original behavior. RFC 6298 requires both, so set them to true to follow it
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct RtoConfig {
+    /// The RTO is never smaller than this
+    pub min: Time,
+    /// The RTO is never larger than this, even after backoff
+    pub max: Time,
+    /// RTO to use before the first RTT sample is available
+    pub initial: Time,
+    /// The maximum factor by which the RTO is multiplied due to exponential backoff
+    pub max_backoff: u64,
+    /// If true, RTT samples from retransmitted packets are ignored, as TCP does (Karn's
+    /// algorithm). Else, every ack gives an RTT sample, as in QUIC where every packet has a unique
+    /// id
+    pub karn: bool,
+    /// If true, the backoff is reset when an ack gives an RTT sample, as RFC 6298 requires (with
+    /// `karn`, that is an ack for a packet that was not retransmitted). Else the backoff, once
+    /// raised by a timeout, is kept for the rest of the connection
+    pub reset_backoff: bool,
+}
+
+impl Default for RtoConfig {
+    fn default() -> Self {
+        Self {
+            min: Time::from_secs(1),
+            max: Time::from_secs(60),
+            initial: Time::from_secs(1),
+            max_backoff: 256,
+            karn: false,
+            reset_backoff: false,
+        }
+    }
+}
+
+/// The transport protocol used by a group of senders
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub enum TransportConfig {
+    /// Reliable, TCP-like transport. Uses the window-based congestion control given by `cc`
+    #[default]
+    Tcp,
+    /// Real-time media sent as unreliable datagrams, with per-packet feedback from the receiver.
+    /// `cc`, `rcv_bufsize`, `app_read_rate`, `loss_detection` and `rto` are ignored
+    Media(MediaConfig),
+}
+
+/// Configure a real-time media source, such as a video call
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct MediaConfig {
+    /// Frames per second produced by the encoder
+    pub fps: f64,
+    /// Number of frames between keyframes. If 0, only the first frame is a keyframe
+    pub keyframe_interval: u64,
+    /// How many times larger keyframes are than other frames
+    pub keyframe_factor: f64,
+    /// Bitrate of the encoder (in bytes per second) when it starts
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub start_bitrate: f64,
+    /// The encoder's bitrate is always between these (in bytes per second)
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub min_bitrate: f64,
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub max_bitrate: f64,
+    /// Every frame, the encoder moves its bitrate this fraction of the way towards the rate given
+    /// by rate control. 1 means it follows instantly
+    pub encoder_gain: f64,
+    /// Packets are paced at this multiple of the rate given by rate control
+    pub pacing_factor: f64,
+    /// How often the receiver sends feedback
+    pub feedback_interval: Time,
+    /// Datagrams the sender hasn't heard about this long after sending them, e.g. because the
+    /// feedback on them was lost, are counted as lost. Defaults to 1s
+    #[serde(default)]
+    pub feedback_timeout: Option<Time>,
+    pub rate_control: RateControlConfig,
+}
+
+/// Rate control algorithm for real-time media
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub enum RateControlConfig {
+    /// Constant rate in bytes per second
+    Const(
+        #[serde(with = "crate::units::rate")]
+        #[schemars(with = "crate::units::Rate")]
+        f64,
+    ),
+    /// Delay-gradient based controller in the style of Google Congestion Control (GCC)
+    Gcc,
+}
+
+/// The application generating data for a TCP sender
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub enum AppConfig {
+    /// Always has data to send, until `tx_length` is reached
+    #[default]
+    Bulk,
+    /// Adaptive-bitrate video streaming
+    Video(VideoConfig),
+}
+
+/// Configure a video streaming client. Chunks are requested one at a time, and the ABR algorithm
+/// picks the bitrate of each. The delay in sending requests to the server is ignored
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct VideoConfig {
+    /// Bitrates (in bytes per second) at which the video is available, in increasing order
+    #[serde(with = "crate::units::rate_vec")]
+    #[schemars(with = "Vec<crate::units::Rate>")]
+    pub bitrates: Vec<f64>,
+    /// Duration of video in each chunk
+    pub chunk_dur: Time,
+    /// Number of chunks in the video. If not given, the video never ends
+    pub num_chunks: Option<u64>,
+    /// The client doesn't request a chunk if that would take its buffer above this
+    pub max_buffer: Time,
+    /// Playback starts (or resumes after rebuffering) once this much video is buffered
+    pub startup_buffer: Time,
+    pub abr: AbrConfig,
+}
+
+/// Adaptive bitrate (ABR) algorithm for video streaming
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub enum AbrConfig {
+    /// Buffer-based (BBA). Uses the lowest bitrate when the buffer is below `reservoir`, the
+    /// highest when it is above `reservoir + cushion` and interpolates linearly in between
+    BufferBased { reservoir: Time, cushion: Time },
+    /// Rate-based. Picks the highest bitrate below `safety` times the harmonic mean of the
+    /// throughput of the last `window` chunks
+    RateBased { window: usize, safety: f64 },
+    /// RobustMPC. Picks the bitrate that maximizes QoE over the next `horizon` chunks, assuming a
+    /// conservative throughput estimate. The QoE of a chunk is its bitrate (in bytes per second),
+    /// minus `rebuf_penalty` times the seconds of rebuffering, minus `switch_penalty` times the
+    /// change in bitrate. `horizon` is at most 5, as in RobustMPC, since every sequence of
+    /// bitrates over it is tried for each chunk
+    Mpc {
+        horizon: usize,
+        rebuf_penalty: f64,
+        switch_penalty: f64,
+    },
+}
+
 /// A group of senders
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct SenderGroupConfig {
     /// Number of senders in this group
     pub num_senders: usize,
@@ -62,19 +305,186 @@ pub struct SenderGroupConfig {
     /// When should the senders start transmit?
     pub start_time: Time,
     pub tx_length: TcpSenderTxLength,
+    /// Size of the receiver's buffer (in packets). Packets that arrived out of order or haven't
+    /// been read by the application occupy this buffer. Its free space is advertised to the sender
+    /// as the receive window
+    #[serde(default)]
+    pub rcv_bufsize: BufferSize,
+    /// Rate (in bytes per second) at which the receiving application reads data. If not given,
+    /// data is read as soon as it arrives in order
+    #[serde(default, with = "crate::units::rate_opt")]
+    #[schemars(with = "Option<crate::units::Rate>")]
+    pub app_read_rate: Option<f64>,
+    /// How the senders detect lost packets. Defaults to 3 dupacks
+    #[serde(default)]
+    pub loss_detection: LossDetectionConfig,
+    /// How the senders compute their retransmission timeout
+    #[serde(default)]
+    pub rto: RtoConfig,
+    /// Transport protocol used by the senders. Defaults to TCP
+    #[serde(default)]
+    pub transport: TransportConfig,
+    /// The application running over TCP. Ignored for other transports. Defaults to bulk transfer
+    #[serde(default)]
+    pub app: AppConfig,
 }
 
 /// Configure the topology of the network
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ConfigTopo {
     /// How the common bottleneck link rate varies with time
     pub link: LinkTraceConfig,
     /// Buffer size of the bottleneck link
     pub bufsize: BufferSize,
+    #[serde(default)]
     pub sender_groups: Vec<SenderGroupConfig>,
+    /// Series of similar sender groups, created after those in `sender_groups`
+    #[serde(default)]
+    pub sender_group_series: Vec<SenderGroupSeries>,
+    /// Outages and path switches of the bottleneck link
+    #[serde(default)]
+    pub link_events: LinkEventsConfig,
+    /// If given, a Wi-Fi hop between the bottleneck link and the receivers. Data and acks share
+    /// its channel
+    #[serde(default)]
+    pub wifi: Option<WifiConfig>,
+}
+
+/// An 802.11-style channel with A-MPDU aggregation. Data (from the bottleneck link) and acks
+/// (from all the receivers, as if they were behind one station) wait in their own queues and
+/// contend for the channel. The winner sends as many queued packets as fit in one aggregate, and
+/// they are all delivered when it ends. The defaults are roughly those of 802.11ac
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct WifiConfig {
+    /// Rate at which the packets in an aggregate are sent
+    #[serde(with = "crate::units::rate")]
+    #[schemars(with = "crate::units::Rate")]
+    pub phy_rate: f64,
+    /// Fixed airtime of every aggregate, e.g. for the preamble and the block ack
+    pub overhead: Time,
+    /// Time spent contending for the channel before each aggregate (in microseconds). When both
+    /// queues have packets, each samples it and the smaller one wins. The loser keeps what is left
+    /// of its sample for the next contention
+    pub contention: RandomVariable,
+    /// Maximum number of packets in an aggregate
+    pub max_aggregate: usize,
+    /// An aggregate has more than one packet only if they can be sent in this long (not
+    /// counting `overhead`)
+    pub max_airtime: Time,
+    /// Buffer size of each of the queues
+    pub bufsize: BufferSize,
+}
+
+impl Default for WifiConfig {
+    fn default() -> Self {
+        Self {
+            phy_rate: 50_000_000.,
+            overhead: Time::from_micros(100),
+            // DIFS and a random backoff of up to 15 slots
+            contention: RandomVariable::Uniform {
+                min: 34.,
+                max: 34. + 15. * 9.,
+            },
+            max_aggregate: 64,
+            max_airtime: Time::from_micros(5484),
+            bufsize: BufferSize::Infinite,
+        }
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// Events that disrupt the bottleneck link. During an outage, the link transmits nothing
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct LinkEventsConfig {
+    /// Outages at fixed times, given as (start time, duration)
+    pub outages: Vec<(Time, Time)>,
+    /// Outages that happen at random
+    pub random_outages: Option<RandomOutagesConfig>,
+    /// What happens to packets in the link's buffer during an outage
+    pub outage_queue: OutageQueuePolicy,
+    /// Switches to a different path, e.g. due to a handover
+    pub path_switches: Vec<PathSwitchConfig>,
+}
+
+/// Outages whose start and duration are random (in microseconds)
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RandomOutagesConfig {
+    /// Time from the start of the simulation or the end of an outage to the start of the next
+    pub interval: RandomVariable,
+    /// How long each outage lasts
+    pub duration: RandomVariable,
+}
+
+/// What a link does with its packets during an outage
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub enum OutageQueuePolicy {
+    /// Keep the packets buffered (and keep accepting packets while there is space), and transmit
+    /// them once the outage ends
+    #[default]
+    Hold,
+    /// Drop the buffered packets when the outage starts, and those that arrive during it
+    Flush,
+}
+
+/// At `time`, the link switches to a path with a different rate and delay
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PathSwitchConfig {
+    pub time: Time,
+    /// The link's rate on the new path. If not given, it doesn't change
+    #[serde(default)]
+    pub link: Option<LinkTraceConfig>,
+    /// One-way delay the link adds on the new path, in addition to the senders' own `delay`. If
+    /// not given, it doesn't change. It is 0 before the first switch. If it drops, packets sent
+    /// after the switch still arrive after those sent before it
+    #[serde(default)]
+    pub delay: Option<Time>,
+    /// The link is in an outage for this long while switching
+    #[serde(default)]
+    pub interruption: Time,
+}
+
+impl ConfigTopo {
+    /// All the sender groups: those in `sender_groups` followed by those generated by each of
+    /// `sender_group_series`
+    pub fn all_sender_groups(&self) -> Vec<SenderGroupConfig> {
+        let mut groups = self.sender_groups.clone();
+        for series in &self.sender_group_series {
+            groups.extend(series.groups());
+        }
+        groups
+    }
+}
+
+/// Generates `count` sender groups that are copies of `group`, except that their start times and
+/// delays are spaced out. E.g. five groups starting 5s apart
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SenderGroupSeries {
+    /// Number of groups to create
+    pub count: usize,
+    /// The first group. The others are copies of it
+    pub group: SenderGroupConfig,
+    /// The i-th group (counting from 0) starts `i * start_time_step` after `group.start_time`
+    #[serde(default)]
+    pub start_time_step: Time,
+    /// The i-th group (counting from 0) has `i * delay_step` more delay than `group.delay`
+    #[serde(default)]
+    pub delay_step: Time,
+}
+
+impl SenderGroupSeries {
+    /// The groups in this series
+    pub fn groups(&self) -> impl Iterator<Item = SenderGroupConfig> + '_ {
+        (0..self.count as u64).map(move |i| {
+            let mut group = self.group.clone();
+            group.start_time = group.start_time + self.start_time_step * i;
+            group.delay = group.delay + self.delay_step * i;
+            group
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq)]
 pub enum LogType {
     /// Ignore these values whenever they are seen
     Ignore,
@@ -99,7 +509,7 @@ impl LogType {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ConfigLog {
     /// The terminal to output graphs to. Commonly used ones: (wxt, pdfcairo, epscairo, pngcairo,
     /// svg, canvas). wxt is the interactive terminal
@@ -125,3 +535,1048 @@ pub struct ConfigLog {
     /// Bucket size for plotting link stats
     pub link_bucket_size: Time,
 }
+
+/// A parameter sweep: the simulation in `base` is run for every combination of the values along
+/// each axis, and for each seed. Axes that are empty are not swept, i.e. `base`'s value is used
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SweepConfig {
+    pub base: Config,
+    /// Congestion control algorithm of all sender groups
+    #[serde(default)]
+    pub cc: Vec<CCConfig>,
+    /// Bottleneck link rate
+    #[serde(default)]
+    pub link: Vec<LinkTraceConfig>,
+    /// Fixed delay of all sender groups
+    #[serde(default)]
+    pub delay: Vec<Time>,
+    /// Buffer size of the bottleneck link
+    #[serde(default)]
+    pub bufsize: Vec<BufferSize>,
+    /// Number of senders in each sender group
+    #[serde(default)]
+    pub num_senders: Vec<usize>,
+    /// Random seeds to run each combination with. Results are aggregated over these. Defaults to
+    /// `base.random_seed`
+    #[serde(default)]
+    pub seeds: Vec<u64>,
+    /// Number of simulations to run in parallel. Defaults to the number of CPUs
+    #[serde(default)]
+    pub num_threads: Option<usize>,
+    /// File to write results to. If not given, they are written to stdout
+    #[serde(default)]
+    pub out_file: Option<String>,
+}
+
+/// Format of a config file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Pick the format from the file name's extension if it has a known one. Else, guess from the
+    /// contents: JSON configs are objects, so they start with `{`
+    pub fn detect(fname: Option<&str>, contents: &str) -> Self {
+        let ext = fname
+            .and_then(|f| std::path::Path::new(f).extension())
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("json") => Self::Json,
+            Some("yaml") | Some("yml") => Self::Yaml,
+            _ => {
+                if contents.trim_start().starts_with('{') {
+                    Self::Json
+                } else {
+                    Self::Yaml
+                }
+            }
+        }
+    }
+}
+
+/// Parse a config (e.g. `Config` or `SweepConfig`) in the given format
+pub fn parse_config<T: DeserializeOwned>(contents: &str, format: ConfigFormat) -> Result<T, Error> {
+    Ok(match format {
+        ConfigFormat::Json => serde_json::from_str(contents)?,
+        ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+    })
+}
+
+/// Write a config in the given format. Times, rates and sizes are written with units
+pub fn format_config<T: Serialize>(config: &T, format: ConfigFormat) -> Result<String, Error> {
+    crate::units::with_units(|| {
+        Ok(match format {
+            ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(config)?,
+        })
+    })
+}
+
+/// Read a config from a JSON or YAML file. See `ConfigFormat::detect` for how the format is chosen
+pub fn read_config_file<T: DeserializeOwned + JsonSchema>(fname: &str) -> Result<T, Error> {
+    load_config(fname, &[])
+}
+
+/// Read a config from a JSON or YAML file, or from stdin if `fname` is "-", and apply
+/// `overrides` to it before parsing. Each override is of the form `path=value` (see
+/// `apply_override`). The result is checked against the schema of `T` (see `config_schema`)
+pub fn load_config<T: DeserializeOwned + JsonSchema>(
+    fname: &str,
+    overrides: &[String],
+) -> Result<T, Error> {
+    let (contents, format) = if fname == "-" {
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut contents)?;
+        let format = ConfigFormat::detect(None, &contents);
+        (contents, format)
+    } else {
+        let contents = std::fs::read_to_string(fname)
+            .map_err(|e| format_err!("Could not read config file '{}': {}", fname, e))?;
+        let format = ConfigFormat::detect(Some(fname), &contents);
+        (contents, format)
+    };
+    let parse = || -> Result<T, Error> {
+        let mut value: serde_json::Value = parse_config(&contents, format)?;
+        let dir = std::path::Path::new(fname)
+            .parent()
+            .unwrap_or(std::path::Path::new(""));
+        let mut stack: Vec<_> = std::path::Path::new(fname)
+            .canonicalize()
+            .into_iter()
+            .collect();
+        let has_paths = resolve_paths(&mut value, dir);
+        let has_includes = resolve_includes(&mut value, dir, &mut stack)?;
+        // Paths given on the command line are relative to the working directory
+        for assignment in overrides {
+            apply_override(&mut value, assignment)?;
+        }
+        check_schema::<T>(&value)?;
+        if !has_paths && !has_includes && overrides.is_empty() {
+            // Parse directly, so errors point to the line they are on
+            return parse_config(&contents, format);
+        }
+        Ok(serde_json::from_value(value)?)
+    };
+    parse().map_err(|e| format_err!("Could not parse config file '{}': {}", fname, e))
+}
+
+/// JSON Schema of a config type (e.g. `Config` or `SweepConfig`), with doc comments as
+/// descriptions. Unlike the parser, the schema doesn't allow unknown fields, so that misspelled or
+/// outdated fields are caught. Fields starting with `_` are allowed, and can be used for comments
+pub fn config_schema<T: JsonSchema>() -> serde_json::Value {
+    /// Disallow unknown fields in every object that lists its fields
+    fn forbid_unknown_fields(schema: &mut serde_json::Value) {
+        match schema {
+            serde_json::Value::Object(map) => {
+                if map.contains_key("properties") && !map.contains_key("additionalProperties") {
+                    map.insert("additionalProperties".to_string(), false.into());
+                    map.insert(
+                        "patternProperties".to_string(),
+                        serde_json::json!({ "^_": {} }),
+                    );
+                }
+                map.values_mut().for_each(forbid_unknown_fields);
+            }
+            serde_json::Value::Array(arr) => arr.iter_mut().for_each(forbid_unknown_fields),
+            _ => {}
+        }
+    }
+    let mut schema = serde_json::to_value(schemars::schema_for!(T)).unwrap();
+    forbid_unknown_fields(&mut schema);
+    schema
+}
+
+/// Check a config against the schema of `T`, reporting every mismatch with its path
+pub fn check_schema<T: JsonSchema>(config: &serde_json::Value) -> Result<(), Error> {
+    let schema = config_schema::<T>();
+    let compiled = jsonschema::JSONSchema::compile(&schema)
+        .map_err(|e| format_err!("Invalid config schema: {}", e))?;
+    let res = compiled.validate(config);
+    if let Err(errors) = res {
+        let errors: Vec<String> = errors
+            .map(|e| {
+                // Write the path like the ones `Config::validate` reports
+                let mut path = String::new();
+                for chunk in e.instance_path.iter() {
+                    let key = match chunk {
+                        PathChunk::Index(i) => {
+                            path += &format!("[{}]", i);
+                            continue;
+                        }
+                        PathChunk::Property(key) => key.to_string(),
+                        PathChunk::Keyword(key) => key.to_string(),
+                    };
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path += &key;
+                }
+                if path.is_empty() {
+                    path.push_str("(top level)");
+                }
+                format!("{}: {}", path, e)
+            })
+            .collect();
+        return Err(format_err!(
+            "Config doesn't match the schema:\n  {}",
+            errors.join("\n  ")
+        ));
+    }
+    Ok(())
+}
+
+/// Replace every map with an `include` key by the file(s) it names, with the rest of the map
+/// merged on top (see `merge_config`). `include` is a file name or a list of them, merged in
+/// order, and relative to `dir`. Includes can be nested. Returns whether there were any
+fn resolve_includes(
+    value: &mut serde_json::Value,
+    dir: &std::path::Path,
+    stack: &mut Vec<std::path::PathBuf>,
+) -> Result<bool, Error> {
+    use serde_json::Value;
+    let mut found = false;
+    match value {
+        Value::Array(arr) => {
+            for elem in arr {
+                found |= resolve_includes(elem, dir, stack)?;
+            }
+        }
+        Value::Object(map) => {
+            for elem in map.values_mut() {
+                found |= resolve_includes(elem, dir, stack)?;
+            }
+            if let Some(include) = map.remove("include") {
+                let fnames = match include {
+                    Value::String(fname) => vec![Value::String(fname)],
+                    Value::Array(fnames) => fnames,
+                    _ => {
+                        return Err(format_err!(
+                            "`include` must be a file name or a list of them"
+                        ))
+                    }
+                };
+                let mut merged = Value::Object(Default::default());
+                for fname in fnames {
+                    let fname = fname.as_str().ok_or_else(|| {
+                        format_err!("`include` must be a file name or a list of them")
+                    })?;
+                    let path = dir.join(fname);
+                    let canonical = path.canonicalize().map_err(|e| {
+                        format_err!("Could not include '{}': {}", path.display(), e)
+                    })?;
+                    if stack.contains(&canonical) {
+                        return Err(format_err!("'{}' is included in a cycle", path.display()));
+                    }
+                    let contents = std::fs::read_to_string(&path).map_err(|e| {
+                        format_err!("Could not include '{}': {}", path.display(), e)
+                    })?;
+                    let mut included: Value =
+                        parse_config(&contents, ConfigFormat::detect(path.to_str(), &contents))
+                            .map_err(|e| {
+                                format_err!("In included file '{}': {}", path.display(), e)
+                            })?;
+                    stack.push(canonical);
+                    resolve_paths(&mut included, path.parent().unwrap());
+                    resolve_includes(&mut included, path.parent().unwrap(), stack)?;
+                    stack.pop();
+                    merge_config(&mut merged, included);
+                }
+                merge_config(&mut merged, Value::Object(std::mem::take(map)));
+                *value = merged;
+                found = true;
+            }
+        }
+        _ => {}
+    }
+    Ok(found)
+}
+
+/// Where configs name files, as (enum variant, field) pairs. The field is `None` if the variant
+/// holds the file name itself
+const PATH_FIELDS: [(&str, Option<&str>); 3] = [
+    ("MahimahiFile", None),
+    ("EmpiricalFile", None),
+    ("TraceFile", Some("file")),
+];
+
+/// Make the relative file names in a config file (e.g. of `MahimahiFile` links) relative to `dir`,
+/// the directory of the config file, rather than to the working directory. Files it includes are
+/// resolved relative to their own directory by `resolve_includes`. Returns whether any file names
+/// were changed
+fn resolve_paths(value: &mut serde_json::Value, dir: &std::path::Path) -> bool {
+    use serde_json::Value;
+    let mut changed = false;
+    let mut resolve = |fname: &mut Value| {
+        if let Value::String(fname) = fname {
+            let path = dir.join(fname.as_str());
+            if path.as_os_str() != fname.as_str() {
+                *fname = path.to_string_lossy().into_owned();
+                changed = true;
+            }
+        }
+    };
+    match value {
+        Value::Array(arr) => {
+            for elem in arr {
+                changed |= resolve_paths(elem, dir);
+            }
+        }
+        Value::Object(map) => {
+            for (variant, field) in PATH_FIELDS {
+                match (map.get_mut(variant), field) {
+                    (Some(fname), None) => resolve(fname),
+                    (Some(Value::Object(fields)), Some(field)) => {
+                        if let Some(fname) = fields.get_mut(field) {
+                            resolve(fname);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            for elem in map.values_mut() {
+                changed |= resolve_paths(elem, dir);
+            }
+        }
+        _ => {}
+    }
+    changed
+}
+
+/// Merge `over` into `base`. Maps are merged key by key, and everything else in `over` replaces
+/// what is in `base`. Maps with a single, different key each (like `{Const: 1}` and
+/// `{Piecewise: [...]}`) are different variants of an enum, so `over` replaces `base`
+fn merge_config(base: &mut serde_json::Value, over: serde_json::Value) {
+    use serde_json::Value;
+    match (base, over) {
+        (Value::Object(base), Value::Object(over))
+            if !(base.len() == 1 && over.len() == 1 && base.keys().ne(over.keys())) =>
+        {
+            for (key, val) in over {
+                match base.get_mut(&key) {
+                    Some(base_val) => merge_config(base_val, val),
+                    None => {
+                        base.insert(key, val);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Apply an override such as `topo.bufsize.Finite=100` or `topo.sender_groups[0].delay=50ms` to
+/// a config. The value is parsed as YAML, so it can be a number, a string or a whole structure
+/// such as `{Const: 12Mbps}`. Fields that don't exist yet are created. If a field on the path is
+/// not a map, it is replaced by one, e.g. to switch `bufsize` from `Infinite` to `Finite`
+pub fn apply_override(config: &mut serde_json::Value, assignment: &str) -> Result<(), Error> {
+    let (path, value) = assignment
+        .split_once('=')
+        .ok_or_else(|| format_err!("Override '{}' is not of the form path=value", assignment))?;
+    let value: serde_json::Value = serde_yaml::from_str(value)
+        .map_err(|e| format_err!("Could not parse value in override '{}': {}", assignment, e))?;
+
+    // Split "a.b[0].c" into "a", "b", "0", "c"
+    let keys: Vec<&str> = path
+        .split(['.', '['])
+        .map(|k| k.trim_end_matches(']'))
+        .collect();
+    if keys.iter().any(|k| k.is_empty()) {
+        return Err(format_err!("Invalid path in override '{}'", assignment));
+    }
+
+    let mut cur = config;
+    for key in keys {
+        cur = match cur {
+            serde_json::Value::Array(arr) => {
+                let len = arr.len();
+                key.parse::<usize>()
+                    .ok()
+                    .and_then(move |i| arr.get_mut(i))
+                    .ok_or_else(|| {
+                        format_err!(
+                            "In override '{}': '{}' is not an index into a list of length {}",
+                            assignment,
+                            key,
+                            len
+                        )
+                    })?
+            }
+            _ => {
+                if !cur.is_object() {
+                    *cur = serde_json::Value::Object(Default::default());
+                }
+                cur.as_object_mut()
+                    .unwrap()
+                    .entry(key)
+                    .or_insert(serde_json::Value::Null)
+            }
+        };
+    }
+    *cur = value;
+    Ok(())
+}
+
+/// Collects the problems found while validating a config, each with the path of the offending
+/// field
+#[derive(Default)]
+struct Validator {
+    errors: Vec<String>,
+    /// Needed to read trace files
+    pkt_size: u64,
+}
+
+impl Validator {
+    /// Record an error at `path` unless `ok`
+    fn check(&mut self, ok: bool, path: &str, msg: &str) {
+        if !ok {
+            self.errors.push(format!("{}: {}", path, msg));
+        }
+    }
+
+    fn check_positive(&mut self, val: f64, path: &str) {
+        self.check(
+            val.is_finite() && val > 0.,
+            path,
+            "must be a finite number greater than 0",
+        );
+    }
+
+    fn check_non_negative(&mut self, val: f64, path: &str) {
+        self.check(
+            val.is_finite() && val >= 0.,
+            path,
+            "must be a finite number that is at least 0",
+        );
+    }
+
+    /// `nonzero` requires that samples are not always 0 (or less), e.g. for times between events
+    fn random_variable(&mut self, var: &RandomVariable, nonzero: bool, path: &str) {
+        if nonzero {
+            self.check(
+                !var.always_non_positive(),
+                path,
+                "must not always be 0 or less, else time never advances",
+            );
+        }
+        match var {
+            RandomVariable::Const(val) => self.check_non_negative(*val, &format!("{}.Const", path)),
+            RandomVariable::Exponential(lambda) => {
+                self.check_positive(*lambda, &format!("{}.Exponential", path))
+            }
+            RandomVariable::Uniform { min, max } => {
+                let path = format!("{}.Uniform", path);
+                self.check(
+                    min.is_finite() && max.is_finite() && min <= max,
+                    &path,
+                    "`min` and `max` must be finite, with `min` at most `max`",
+                );
+            }
+            RandomVariable::Normal { mean, std_dev } => {
+                self.check(
+                    mean.is_finite(),
+                    &format!("{}.Normal.mean", path),
+                    "must be finite",
+                );
+                self.check_non_negative(*std_dev, &format!("{}.Normal.std_dev", path));
+            }
+            RandomVariable::TruncatedNormal {
+                mean,
+                std_dev,
+                min,
+                max,
+            } => {
+                let path = format!("{}.TruncatedNormal", path);
+                self.check(
+                    mean.is_finite(),
+                    &format!("{}.mean", path),
+                    "must be finite",
+                );
+                self.check_non_negative(*std_dev, &format!("{}.std_dev", path));
+                self.check(
+                    min.is_finite() && max.is_finite() && min <= max,
+                    &path,
+                    "`min` and `max` must be finite, with `min` at most `max`",
+                );
+            }
+            RandomVariable::LogNormal { mu, sigma } => {
+                self.check(
+                    mu.is_finite(),
+                    &format!("{}.LogNormal.mu", path),
+                    "must be finite",
+                );
+                self.check_non_negative(*sigma, &format!("{}.LogNormal.sigma", path));
+            }
+            RandomVariable::Pareto { scale, shape } => {
+                self.check_positive(*scale, &format!("{}.Pareto.scale", path));
+                self.check_positive(*shape, &format!("{}.Pareto.shape", path));
+            }
+            RandomVariable::Poisson(mean) => {
+                self.check_positive(*mean, &format!("{}.Poisson", path))
+            }
+            RandomVariable::Weibull { scale, shape } => {
+                self.check_positive(*scale, &format!("{}.Weibull.scale", path));
+                self.check_positive(*shape, &format!("{}.Weibull.shape", path));
+            }
+            RandomVariable::Mixture(components) => {
+                let path = format!("{}.Mixture", path);
+                self.check(
+                    components.iter().any(|(weight, _)| *weight > 0.),
+                    &path,
+                    "needs a component with a weight greater than 0",
+                );
+                for (i, (weight, var)) in components.iter().enumerate() {
+                    self.check_non_negative(*weight, &format!("{}[{}][0]", path, i));
+                    self.random_variable(var, false, &format!("{}[{}][1]", path, i));
+                }
+            }
+            // Checked when it is read
+            RandomVariable::EmpiricalFile(_) => {}
+        }
+    }
+
+    fn link(&mut self, link: &LinkTraceConfig, path: &str) {
+        match link {
+            LinkTraceConfig::Const(rate) => self.check_positive(*rate, &format!("{}.Const", path)),
+            // The samples are times between transmissions
+            LinkTraceConfig::Random(intersend) => {
+                self.random_variable(intersend, true, &format!("{}.Random", path))
+            }
+            LinkTraceConfig::Piecewise(rates) => {
+                let path = format!("{}.Piecewise", path);
+                self.check(
+                    rates.iter().any(|(rate, _)| *rate > 0.),
+                    &path,
+                    "must have at least one rate greater than 0",
+                );
+                for (i, (rate, dur)) in rates.iter().enumerate() {
+                    self.check_non_negative(*rate, &format!("{}[{}][0]", path, i));
+                    self.check(
+                        *dur > Time::ZERO,
+                        &format!("{}[{}][1]", path, i),
+                        "must be greater than 0",
+                    );
+                }
+            }
+            LinkTraceConfig::MahimahiFile(fname) => {
+                if let Err(e) = LinkTrace::new_mahimahi_from_file(std::path::Path::new(fname)) {
+                    self.check(
+                        false,
+                        &format!("{}.MahimahiFile", path),
+                        &format!("invalid trace file '{}': {}", fname, e),
+                    );
+                }
+            }
+            LinkTraceConfig::TraceFile { file, format } => {
+                if let Err(e) = LinkTrace::new_from_trace_file(file, *format, self.pkt_size) {
+                    self.check(
+                        false,
+                        &format!("{}.TraceFile", path),
+                        &format!("invalid trace file '{}': {}", file, e),
+                    );
+                }
+            }
+            LinkTraceConfig::Markov {
+                states,
+                initial_state,
+            } => {
+                let path = format!("{}.Markov", path);
+                self.check(
+                    states.iter().any(|s| s.rate > 0.),
+                    &format!("{}.states", path),
+                    "at least one state must have a rate greater than 0",
+                );
+                self.check(
+                    *initial_state < states.len(),
+                    &format!("{}.initial_state", path),
+                    "must be the index of a state",
+                );
+                for (i, state) in states.iter().enumerate() {
+                    let path = format!("{}.states[{}]", path, i);
+                    self.check_non_negative(state.rate, &format!("{}.rate", path));
+                    for (j, (next, rate)) in state.transitions.iter().enumerate() {
+                        let path = format!("{}.transitions[{}]", path, j);
+                        self.check(
+                            *next < states.len(),
+                            &format!("{}[0]", path),
+                            "must be the index of a state",
+                        );
+                        self.check_non_negative(*rate, &format!("{}[1]", path));
+                    }
+                    self.check(
+                        state.rate > 0. || state.transitions.iter().any(|(_, r)| *r > 0.),
+                        &path,
+                        "a state with rate 0 must have a transition out of it, else the link \
+                         never sends again",
+                    );
+                }
+                if *initial_state < states.len() {
+                    // Else the link never sends anything
+                    let mut reached = vec![false; states.len()];
+                    let mut stack = vec![*initial_state];
+                    reached[*initial_state] = true;
+                    while let Some(i) = stack.pop() {
+                        for (next, rate) in &states[i].transitions {
+                            if *rate > 0. && *next < states.len() && !reached[*next] {
+                                reached[*next] = true;
+                                stack.push(*next);
+                            }
+                        }
+                    }
+                    self.check(
+                        (0..states.len()).any(|i| reached[i] && states[i].rate > 0.),
+                        &format!("{}.states", path),
+                        "a state with a rate greater than 0 must be reachable from \
+                         `initial_state`",
+                    );
+                }
+            }
+            LinkTraceConfig::Cellular(params) => {
+                let path = format!("{}.Cellular", path);
+                self.check_positive(params.max_rate, &format!("{}.max_rate", path));
+                self.check(
+                    params.initial_rate.is_finite()
+                        && params.initial_rate >= 0.
+                        && params.initial_rate <= params.max_rate,
+                    &format!("{}.initial_rate", path),
+                    "must be at least 0 and at most `max_rate`",
+                );
+                self.check_non_negative(params.volatility, &format!("{}.volatility", path));
+                self.check(
+                    params.volatility > 0. || params.initial_rate > 0.,
+                    &format!("{}.volatility", path),
+                    "must be greater than 0 if `initial_rate` is 0, else the rate stays at 0",
+                );
+                self.check_positive(
+                    params.outage_escape_rate,
+                    &format!("{}.outage_escape_rate", path),
+                );
+                self.check(
+                    params.tick > Time::ZERO,
+                    &format!("{}.tick", path),
+                    "must be greater than 0",
+                );
+            }
+        }
+    }
+
+    fn link_events(&mut self, events: &LinkEventsConfig, path: &str) {
+        for (i, (_, dur)) in events.outages.iter().enumerate() {
+            self.check(
+                *dur > Time::ZERO,
+                &format!("{}.outages[{}][1]", path, i),
+                "must be greater than 0",
+            );
+        }
+        if let Some(random) = &events.random_outages {
+            let path = format!("{}.random_outages", path);
+            // Else outages start and end forever without time advancing
+            self.random_variable(&random.interval, true, &format!("{}.interval", path));
+            self.random_variable(&random.duration, false, &format!("{}.duration", path));
+        }
+        for (i, switch) in events.path_switches.iter().enumerate() {
+            if let Some(link) = &switch.link {
+                self.link(link, &format!("{}.path_switches[{}].link", path, i));
+            }
+        }
+    }
+
+    fn cc(&mut self, cc: &CCConfig, delay: Time, path: &str) {
+        match cc {
+            CCConfig::Const { cwnd, .. } => self.check(
+                *cwnd > 0,
+                &format!("{}.Const.cwnd", path),
+                "must be greater than 0",
+            ),
+            CCConfig::OscInstantCC { k, omega } => {
+                self.check_positive(*k, &format!("{}.OscInstantCC.k", path));
+                self.check_non_negative(*omega, &format!("{}.OscInstantCC.omega", path));
+            }
+            CCConfig::StableLinearCC { alpha, k } => {
+                self.check_positive(*alpha, &format!("{}.StableLinearCC.alpha", path));
+                self.check(
+                    k.is_finite() && *k > std::f64::consts::FRAC_PI_4,
+                    &format!("{}.StableLinearCC.k", path),
+                    "must be greater than pi/4 for stability",
+                );
+                // It uses the delay as its minimum RTT
+                self.check(
+                    delay > Time::ZERO,
+                    &format!("{}.StableLinearCC", path),
+                    "needs `delay` to be greater than 0",
+                );
+            }
+            CCConfig::Copa2 => {
+                self.check(
+                    delay > Time::ZERO,
+                    &format!("{}.Copa2", path),
+                    "needs `delay` to be greater than 0",
+                );
+            }
+            CCConfig::AIMD | CCConfig::InstantCC | CCConfig::IncreaseBdpCC | CCConfig::Copa => {}
+        }
+    }
+
+    fn media(&mut self, media: &MediaConfig, path: &str) {
+        self.check_positive(media.fps, &format!("{}.fps", path));
+        self.check_positive(media.keyframe_factor, &format!("{}.keyframe_factor", path));
+        self.check_positive(media.min_bitrate, &format!("{}.min_bitrate", path));
+        self.check_positive(media.max_bitrate, &format!("{}.max_bitrate", path));
+        self.check(
+            media.min_bitrate <= media.start_bitrate && media.start_bitrate <= media.max_bitrate,
+            &format!("{}.start_bitrate", path),
+            "must be between `min_bitrate` and `max_bitrate`",
+        );
+        self.check(
+            media.encoder_gain > 0. && media.encoder_gain <= 1.,
+            &format!("{}.encoder_gain", path),
+            "must be greater than 0 and at most 1",
+        );
+        self.check_positive(media.pacing_factor, &format!("{}.pacing_factor", path));
+        self.check(
+            media.feedback_interval > Time::ZERO,
+            &format!("{}.feedback_interval", path),
+            "must be greater than 0",
+        );
+        if let Some(timeout) = media.feedback_timeout {
+            self.check(
+                timeout > Time::ZERO,
+                &format!("{}.feedback_timeout", path),
+                "must be greater than 0",
+            );
+        }
+        if let RateControlConfig::Const(rate) = media.rate_control {
+            self.check_positive(rate, &format!("{}.rate_control.Const", path));
+        }
+    }
+
+    fn video(&mut self, video: &VideoConfig, path: &str) {
+        self.check(
+            !video.bitrates.is_empty(),
+            &format!("{}.bitrates", path),
+            "must not be empty",
+        );
+        for (i, bitrate) in video.bitrates.iter().enumerate() {
+            self.check_positive(*bitrate, &format!("{}.bitrates[{}]", path, i));
+        }
+        self.check(
+            video.bitrates.windows(2).all(|w| w[0] < w[1]),
+            &format!("{}.bitrates", path),
+            "must be in increasing order",
+        );
+        self.check(
+            video.chunk_dur > Time::ZERO,
+            &format!("{}.chunk_dur", path),
+            "must be greater than 0",
+        );
+        self.check(
+            video.max_buffer >= video.chunk_dur,
+            &format!("{}.max_buffer", path),
+            "must be at least `chunk_dur`, else no chunk is ever requested",
+        );
+        self.check(
+            video.startup_buffer <= video.max_buffer,
+            &format!("{}.startup_buffer", path),
+            "must be at most `max_buffer`, else playback never starts",
+        );
+        match video.abr {
+            AbrConfig::BufferBased { cushion, .. } => self.check(
+                cushion > Time::ZERO,
+                &format!("{}.abr.BufferBased.cushion", path),
+                "must be greater than 0",
+            ),
+            AbrConfig::RateBased { window, safety } => {
+                self.check(
+                    window > 0,
+                    &format!("{}.abr.RateBased.window", path),
+                    "must be greater than 0",
+                );
+                self.check_positive(safety, &format!("{}.abr.RateBased.safety", path));
+            }
+            AbrConfig::Mpc {
+                horizon,
+                rebuf_penalty,
+                switch_penalty,
+            } => {
+                self.check(
+                    horizon > 0,
+                    &format!("{}.abr.Mpc.horizon", path),
+                    "must be greater than 0",
+                );
+                self.check(
+                    horizon <= 5,
+                    &format!("{}.abr.Mpc.horizon", path),
+                    "must be at most 5, else trying every sequence of bitrates takes too long",
+                );
+                self.check_non_negative(rebuf_penalty, &format!("{}.abr.Mpc.rebuf_penalty", path));
+                self.check_non_negative(
+                    switch_penalty,
+                    &format!("{}.abr.Mpc.switch_penalty", path),
+                );
+            }
+        }
+    }
+
+    fn sender_group(&mut self, group: &SenderGroupConfig, path: &str) {
+        self.random_variable(
+            &group.agg_intersend,
+            false,
+            &format!("{}.agg_intersend", path),
+        );
+        self.cc(&group.cc, group.delay, &format!("{}.cc", path));
+        match group.tx_length {
+            TcpSenderTxLength::Duration(dur) => self.check(
+                dur > Time::ZERO,
+                &format!("{}.tx_length.Duration", path),
+                "must be greater than 0",
+            ),
+            TcpSenderTxLength::Bytes(bytes) => self.check(
+                bytes > 0,
+                &format!("{}.tx_length.Bytes", path),
+                "must be greater than 0",
+            ),
+            TcpSenderTxLength::Infinite => {}
+        }
+        if let BufferSize::Finite(size) = group.rcv_bufsize {
+            self.check(
+                size > 0,
+                &format!("{}.rcv_bufsize.Finite", path),
+                "must be greater than 0",
+            );
+        }
+        if let Some(rate) = group.app_read_rate {
+            self.check_positive(rate, &format!("{}.app_read_rate", path));
+        }
+        match group.loss_detection {
+            LossDetectionConfig::DupAck(n) => self.check(
+                n > 0,
+                &format!("{}.loss_detection.DupAck", path),
+                "must be greater than 0",
+            ),
+            LossDetectionConfig::RackTlp { reo_wnd } => self
+                .check_non_negative(reo_wnd, &format!("{}.loss_detection.RackTlp.reo_wnd", path)),
+        }
+        let rto = &group.rto;
+        self.check(
+            rto.min > Time::ZERO,
+            &format!("{}.rto.min", path),
+            "must be greater than 0",
+        );
+        self.check(
+            rto.min <= rto.max,
+            &format!("{}.rto.max", path),
+            "must be at least `rto.min`",
+        );
+        self.check(
+            rto.min <= rto.initial && rto.initial <= rto.max,
+            &format!("{}.rto.initial", path),
+            "must be between `rto.min` and `rto.max`",
+        );
+        self.check(
+            rto.max_backoff > 0,
+            &format!("{}.rto.max_backoff", path),
+            "must be greater than 0",
+        );
+        match &group.transport {
+            TransportConfig::Tcp => {
+                if let AppConfig::Video(video) = &group.app {
+                    self.video(video, &format!("{}.app.Video", path));
+                }
+            }
+            TransportConfig::Media(media) => {
+                self.media(media, &format!("{}.transport.Media", path))
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Check that the config describes a simulation that can run, so that mistakes are reported
+    /// up front instead of as panics deep inside the simulation. Reports every problem found,
+    /// one per line, along with the path of the field (e.g. `topo.sender_groups[0].cc`)
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut v = Validator {
+            pkt_size: self.pkt_size,
+            ..Default::default()
+        };
+        v.check(self.pkt_size > 0, "pkt_size", "must be greater than 0");
+        if let Some(sim_dur) = self.sim_dur {
+            v.check(sim_dur > Time::ZERO, "sim_dur", "must be greater than 0");
+        }
+
+        v.check(
+            self.log.link_bucket_size > Time::ZERO,
+            "log.link_bucket_size",
+            "must be greater than 0",
+        );
+        for (i, (start, end)) in self.log.stats_intervals.iter().enumerate() {
+            if let Some(end) = end {
+                v.check(
+                    end > start,
+                    &format!("log.stats_intervals[{}]", i),
+                    "end must be after start",
+                );
+            }
+            if let Some(sim_dur) = self.sim_dur {
+                v.check(
+                    *start < sim_dur,
+                    &format!("log.stats_intervals[{}]", i),
+                    "must start before the end of the simulation (`sim_dur`)",
+                );
+            }
+        }
+
+        v.link(&self.topo.link, "topo.link");
+        v.link_events(&self.topo.link_events, "topo.link_events");
+        if let Some(wifi) = &self.topo.wifi {
+            v.check_positive(wifi.phy_rate, "topo.wifi.phy_rate");
+            v.random_variable(&wifi.contention, false, "topo.wifi.contention");
+            v.check(
+                wifi.max_aggregate > 0,
+                "topo.wifi.max_aggregate",
+                "must be greater than 0",
+            );
+            v.check(
+                wifi.max_airtime > Time::ZERO,
+                "topo.wifi.max_airtime",
+                "must be greater than 0",
+            );
+            // Else aggregates of small packets could take no time at all
+            v.check(
+                wifi.overhead > Time::ZERO,
+                "topo.wifi.overhead",
+                "must be greater than 0",
+            );
+            if let BufferSize::Finite(size) = wifi.bufsize {
+                v.check(
+                    size > 0,
+                    "topo.wifi.bufsize.Finite",
+                    "must be greater than 0",
+                );
+            }
+        }
+        for (i, group) in self.topo.sender_groups.iter().enumerate() {
+            v.sender_group(group, &format!("topo.sender_groups[{}]", i));
+        }
+        for (i, series) in self.topo.sender_group_series.iter().enumerate() {
+            let path = format!("topo.sender_group_series[{}]", i);
+            v.check(
+                series.count > 0,
+                &format!("{}.count", path),
+                "must be greater than 0",
+            );
+            // Constraints that depend on the delay need to hold for every group in the series
+            for group in series.groups() {
+                v.sender_group(&group, &format!("{}.group", path));
+            }
+        }
+        v.check(
+            !self.topo.all_sender_groups().is_empty(),
+            "topo",
+            "needs at least one of `sender_groups` or `sender_group_series`",
+        );
+
+        // Groups in a series repeat the same problems
+        v.errors.dedup();
+        if v.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!("Invalid config:\n  {}", v.errors.join("\n  ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config with the given link, which is valid if the link is
+    fn config(link: &str) -> Config {
+        serde_yaml::from_str(&format!(
+            "pkt_size: 1500
+sim_dur: 10s
+random_seed: 0
+log:
+  out_terminal: png
+  out_file: out.png
+  cwnd: Ignore
+  rtt: Ignore
+  sender_losses: Ignore
+  timeouts: Ignore
+  link_rates: Ignore
+  stats_intervals: []
+  stats_file: null
+  link_bucket_size: 1s
+topo:
+  link: {}
+  bufsize: Infinite
+  sender_groups:
+    - num_senders: 1
+      delay: 10ms
+      agg_intersend: {{Const: 0}}
+      cc: AIMD
+      start_time: 0
+      tx_length: Infinite
+",
+            link
+        ))
+        .unwrap()
+    }
+
+    fn errors(config: &Config) -> String {
+        match config.validate() {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn markov_needs_reachable_positive_rate() {
+        // State 0 never leaves, so state 1's rate doesn't help
+        let with_link = config(
+            "{Markov: {states: [{rate: 0, transitions: [[0, 10.0]]}, \
+             {rate: 1000000, transitions: []}]}}",
+        );
+        assert!(errors(&with_link).contains("must be reachable from `initial_state`"));
+
+        let with_link = config(
+            "{Markov: {states: [{rate: 0, transitions: [[1, 10.0]]}, \
+             {rate: 1000000, transitions: []}]}}",
+        );
+        assert_eq!(errors(&with_link), "");
+    }
+
+    #[test]
+    fn cellular_needs_a_way_out_of_zero_rate() {
+        let with_link = config("{Cellular: {initial_rate: 0, volatility: 0}}");
+        assert!(errors(&with_link).contains("link.Cellular.volatility"));
+
+        let with_link = config("{Cellular: {initial_rate: 100000, volatility: 0}}");
+        assert_eq!(errors(&with_link), "");
+    }
+
+    #[test]
+    fn mpc_horizon_is_capped() {
+        let mut with_video = config("{Const: 1000000}");
+        let video = |horizon| {
+            AppConfig::Video(VideoConfig {
+                bitrates: vec![100_000., 200_000.],
+                chunk_dur: Time::from_secs(4),
+                num_chunks: None,
+                max_buffer: Time::from_secs(30),
+                startup_buffer: Time::from_secs(4),
+                abr: AbrConfig::Mpc {
+                    horizon,
+                    rebuf_penalty: 1e6,
+                    switch_penalty: 1.,
+                },
+            })
+        };
+        with_video.topo.sender_groups[0].app = video(5);
+        assert_eq!(errors(&with_video), "");
+        with_video.topo.sender_groups[0].app = video(20);
+        assert!(errors(&with_video).contains("abr.Mpc.horizon: must be at most 5"));
+    }
+}
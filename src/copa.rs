@@ -1,7 +1,10 @@
 use crate::rtt_window::RTTWindow;
-use crate::simulator::{PktId, SeqNum, Time};
+use crate::simulator::{impl_save_state, PktId, SeqNum, Time};
 use crate::transport::CongestionControl;
 
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Copa {
     base_rtt: RTTWindow,
     standing_rtt: RTTWindow,
@@ -20,6 +23,8 @@ impl Default for Copa {
     }
 }
 
+impl_save_state!(Copa);
+
 impl CongestionControl for Copa {
     fn on_ack(&mut self, now: Time, _cum_ack: SeqNum, _ack_uid: PktId, rtt: Time, num_lost: u64) {
         // Multiplicatively decrease on loss
@@ -1,10 +1,12 @@
 use crate::rtt_window::RTTWindow;
-use crate::simulator::{PktId, SeqNum, Time};
+use crate::simulator::{impl_save_state, PktId, SeqNum, Time};
 use crate::transport::CongestionControl;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, VecDeque};
 
 /// Data recorded for each transmitted packet. This information is retrieved and used when the
 /// packet is acked
+#[derive(Clone, Serialize, Deserialize)]
 struct PktData {
     /// The total number of bytes pkts that had been acked when the packet was sent. On ack, this
     /// is used to calculate the total number of bytes acked in the period from transmission to
@@ -16,6 +18,7 @@ struct PktData {
     sent_time: Time,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Copa2 {
     /// Number of packets to maintain in queue in addition to $max_{s \in senders} D_s$. Analogous
     /// to 1 / delta in Copa1
@@ -58,6 +61,8 @@ impl Copa2 {
     }
 }
 
+impl_save_state!(Copa2);
+
 impl CongestionControl for Copa2 {
     fn on_ack(&mut self, now: Time, _cum_ack: SeqNum, ack_uid: PktId, rtt: Time, num_lost: u64) {
         self.num_pkts_acked += 1;
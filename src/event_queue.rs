@@ -0,0 +1,223 @@
+//! The priority queue the scheduler keeps its calendar in
+
+use crate::simulator::Time;
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// A monotone radix heap. Works only when items are never pushed with a time earlier than that of
+/// the last item popped, which always holds in a discrete event simulator. Items with the same
+/// time come out in the order they were pushed. Pushes take O(1) and pops O(log of the time range)
+/// amortized time, and no allocations are made once the buckets have grown to their working size
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>"))]
+pub struct EventQueue<T> {
+    /// Items due at `last`, in the order they were pushed
+    due: VecDeque<(u64, T)>,
+    /// Bucket `i` holds items whose time first differs from `last` in bit `i` (counting from the
+    /// least significant bit). Within a bucket, items are in the order they were pushed. Format:
+    /// (time, seq, item)
+    buckets: Vec<Vec<(Time, u64, T)>>,
+    /// Time of the last item popped. All items in the queue are due at this time or later
+    last: Time,
+    /// Number of items ever pushed. Used as the sequence number of the next item
+    num_pushed: u64,
+    len: usize,
+    /// Reused when redistributing a bucket, so its allocation is not lost
+    #[serde(skip)]
+    scratch: Vec<(Time, u64, T)>,
+}
+
+impl<T> Default for EventQueue<T> {
+    fn default() -> Self {
+        Self {
+            due: VecDeque::new(),
+            buckets: (0..64).map(|_| Vec::new()).collect(),
+            last: Time::ZERO,
+            num_pushed: 0,
+            len: 0,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+impl<T> EventQueue<T> {
+    /// Add an item to be popped at `time`. Returns its sequence number, which is unique and is
+    /// also returned when it is popped. Panics if `time` is earlier than the last time popped
+    pub fn push(&mut self, time: Time, item: T) -> u64 {
+        assert!(time >= self.last, "Pushed item into the past");
+        let seq = self.num_pushed;
+        self.num_pushed += 1;
+        self.len += 1;
+        if time == self.last {
+            self.due.push_back((seq, item));
+        } else {
+            let i = 63 - (time.micros() ^ self.last.micros()).leading_zeros() as usize;
+            self.buckets[i].push((time, seq, item));
+        }
+        seq
+    }
+
+    /// Remove the earliest item, unless it is due after `till`. Returns its time, sequence
+    /// number and the item. Items may still be pushed at the time of the last item popped, even
+    /// if this returned `None`
+    pub fn pop(&mut self, till: Option<Time>) -> Option<(Time, u64, T)> {
+        if self.due.is_empty() {
+            // Redistribute the first non-empty bucket around its earliest time. Every item moves
+            // to a lower bucket or to `due`, and relative order is preserved. Items in lower
+            // buckets are always earlier than those in higher buckets
+            let i = self.buckets.iter().position(|b| !b.is_empty())?;
+            let earliest = self.buckets[i].iter().map(|x| x.0).min().unwrap();
+            if till.is_some_and(|till| earliest > till) {
+                return None;
+            }
+            self.last = earliest;
+            let mut scratch =
+                std::mem::replace(&mut self.buckets[i], std::mem::take(&mut self.scratch));
+            for (time, seq, item) in scratch.drain(..) {
+                if time == self.last {
+                    self.due.push_back((seq, item));
+                } else {
+                    let j = 63 - (time.micros() ^ self.last.micros()).leading_zeros() as usize;
+                    self.buckets[j].push((time, seq, item));
+                }
+            }
+            self.scratch = scratch;
+        }
+        if till.is_some_and(|till| self.last > till) {
+            return None;
+        }
+        let (seq, item) = self.due.pop_front().unwrap();
+        self.len -= 1;
+        Some((self.last, seq, item))
+    }
+
+    /// Number of items in the queue
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn drain(queue: &mut EventQueue<u32>, till: Option<Time>) -> Vec<(u64, u32)> {
+        let mut res = Vec::new();
+        while let Some((time, _, item)) = queue.pop(till) {
+            res.push((time.micros(), item));
+        }
+        res
+    }
+
+    #[test]
+    fn pops_in_time_order() {
+        let mut queue = EventQueue::default();
+        for (i, t) in [50, 3, 1 << 40, 7, 0, 64, 63, 65].iter().enumerate() {
+            queue.push(Time::from_micros(*t), i as u32);
+        }
+        assert_eq!(queue.len(), 8);
+        let times: Vec<u64> = drain(&mut queue, None).iter().map(|x| x.0).collect();
+        assert_eq!(times, [0, 3, 7, 50, 63, 64, 65, 1 << 40]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn equal_times_are_fifo() {
+        let mut queue = EventQueue::default();
+        // Items at the same time end up in the same bucket, in `due`, or are pushed after others
+        // at that time were redistributed
+        let seqs: Vec<u64> = [10, 5, 10, 0, 10, 5]
+            .iter()
+            .enumerate()
+            .map(|(i, t)| queue.push(Time::from_micros(*t), i as u32))
+            .collect();
+        assert_eq!(seqs, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(queue.pop(None), Some((Time::ZERO, 3, 3)));
+        assert_eq!(queue.pop(None), Some((Time::from_micros(5), 1, 1)));
+        queue.push(Time::from_micros(5), 6);
+        queue.push(Time::from_micros(10), 7);
+        assert_eq!(
+            drain(&mut queue, None),
+            [(5, 5), (5, 6), (10, 0), (10, 2), (10, 4), (10, 7)]
+        );
+    }
+
+    #[test]
+    fn stops_at_till() {
+        let mut queue = EventQueue::default();
+        for t in [1, 2, 5, 9] {
+            queue.push(Time::from_micros(t), t as u32);
+        }
+        // Items due exactly at `till` are popped
+        assert_eq!(
+            drain(&mut queue, Some(Time::from_micros(5))),
+            [(1, 1), (2, 2), (5, 5)]
+        );
+        assert_eq!(queue.pop(Some(Time::from_micros(8))), None);
+        assert_eq!(queue.len(), 1);
+        // Items can still be pushed at the time of the last pop, and before those not popped
+        queue.push(Time::from_micros(5), 50);
+        queue.push(Time::from_micros(6), 6);
+        assert_eq!(
+            drain(&mut queue, Some(Time::from_micros(8))),
+            [(5, 50), (6, 6)]
+        );
+        assert_eq!(drain(&mut queue, None), [(9, 9)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pushed item into the past")]
+    fn rejects_past_items() {
+        let mut queue = EventQueue::default();
+        queue.push(Time::from_micros(10), 0);
+        queue.pop(None);
+        queue.push(Time::from_micros(9), 1);
+    }
+
+    #[test]
+    fn interleaved_push_and_pop_match_sorting() {
+        // Compare against a set ordered by (time, seq), with times that jump by anything from 0 to
+        // 2^50us. The queue is pushed into after every pop, as in a simulation
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut queue = EventQueue::default();
+        let mut reference = std::collections::BTreeSet::new();
+        let mut now = 0;
+        for i in 0..20_000u32 {
+            let num_pushes = rng.gen_range(0, 4);
+            for _ in 0..num_pushes {
+                let bits = rng.gen_range(0, 51);
+                let delay = match rng.gen_range(0, 4) {
+                    0 => 0,
+                    _ => rng.gen_range(0, 1u64 << bits),
+                };
+                let seq = queue.push(Time::from_micros(now + delay), i);
+                reference.insert((now + delay, seq, i));
+            }
+            // Sometimes stop at a time that may be before the earliest item
+            let bits = rng.gen_range(0, 51);
+            let till = match rng.gen_range(0, 2) {
+                0 => None,
+                _ => Some(now + rng.gen_range(0, 1u64 << bits)),
+            };
+            match queue.pop(till.map(Time::from_micros)) {
+                Some((time, seq, item)) => {
+                    assert!(till.is_none_or(|till| time.micros() <= till));
+                    assert_eq!((time.micros(), seq, item), reference.pop_first().unwrap());
+                    now = time.micros();
+                }
+                None => {
+                    if let Some(first) = reference.first() {
+                        assert!(till.is_some_and(|till| first.0 > till));
+                    }
+                }
+            }
+            assert_eq!(queue.len(), reference.len());
+        }
+        for (time, seq, item) in reference {
+            assert_eq!(queue.pop(None), Some((Time::from_micros(time), seq, item)));
+        }
+        assert_eq!(queue.pop(None), None);
+    }
+}
@@ -0,0 +1,268 @@
+//! A delay-based rate controller for real-time media in the style of Google Congestion Control
+//! (draft-ietf-rmcat-gcc-02), combined with its loss-based controller. Simplified: packets are
+//! grouped into bursts by send time, and overuse is detected with the trendline estimator on the
+//! one-way delay gradient
+
+use crate::media::{PktReport, RateControl};
+use crate::simulator::*;
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Packets sent within this long of the first packet of a group are treated as one burst
+const BURST_INTERVAL: Time = Time::from_micros(5_000);
+/// Number of delay samples over which the trendline is computed
+const TRENDLINE_WINDOW: usize = 20;
+/// Weight of new samples when smoothing the accumulated delay
+const SMOOTHING_COEF: f64 = 0.9;
+/// The trend is scaled by this (and the number of samples) before comparing with the threshold
+const THRESHOLD_GAIN: f64 = 4.;
+/// Overuse has to persist for this long before we react
+const OVERUSE_TIME: Time = Time::from_micros(10_000);
+/// The receive rate is measured over this window
+const RECV_RATE_WINDOW: Time = Time::from_micros(500_000);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum BandwidthUsage {
+    Normal,
+    Overuse,
+    Underuse,
+}
+
+/// A group of packets sent in a short burst
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct PktGroup {
+    first_sent: Time,
+    last_sent: Time,
+    last_arrival: Time,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Gcc {
+    min_rate: f64,
+    max_rate: f64,
+    /// Rate recommended by the delay-based controller (bytes per second)
+    delay_rate: f64,
+    /// Rate recommended by the loss-based controller (bytes per second)
+    loss_rate: f64,
+    /// The last complete group, and the group currently being received
+    prev_group: Option<PktGroup>,
+    cur_group: Option<PktGroup>,
+    /// Accumulated delay gradient and its smoothed version (in ms)
+    acc_delay: f64,
+    smoothed_delay: f64,
+    /// Samples for the trendline. Format: (arrival time in ms, smoothed delay in ms)
+    delay_hist: VecDeque<(f64, f64)>,
+    /// Total number of delay samples so far
+    num_deltas: u64,
+    /// Previous output of the trendline estimator
+    prev_trend: f64,
+    /// Adaptive threshold for overuse detection (in ms)
+    threshold: f64,
+    last_threshold_update: Option<Time>,
+    /// When the trend first exceeded the threshold, if it still does
+    overuse_start: Option<Time>,
+    state: BandwidthUsage,
+    /// Last time the delay-based rate was updated
+    last_rate_update: Option<Time>,
+    /// Recently received packets, to measure the receive rate. Format: (arrival time, size)
+    recv_hist: VecDeque<(Time, u64)>,
+}
+
+impl Gcc {
+    pub fn new(start_rate: f64, min_rate: f64, max_rate: f64) -> Self {
+        Self {
+            min_rate,
+            max_rate,
+            delay_rate: start_rate,
+            loss_rate: start_rate,
+            prev_group: None,
+            cur_group: None,
+            acc_delay: 0.,
+            smoothed_delay: 0.,
+            delay_hist: VecDeque::new(),
+            num_deltas: 0,
+            prev_trend: 0.,
+            threshold: 12.5,
+            last_threshold_update: None,
+            overuse_start: None,
+            state: BandwidthUsage::Normal,
+            last_rate_update: None,
+            recv_hist: VecDeque::new(),
+        }
+    }
+
+    /// Receive rate (in bytes per second) over the last `RECV_RATE_WINDOW`
+    fn recv_rate(&self) -> Option<f64> {
+        if self.recv_hist.len() < 2 {
+            return None;
+        }
+        let bytes: u64 = self.recv_hist.iter().map(|x| x.1).sum();
+        Some(bytes as f64 / RECV_RATE_WINDOW.secs())
+    }
+
+    /// Add a received packet to its burst group. When a group completes, feed the delay
+    /// variation between it and the previous group to the trendline estimator
+    fn on_arrival(&mut self, now: Time, sent_time: Time, arrival: Time) {
+        let cur = if let Some(cur) = &mut self.cur_group {
+            cur
+        } else {
+            self.cur_group = Some(PktGroup {
+                first_sent: sent_time,
+                last_sent: sent_time,
+                last_arrival: arrival,
+            });
+            return;
+        };
+        if sent_time < cur.first_sent + BURST_INTERVAL {
+            cur.last_sent = std::cmp::max(cur.last_sent, sent_time);
+            cur.last_arrival = std::cmp::max(cur.last_arrival, arrival);
+            return;
+        }
+
+        let cur = *cur;
+        if let Some(prev) = self.prev_group {
+            let send_delta = cur.last_sent.millis() - prev.last_sent.millis();
+            let arrival_delta = cur.last_arrival.millis() - prev.last_arrival.millis();
+            self.update_trendline(now, arrival_delta - send_delta, cur.last_arrival);
+        }
+        self.prev_group = Some(cur);
+        self.cur_group = Some(PktGroup {
+            first_sent: sent_time,
+            last_sent: sent_time,
+            last_arrival: arrival,
+        });
+    }
+
+    fn update_trendline(&mut self, now: Time, delay_delta: f64, arrival: Time) {
+        self.num_deltas += 1;
+        self.acc_delay += delay_delta;
+        self.smoothed_delay =
+            SMOOTHING_COEF * self.smoothed_delay + (1. - SMOOTHING_COEF) * self.acc_delay;
+        self.delay_hist
+            .push_back((arrival.millis(), self.smoothed_delay));
+        if self.delay_hist.len() > TRENDLINE_WINDOW {
+            self.delay_hist.pop_front();
+        }
+        if self.delay_hist.len() < TRENDLINE_WINDOW {
+            return;
+        }
+
+        // Slope of the least-squares fit of smoothed delay against arrival time
+        let n = self.delay_hist.len() as f64;
+        let mean_x = self.delay_hist.iter().map(|x| x.0).sum::<f64>() / n;
+        let mean_y = self.delay_hist.iter().map(|x| x.1).sum::<f64>() / n;
+        let (mut num, mut den) = (0., 0.);
+        for (x, y) in &self.delay_hist {
+            num += (x - mean_x) * (y - mean_y);
+            den += (x - mean_x) * (x - mean_x);
+        }
+        let slope = if den == 0. { 0. } else { num / den };
+        let trend = std::cmp::min(self.num_deltas, 60) as f64 * slope * THRESHOLD_GAIN;
+        self.detect(now, trend);
+        self.prev_trend = trend;
+    }
+
+    /// Compare the trend with the adaptive threshold to decide whether the link is overused
+    fn detect(&mut self, now: Time, trend: f64) {
+        if trend > self.threshold {
+            let start = *self.overuse_start.get_or_insert(now);
+            if now - start >= OVERUSE_TIME && trend >= self.prev_trend {
+                self.state = BandwidthUsage::Overuse;
+            }
+        } else if trend < -self.threshold {
+            self.overuse_start = None;
+            self.state = BandwidthUsage::Underuse;
+        } else {
+            self.overuse_start = None;
+            self.state = BandwidthUsage::Normal;
+        }
+
+        // Adapt the threshold, so we neither starve against loss-based flows nor react to noise
+        let dt = match self.last_threshold_update {
+            Some(last) => f64::min((now - last).millis(), 100.),
+            None => 0.,
+        };
+        self.last_threshold_update = Some(now);
+        if trend.abs() > self.threshold + 15. {
+            // Don't adapt to sudden spikes
+            return;
+        }
+        let k = if trend.abs() < self.threshold {
+            0.039
+        } else {
+            0.0087
+        };
+        self.threshold += k * (trend.abs() - self.threshold) * dt;
+        self.threshold = self.threshold.clamp(6., 600.);
+    }
+
+    /// Update the delay-based rate according to the current state of the detector
+    fn update_delay_rate(&mut self, now: Time) {
+        let dt = match self.last_rate_update {
+            Some(last) => f64::min((now - last).secs(), 1.),
+            None => 0.,
+        };
+        self.last_rate_update = Some(now);
+        let recv_rate = self.recv_rate();
+
+        match self.state {
+            BandwidthUsage::Overuse => {
+                if let Some(recv_rate) = recv_rate {
+                    self.delay_rate = f64::min(self.delay_rate, 0.85 * recv_rate);
+                }
+            }
+            BandwidthUsage::Normal => self.delay_rate *= 1.08f64.powf(dt),
+            BandwidthUsage::Underuse => {}
+        }
+        // Don't run away from what the network is actually delivering
+        if let Some(recv_rate) = recv_rate {
+            self.delay_rate = f64::min(self.delay_rate, 1.5 * recv_rate);
+        }
+        self.delay_rate = self.delay_rate.clamp(self.min_rate, self.max_rate);
+    }
+
+    /// Update the loss-based rate from the loss fraction in the latest feedback
+    fn update_loss_rate(&mut self, reports: &[PktReport]) {
+        if reports.is_empty() {
+            return;
+        }
+        let num_lost = reports.iter().filter(|r| r.arrival.is_none()).count();
+        let loss = num_lost as f64 / reports.len() as f64;
+        if loss > 0.1 {
+            self.loss_rate *= 1. - 0.5 * loss;
+        } else if loss < 0.02 {
+            self.loss_rate *= 1.05;
+        }
+        self.loss_rate = self.loss_rate.clamp(self.min_rate, self.max_rate);
+    }
+}
+
+impl_save_state!(Gcc);
+
+impl RateControl for Gcc {
+    fn on_feedback(&mut self, now: Time, reports: &[PktReport]) {
+        for report in reports {
+            if let Some(arrival) = report.arrival {
+                self.recv_hist.push_back((arrival, report.size));
+                self.on_arrival(now, report.sent_time, arrival);
+            }
+        }
+        while let Some((arrival, _)) = self.recv_hist.front() {
+            if *arrival + RECV_RATE_WINDOW < now {
+                self.recv_hist.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        self.update_delay_rate(now);
+        self.update_loss_rate(reports);
+    }
+
+    fn on_send(&mut self, _: Time, _: SeqNum, _: u64) {}
+
+    fn get_rate(&mut self) -> f64 {
+        f64::min(self.delay_rate, self.loss_rate)
+    }
+}
@@ -0,0 +1,30 @@
+//! A discrete-event simulator that allows for rapid prototyping of congestion control algorithms.
+//!
+//! Typical use is to build a `Scheduler` from a `Config` with `create_topology`, and then either
+//! run it to completion with `simulate`, or drive it with `step` and `run_until` while inspecting
+//! the objects in it with `obj_state`.
+
+pub mod abr;
+pub mod base;
+pub mod cc;
+pub mod config;
+pub mod copa;
+pub mod copa2;
+mod event_queue;
+pub mod gcc;
+pub mod media;
+pub mod random;
+pub mod rtt_window;
+pub mod simulator;
+pub mod sweep;
+pub mod topology;
+pub mod trace;
+pub mod tracer;
+pub mod transport;
+pub mod units;
+
+pub use config::Config;
+pub use simulator::{NetObj, NetObjId, SaveState, SavedState, Scheduler, Time};
+pub use topology::create_topology;
+pub use tracer::Tracer;
+pub use transport::CongestionControl;
@@ -1,111 +1,428 @@
-mod base;
-mod cc;
-mod config;
-mod copa;
-mod copa2;
-mod random;
-mod rtt_window;
-mod simulator;
-mod topology;
-mod tracer;
-mod transport;
-
 // Internal dependencies.
-use base::BufferSize;
-use config::{
-    CCConfig, Config, ConfigLog, ConfigTopo, LinkTraceConfig, LogType, SenderGroupConfig,
+use cc_sim::base::BufferSize;
+use cc_sim::config::{
+    config_schema, format_config, load_config, read_config_file, AppConfig, CCConfig, Config,
+    ConfigFormat, ConfigLog, ConfigTopo, LinkTraceConfig, LogType, LossDetectionConfig, RtoConfig,
+    SenderGroupConfig, SweepConfig, TransportConfig,
 };
-use random::seed;
-use simulator::*;
-use topology::create_topology;
-use tracer::Tracer;
-use transport::*;
+use cc_sim::random;
+use cc_sim::simulator::*;
+use cc_sim::sweep::run_sweep;
+use cc_sim::topology::create_topology;
+use cc_sim::trace::{Trace, TraceFormat};
+use cc_sim::tracer::Tracer;
+use cc_sim::transport::*;
 
 // External dependencies.
-use failure::Error;
+use clap::{Parser, Subcommand, ValueEnum};
+use failure::{format_err, Error};
+
+/// A discrete-event simulator for prototyping congestion control algorithms
+#[derive(Parser)]
+#[command(name = "cc_sim")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the simulation described by a config file ("-" reads it from stdin)
+    Run {
+        config: String,
+        /// Override a value in the config, e.g. `--set topo.bufsize.Finite=100`. Can be repeated
+        #[arg(long = "set", value_name = "PATH=VALUE")]
+        overrides: Vec<String>,
+    },
+    /// Run a parameter sweep described by a sweep config file ("-" reads it from stdin)
+    Sweep {
+        config: String,
+        /// Override a value in the sweep config, e.g. `--set base.sim_dur=10s`. Can be repeated
+        #[arg(long = "set", value_name = "PATH=VALUE")]
+        overrides: Vec<String>,
+    },
+    /// Check config files for mistakes without running them
+    Validate {
+        #[arg(required = true)]
+        configs: Vec<String>,
+        /// Override a value in each config before checking it. Can be repeated
+        #[arg(long = "set", value_name = "PATH=VALUE")]
+        overrides: Vec<String>,
+    },
+    /// Print the built-in default config, as a starting point for new configs
+    PrintDefaultConfig {
+        #[arg(long, value_enum, default_value_t = Format::Yaml)]
+        format: Format,
+    },
+    /// Print the JSON Schema that configs are checked against, for use by editors and other tools
+    PrintSchema {
+        /// Print the schema of sweep configs instead
+        #[arg(long)]
+        sweep: bool,
+    },
+    /// Summarize a link trace file: its mean rate and how much the rate varies
+    TraceInfo {
+        trace: String,
+        #[arg(long, value_enum, default_value_t = TraceFileFormat::Mahimahi)]
+        format: TraceFileFormat,
+        /// Size of the packet delivered at each opportunity in the trace
+        #[arg(long, default_value_t = 1500)]
+        pkt_size: u64,
+        /// Report the distribution of the link rate over windows of this length
+        #[arg(long, default_value = "1s")]
+        window: Time,
+    },
+    /// Convert a link trace file to another format. Files ending in `.gz` are compressed
+    TraceConvert {
+        input: String,
+        /// Output file ("-" for stdout)
+        #[arg(short, long)]
+        output: String,
+        #[arg(long, value_enum, default_value_t = TraceFileFormat::Mahimahi)]
+        from: TraceFileFormat,
+        #[arg(long, value_enum, default_value_t = TraceFileFormat::Mahimahi)]
+        to: TraceFileFormat,
+        /// Size of the packet delivered at each opportunity in the trace
+        #[arg(long, default_value_t = 1500)]
+        pkt_size: u64,
+        /// Length of the windows over which rates are computed for the time-rate format
+        #[arg(long, default_value = "100ms")]
+        window: Time,
+    },
+    /// Scale a link trace file by multiplying its rate and stretching its time
+    TraceScale {
+        input: String,
+        /// Output file ("-" for stdout)
+        #[arg(short, long)]
+        output: String,
+        #[arg(long, value_enum, default_value_t = TraceFileFormat::Mahimahi)]
+        format: TraceFileFormat,
+        /// Multiply the rate by this
+        #[arg(long, default_value_t = 1.)]
+        rate: f64,
+        /// Stretch time by this factor, which also divides the rate by it
+        #[arg(long, default_value_t = 1.)]
+        time: f64,
+        /// Size of the packet delivered at each opportunity in the trace
+        #[arg(long, default_value_t = 1500)]
+        pkt_size: u64,
+    },
+    /// Join link trace files one after the other
+    TraceSplice {
+        #[arg(required = true)]
+        inputs: Vec<String>,
+        /// Output file ("-" for stdout)
+        #[arg(short, long)]
+        output: String,
+        #[arg(long, value_enum, default_value_t = TraceFileFormat::Mahimahi)]
+        format: TraceFileFormat,
+        /// Size of the packet delivered at each opportunity in the trace
+        #[arg(long, default_value_t = 1500)]
+        pkt_size: u64,
+    },
+    /// Run simulations without producing output, and report how fast events are processed
+    Bench {
+        #[arg(required = true)]
+        configs: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Json,
+    Yaml,
+}
 
-fn main() -> Result<(), Error> {
-    let args: Vec<_> = std::env::args().collect();
-    let usage_string = format!("Usage: {} stdin|file|default [config_file_name]", &args[0]);
+/// See `TraceFormat`
+#[derive(Clone, Copy, ValueEnum)]
+enum TraceFileFormat {
+    Mahimahi,
+    TimeBytes,
+    TimeRate,
+}
 
-    if args.len() < 2 {
-        eprintln!("{}", usage_string);
-        return Ok(());
+impl From<TraceFileFormat> for TraceFormat {
+    fn from(format: TraceFileFormat) -> Self {
+        match format {
+            TraceFileFormat::Mahimahi => TraceFormat::Mahimahi,
+            TraceFileFormat::TimeBytes => TraceFormat::TimeBytes,
+            TraceFileFormat::TimeRate => TraceFormat::TimeRate,
+        }
     }
+}
 
-    let config = if args[1] == "stdin" {
-        serde_json::from_reader(std::io::stdin().lock())?
-    } else if args[1] == "file" {
-        if args.len() != 3 {
-            eprintln!("Please specify name of config file");
-            return Ok(());
+fn main() {
+    // Usage errors exit with status 2, and errors while running with 1
+    let cli = Cli::parse();
+    if let Err(e) = run(cli.command) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<(), Error> {
+    match command {
+        Command::Run { config, overrides } => {
+            let config: Config = load_config(&config, &overrides)?;
+            let tracer = Tracer::new(&config);
+            let mut sched = create_topology(&config, &tracer)?;
+            sched.simulate(config.sim_dur)?;
+            tracer.finalize()?;
         }
-        serde_json::from_reader(std::fs::File::open(&args[2])?)?
-    } else if args[1] == "default" {
-        // Pick a default config
-        // Three variants of links to choose from
-        let _c_link_trace = LinkTraceConfig::Const(15_000_000.);
-        let _p_link_trace = LinkTraceConfig::Piecewise(vec![
-            (1_500_000., Time::from_secs(20)),
-            (15_000_000., Time::from_secs(20)),
-        ]);
-        let _m_link_trace = LinkTraceConfig::MahimahiFile("traces/ATT-LTE-driving.up".to_string());
-
-        // Configurations for different CC algorithms
-        let _osc_instant_cc_config = CCConfig::OscInstantCC {
-            k: 1.,
-            omega: 6.28 * 10.,
-        };
-        let _stable_linear_cc_config = CCConfig::StableLinearCC { alpha: 0.1, k: 0.8 };
-
-        // Configure senders
-        let mut sender_groups = Vec::new();
-        for i in 0..2 {
-            sender_groups.push(SenderGroupConfig {
-                num_senders: 1,
-                delay: Time::from_millis(10),
-                agg_intersend: random::RandomVariable::Const(0.),
-                cc: _stable_linear_cc_config.clone(),
-                start_time: Time::from_secs(i * 10),
-                tx_length: TcpSenderTxLength::Duration(Time::from_secs(100 - i * 20)),
-            });
+        Command::Sweep { config, overrides } => {
+            let sweep: SweepConfig = load_config(&config, &overrides)?;
+            run_sweep(&sweep)?;
         }
-
-        // Create configuration
-        Config {
-            pkt_size: 1500,
-            sim_dur: Some(Time::from_secs(100)),
-            topo: ConfigTopo {
-                link: _p_link_trace,
-                bufsize: BufferSize::Finite(50000),
-                sender_groups,
-            },
-            log: ConfigLog {
-                out_terminal: "png size 600,400".to_string(),
-                out_file: "out.png".to_string(),
-                cwnd: LogType::Plot,
-                rtt: LogType::Plot,
-                sender_losses: LogType::Ignore,
-                timeouts: LogType::Ignore,
-                link_rates: LogType::Plot,
-                stats_intervals: vec![(Time::from_secs(0), None)],
-                stats_file: None,
-                link_bucket_size: Time::from_millis(200),
-            },
-            random_seed: 0,
+        Command::Validate { configs, overrides } => validate(&configs, &overrides)?,
+        Command::PrintDefaultConfig { format } => {
+            let format = match format {
+                Format::Json => ConfigFormat::Json,
+                Format::Yaml => ConfigFormat::Yaml,
+            };
+            println!("{}", format_config(&default_config(), format)?);
+        }
+        Command::PrintSchema { sweep } => {
+            let schema = if sweep {
+                config_schema::<SweepConfig>()
+            } else {
+                config_schema::<Config>()
+            };
+            println!("{}", serde_json::to_string_pretty(&schema)?);
         }
-    } else {
-        eprintln!("{}", usage_string);
-        return Ok(());
+        Command::TraceInfo {
+            trace,
+            format,
+            pkt_size,
+            window,
+        } => trace_info(&trace, format.into(), pkt_size, window)?,
+        Command::TraceConvert {
+            input,
+            output,
+            from,
+            to,
+            pkt_size,
+            window,
+        } => read_trace(&input, from.into(), pkt_size)?.to_file(
+            &output,
+            to.into(),
+            pkt_size,
+            window,
+        )?,
+        Command::TraceScale {
+            input,
+            output,
+            format,
+            rate,
+            time,
+            pkt_size,
+        } => {
+            if !(rate.is_finite() && rate >= 0. && time.is_finite() && time > 0.) {
+                return Err(format_err!(
+                    "--rate must be at least 0 and --time greater than 0"
+                ));
+            }
+            let trace = read_trace(&input, format.into(), pkt_size)?.scale(rate, time);
+            write_trace(&trace, &output, format.into(), pkt_size)?
+        }
+        Command::TraceSplice {
+            inputs,
+            output,
+            format,
+            pkt_size,
+        } => {
+            let traces = inputs
+                .iter()
+                .map(|input| read_trace(input, format.into(), pkt_size))
+                .collect::<Result<Vec<_>, _>>()?;
+            write_trace(&Trace::splice(&traces), &output, format.into(), pkt_size)?
+        }
+        Command::Bench { configs } => bench(&configs)?,
+    }
+    Ok(())
+}
+
+/// A config for two senders sharing a link whose rate changes every 20s
+fn default_config() -> Config {
+    // Three variants of links to choose from
+    let _c_link_trace = LinkTraceConfig::Const(15_000_000.);
+    let _p_link_trace = LinkTraceConfig::Piecewise(vec![
+        (1_500_000., Time::from_secs(20)),
+        (15_000_000., Time::from_secs(20)),
+    ]);
+    let _m_link_trace = LinkTraceConfig::MahimahiFile("traces/ATT-LTE-driving.up".to_string());
+
+    // Configurations for different CC algorithms
+    let _osc_instant_cc_config = CCConfig::OscInstantCC {
+        k: 1.,
+        omega: 6.28 * 10.,
     };
+    let _stable_linear_cc_config = CCConfig::StableLinearCC { alpha: 0.1, k: 0.8 };
 
-    seed(config.random_seed);
-    let tracer = Tracer::new(&config);
-    let mut sched = create_topology(&config, &tracer)?;
+    // Configure senders
+    let mut sender_groups = Vec::new();
+    for i in 0..2 {
+        sender_groups.push(SenderGroupConfig {
+            num_senders: 1,
+            delay: Time::from_millis(10),
+            agg_intersend: random::RandomVariable::Const(0.),
+            cc: _stable_linear_cc_config.clone(),
+            start_time: Time::from_secs(i * 10),
+            tx_length: TcpSenderTxLength::Duration(Time::from_secs(100 - i * 20)),
+            rcv_bufsize: BufferSize::Infinite,
+            app_read_rate: None,
+            loss_detection: LossDetectionConfig::DupAck(3),
+            rto: RtoConfig::default(),
+            transport: TransportConfig::Tcp,
+            app: AppConfig::Bulk,
+        });
+    }
 
-    sched.simulate(config.sim_dur)?;
+    // Create configuration
+    Config {
+        pkt_size: 1500,
+        sim_dur: Some(Time::from_secs(100)),
+        topo: ConfigTopo {
+            link: _p_link_trace,
+            bufsize: BufferSize::Finite(50000),
+            sender_groups,
+            sender_group_series: Vec::new(),
+            link_events: Default::default(),
+            wifi: None,
+        },
+        log: ConfigLog {
+            out_terminal: "png size 600,400".to_string(),
+            out_file: "out.png".to_string(),
+            cwnd: LogType::Plot,
+            rtt: LogType::Plot,
+            sender_losses: LogType::Ignore,
+            timeouts: LogType::Ignore,
+            link_rates: LogType::Plot,
+            stats_intervals: vec![(Time::from_secs(0), None)],
+            stats_file: None,
+            link_bucket_size: Time::from_millis(200),
+        },
+        random_seed: 0,
+    }
+}
+
+/// Check each config, reporting all problems found in it
+fn validate(configs: &[String], overrides: &[String]) -> Result<(), Error> {
+    let mut num_invalid = 0;
+    for fname in configs {
+        let res = load_config::<Config>(fname, overrides).and_then(|config| config.validate());
+        match res {
+            Ok(()) => println!("{}: ok", fname),
+            Err(e) => {
+                println!("{}: {}", fname, e);
+                num_invalid += 1;
+            }
+        }
+    }
+    if num_invalid > 0 {
+        return Err(format_err!(
+            "{} of {} configs are invalid",
+            num_invalid,
+            configs.len()
+        ));
+    }
+    Ok(())
+}
+
+fn read_trace(fname: &str, format: TraceFormat, pkt_size: u64) -> Result<Trace, Error> {
+    Trace::from_file(fname, format, pkt_size)
+        .map_err(|e| format_err!("Could not read trace file '{}': {}", fname, e))
+}
+
+/// Write a trace in the same format it was read in. Rates in the time-rate format are given
+/// over windows of 100ms
+fn write_trace(
+    trace: &Trace,
+    fname: &str,
+    format: TraceFormat,
+    pkt_size: u64,
+) -> Result<(), Error> {
+    trace
+        .to_file(fname, format, pkt_size, Time::from_millis(100))
+        .map_err(|e| format_err!("Could not write trace file '{}': {}", fname, e))
+}
+
+/// Print the duration, mean rate and distribution of rates over windows of a trace
+fn trace_info(fname: &str, format: TraceFormat, pkt_size: u64, window: Time) -> Result<(), Error> {
+    if window == Time::ZERO {
+        return Err(format_err!("Window must be greater than 0"));
+    }
+    let trace = read_trace(fname, format, pkt_size)?;
+    let mbps = |bytes_per_sec: f64| bytes_per_sec * 8. / 1e6;
 
-    tracer.finalize()?;
+    // The last window may be cut short by the end of the trace, so it is left out unless it is
+    // the only one
+    let mut rates: Vec<f64> = trace.window_rates(pkt_size, window);
+    if rates.len() > 1 && trace.duration.micros() % window.micros() != 0 {
+        rates.pop();
+    }
+    let mean = rates.iter().sum::<f64>() / rates.len() as f64;
+    let std_dev =
+        (rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / rates.len() as f64).sqrt();
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let percentile = |p: f64| mbps(rates[((rates.len() - 1) as f64 * p).round() as usize]);
 
+    println!("Trace: {}", fname);
+    println!("Delivery opportunities: {}", trace.opportunities.len());
+    println!("Duration: {} (the trace loops after this)", trace.duration);
+    println!(
+        "Mean rate: {:.3} Mbps with {} byte packets",
+        mbps(trace.mean_rate(pkt_size)),
+        pkt_size
+    );
+    println!(
+        "Rate over {} windows (Mbps): min {:.3}, p5 {:.3}, median {:.3}, p95 {:.3}, max {:.3}",
+        window,
+        percentile(0.),
+        percentile(0.05),
+        percentile(0.5),
+        percentile(0.95),
+        percentile(1.)
+    );
+    println!(
+        "Variability over {} windows: std dev {:.3} Mbps, coefficient of variation {:.3}",
+        window,
+        mbps(std_dev),
+        if mean > 0. { std_dev / mean } else { 0. }
+    );
+    Ok(())
+}
+
+/// Run the simulation for each of the given config files without producing any output, and report
+/// how fast the simulator processes events
+fn bench(config_files: &[String]) -> Result<(), Error> {
+    let (mut total_actions, mut total_secs) = (0, 0.);
+    for config_file in config_files {
+        let config: Config = read_config_file(config_file)?;
+        let tracer = Tracer::new(&config);
+        let mut sched = create_topology(&config, &tracer)?;
+
+        let start = std::time::Instant::now();
+        sched.simulate(config.sim_dur)?;
+        let secs = start.elapsed().as_secs_f64();
+
+        println!(
+            "{}: {} events in {:.3}s ({:.0} events/s), at most {} pending",
+            config_file,
+            sched.num_actions(),
+            secs,
+            sched.num_actions() as f64 / secs,
+            sched.max_pending()
+        );
+        total_actions += sched.num_actions();
+        total_secs += secs;
+    }
+    if config_files.len() > 1 {
+        println!(
+            "Total: {} events in {:.3}s ({:.0} events/s)",
+            total_actions,
+            total_secs,
+            total_actions as f64 / total_secs
+        );
+    }
     Ok(())
 }
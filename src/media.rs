@@ -0,0 +1,849 @@
+//! Unreliable datagram transport with per-packet feedback, and a model of a real-time media source
+//! (e.g. a video encoder) that sends over it
+
+use crate::config::{Config, MediaConfig};
+use crate::simulator::*;
+use crate::tracer::{TraceElem, Tracer};
+use crate::transport::{TcpSenderTxLength, TransportHeader};
+
+use failure::Error;
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// What the sender learns about a single datagram from feedback
+#[derive(Clone, Copy, Debug)]
+pub struct PktReport {
+    /// Size of the datagram (in bytes)
+    pub size: u64,
+    /// When the datagram was sent
+    pub sent_time: Time,
+    /// When the datagram arrived at the receiver. `None` if it was lost
+    pub arrival: Option<Time>,
+}
+
+/// Congestion control for real-time media. Unlike `CongestionControl`, it outputs a sending rate,
+/// which is used both to pace packets and as the encoder's target bitrate
+pub trait RateControl: CloneRateControl + SaveState {
+    /// Called each time feedback arrives. Reports are in the order the datagrams were sent
+    fn on_feedback(&mut self, now: Time, reports: &[PktReport]);
+    /// Called each time a datagram is sent
+    fn on_send(&mut self, now: Time, seq_num: SeqNum, size: u64);
+    /// The rate (in bytes per second) at which we should send
+    fn get_rate(&mut self) -> f64;
+}
+
+/// Lets us clone `Box<dyn RateControl>`. Implemented for every `RateControl` that is `Clone`
+pub trait CloneRateControl {
+    fn clone_box(&self) -> Box<dyn RateControl>;
+}
+
+impl<T: RateControl + Clone + 'static> CloneRateControl for T {
+    fn clone_box(&self) -> Box<dyn RateControl> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn RateControl> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+impl RateControl for Box<dyn RateControl> {
+    fn on_feedback(&mut self, now: Time, reports: &[PktReport]) {
+        (**self).on_feedback(now, reports)
+    }
+    fn on_send(&mut self, now: Time, seq_num: SeqNum, size: u64) {
+        (**self).on_send(now, seq_num, size)
+    }
+    fn get_rate(&mut self) -> f64 {
+        (**self).get_rate()
+    }
+}
+
+/// Sends at a constant rate, irrespective of feedback
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ConstRate {
+    rate: f64,
+}
+
+impl ConstRate {
+    pub fn new(rate: f64) -> Self {
+        Self { rate }
+    }
+}
+
+impl_save_state!(ConstRate, DatagramReceiver);
+
+impl RateControl for ConstRate {
+    fn on_feedback(&mut self, _: Time, _: &[PktReport]) {}
+
+    fn on_send(&mut self, _: Time, _: SeqNum, _: u64) {}
+
+    fn get_rate(&mut self) -> f64 {
+        self.rate
+    }
+}
+
+/// Model of a real-time video encoder. Produces frames at a fixed frame rate, with periodic
+/// keyframes that are larger than the other frames. Its target bitrate follows the rate given by
+/// congestion control
+#[derive(Clone, Serialize, Deserialize)]
+struct MediaSource {
+    config: MediaConfig,
+    /// The encoder's current target bitrate (in bytes per second)
+    bitrate: f64,
+    /// Number of frames produced so far
+    num_frames: u64,
+}
+
+impl MediaSource {
+    fn new(config: MediaConfig) -> Self {
+        Self {
+            bitrate: config.start_bitrate,
+            num_frames: 0,
+            config,
+        }
+    }
+
+    /// Time between two frames
+    fn frame_interval(&self) -> Time {
+        Time::from_micros((1e6 / self.config.fps) as u64)
+    }
+
+    /// Move the target bitrate towards the rate recommended by congestion control. Real encoders
+    /// can't change their rate abruptly, so we only move `encoder_gain` of the way there
+    fn adapt(&mut self, rate: f64) {
+        let target = rate.clamp(self.config.min_bitrate, self.config.max_bitrate);
+        self.bitrate += self.config.encoder_gain * (target - self.bitrate);
+    }
+
+    /// Produce the next frame. Returns its size (in bytes), which is larger for keyframes
+    fn next_frame(&mut self) -> u64 {
+        let interval = self.config.keyframe_interval;
+        let is_key = if interval == 0 {
+            self.num_frames == 0
+        } else {
+            self.num_frames.is_multiple_of(interval)
+        };
+        self.num_frames += 1;
+
+        // Scale frame sizes so the average rate over a keyframe interval matches the bitrate
+        let avg_size = self.bitrate / self.config.fps;
+        let k = self.config.keyframe_factor;
+        let base_size = if interval == 0 {
+            avg_size
+        } else {
+            let n = interval as f64;
+            avg_size * n / (n - 1. + k)
+        };
+        let size = if is_key { base_size * k } else { base_size };
+        std::cmp::max(1, size as u64)
+    }
+}
+
+/// A frame that has been encoded, but is not yet known to be delivered or lost
+#[derive(Clone, Serialize, Deserialize)]
+struct FrameInfo {
+    /// When the frame was produced by the encoder
+    capture_time: Time,
+    /// Number of datagrams the frame was split into
+    num_pkts: u64,
+    /// Number of those that are known to have arrived
+    num_arrived: u64,
+    /// Arrival time of the latest datagram of this frame
+    last_arrival: Time,
+}
+
+/// A datagram that has been sent, but not reported on yet
+#[derive(Clone, Serialize, Deserialize)]
+struct SentDatagram {
+    frame_id: u64,
+    size: u64,
+    sent_time: Time,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum MediaSenderEvent {
+    /// The encoder produces a frame
+    Frame,
+    /// The pacer sends a datagram
+    Pace,
+}
+
+/// Sends frames from a `MediaSource` as unreliable datagrams, paced at the rate given by a
+/// `RateControl`. Lost datagrams are not retransmitted
+#[derive(Clone)]
+pub struct MediaSender<'a, R: RateControl + 'static> {
+    /// The hop on which to send packets
+    next: NetObjId,
+    /// The address of this sender
+    addr: Addr,
+    /// The destination to which we are communicating
+    dest: Addr,
+    /// Decides the sending rate
+    rc: R,
+    source: MediaSource,
+    /// Datagrams waiting to be sent by the pacer. Format: (frame id, size)
+    pacer_queue: VecDeque<(u64, u64)>,
+    /// Whether the pacer has a transmission scheduled
+    pace_scheduled: bool,
+    /// Last time the pacer sent a datagram
+    last_tx_time: Time,
+    /// Sequence number of the next datagram
+    next_seq: SeqNum,
+    /// Datagrams that have been sent, but not reported on yet
+    in_flight: BTreeMap<SeqNum, SentDatagram>,
+    /// Datagrams in flight for longer than this are counted as lost
+    feedback_timeout: Time,
+    /// Frames that have been encoded but are not yet known to be delivered or lost
+    frames: BTreeMap<u64, FrameInfo>,
+    /// Number of bytes produced by the encoder so far
+    bytes_encoded: u64,
+    /// Time when the flow should start
+    start_time: Time,
+    /// How much should it transmit
+    tx_length: TcpSenderTxLength,
+    /// Map event uids to events
+    event_uid_map: EventUidMap<MediaSenderEvent>,
+    /// Tracer for events and measurements
+    tracer: &'a Tracer<'a>,
+    config: &'a Config,
+}
+
+impl<'a, R: RateControl + 'static> MediaSender<'a, R> {
+    /// `next` is the next hop to which packets should be forwarded. `dest` is the destination the
+    /// packet should be sent to. `addr` is our (the sender's) address. `start_time` is the time at
+    /// which the encoder starts producing frames. `tx_length` is the duration/size of the flow
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        next: NetObjId,
+        addr: Addr,
+        dest: Addr,
+        rc: R,
+        media_config: MediaConfig,
+        start_time: Time,
+        tx_length: TcpSenderTxLength,
+        tracer: &'a Tracer,
+        config: &'a Config,
+    ) -> Self {
+        Self {
+            next,
+            addr,
+            dest,
+            rc,
+            feedback_timeout: media_config.feedback_timeout.unwrap_or(Time::from_secs(1)),
+            source: MediaSource::new(media_config),
+            pacer_queue: VecDeque::new(),
+            pace_scheduled: false,
+            last_tx_time: Time::ZERO,
+            next_seq: 0,
+            in_flight: BTreeMap::new(),
+            frames: BTreeMap::new(),
+            bytes_encoded: 0,
+            start_time,
+            tx_length,
+            event_uid_map: EventUidMap::new(),
+            tracer,
+            config,
+        }
+    }
+
+    /// Whether the encoder should stop producing frames
+    fn has_ended(&self, now: Time) -> bool {
+        match self.tx_length {
+            TcpSenderTxLength::Duration(time) => self.start_time + time < now,
+            TcpSenderTxLength::Bytes(bytes) => self.bytes_encoded >= bytes,
+            TcpSenderTxLength::Infinite => false,
+        }
+    }
+
+    /// Time the pacer should wait after sending a datagram of the given size
+    fn pacing_gap(&mut self, size: u64) -> Time {
+        let rate = self.rc.get_rate() * self.source.config.pacing_factor;
+        Time::from_micros((1e6 * size as f64 / rate) as u64)
+    }
+
+    /// Schedule the pacer if it has packets to send and isn't already scheduled
+    fn schedule_pacer(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) {
+        if self.pace_scheduled || self.pacer_queue.is_empty() {
+            return;
+        }
+        self.pace_scheduled = true;
+        let when = std::cmp::max(now, self.last_tx_time);
+        out.push((
+            when,
+            obj_id,
+            self.event_uid_map.new_event(MediaSenderEvent::Pace),
+        ));
+    }
+
+    /// Count the datagrams that have been in flight for longer than `feedback_timeout` as lost,
+    /// since we may never hear about them if the feedback on them was lost. Adds reports for them
+    /// to `reports` and returns how many there were
+    fn expire_in_flight(
+        &mut self,
+        obj_id: NetObjId,
+        now: Time,
+        reports: &mut Vec<PktReport>,
+    ) -> u64 {
+        let mut num_lost = 0;
+        while let Some(entry) = self.in_flight.first_entry() {
+            if entry.get().sent_time + self.feedback_timeout >= now {
+                break;
+            }
+            let sent = entry.remove();
+            num_lost += 1;
+            self.process_report(obj_id, now, sent.frame_id, None);
+            reports.push(PktReport {
+                size: sent.size,
+                sent_time: sent.sent_time,
+                arrival: None,
+            });
+        }
+        num_lost
+    }
+
+    /// Update the frames with the given feedback, and trace frames that were delivered or lost
+    fn process_report(
+        &mut self,
+        obj_id: NetObjId,
+        now: Time,
+        frame_id: u64,
+        arrival: Option<Time>,
+    ) {
+        let frame = if let Some(frame) = self.frames.get_mut(&frame_id) {
+            frame
+        } else {
+            // We already know the fate of this frame
+            return;
+        };
+        if let Some(arrival) = arrival {
+            frame.num_arrived += 1;
+            frame.last_arrival = std::cmp::max(frame.last_arrival, arrival);
+            if frame.num_arrived == frame.num_pkts {
+                let delay = frame.last_arrival - frame.capture_time;
+                self.frames.remove(&frame_id);
+                self.tracer
+                    .log(obj_id, now, TraceElem::MediaFrameDelivered(delay));
+            }
+        } else {
+            // A frame can't be decoded if any of its datagrams are lost
+            self.frames.remove(&frame_id);
+            self.tracer.log(obj_id, now, TraceElem::MediaFrameLost);
+        }
+    }
+}
+
+impl<'a, R: RateControl + 'static> SaveState for MediaSender<'a, R> {
+    save_fields!(
+        source,
+        pacer_queue,
+        pace_scheduled,
+        last_tx_time,
+        next_seq,
+        in_flight,
+        frames,
+        bytes_encoded,
+        event_uid_map;
+        rc
+    );
+}
+
+impl<'a, R: RateControl + Clone + 'static> NetObj for MediaSender<'a, R> {
+    fn init(&mut self, obj_id: NetObjId, _now: Time, out: &mut Actions) -> Result<(), Error> {
+        let frame_event = self.event_uid_map.new_event(MediaSenderEvent::Frame);
+        out.push((self.start_time, obj_id, frame_event));
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        obj_id: NetObjId,
+        _from: NetObjId,
+        now: Time,
+        pkt: Packet,
+        _out: &mut Actions,
+    ) -> Result<(), Error> {
+        assert_eq!(pkt.dest, self.addr);
+        let arrivals = if let TransportHeader::Feedback { arrivals } = &pkt.ptype {
+            arrivals
+        } else {
+            unreachable!()
+        };
+
+        // Reports are in the order datagrams were sent, and the expired ones were sent first
+        let mut reports = Vec::with_capacity(arrivals.len());
+        let mut num_lost = self.expire_in_flight(obj_id, now, &mut reports);
+        let (mut num_delivered, mut bytes_delivered) = (0, 0);
+        for (seq_num, arrival) in arrivals {
+            let sent = if let Some(sent) = self.in_flight.remove(seq_num) {
+                sent
+            } else {
+                // Already reported
+                continue;
+            };
+            if arrival.is_some() {
+                num_delivered += 1;
+                bytes_delivered += sent.size;
+            } else {
+                num_lost += 1;
+            }
+            self.process_report(obj_id, now, sent.frame_id, *arrival);
+            reports.push(PktReport {
+                size: sent.size,
+                sent_time: sent.sent_time,
+                arrival: *arrival,
+            });
+        }
+
+        self.tracer.log(
+            obj_id,
+            now,
+            TraceElem::MediaPktDelivered(num_delivered, bytes_delivered),
+        );
+        self.tracer
+            .log(obj_id, now, TraceElem::MediaPktLost(num_lost));
+        self.rc.on_feedback(now, &reports);
+        Ok(())
+    }
+
+    fn event(
+        &mut self,
+        obj_id: NetObjId,
+        from: NetObjId,
+        now: Time,
+        uid: u64,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        assert_eq!(obj_id, from);
+        let event = self.event_uid_map.retrieve(uid).unwrap();
+
+        match event {
+            MediaSenderEvent::Frame => {
+                if self.has_ended(now) {
+                    return Ok(());
+                }
+                // Encode a frame and hand it to the pacer
+                let rate = self.rc.get_rate();
+                self.source.adapt(rate);
+                let size = self.source.next_frame();
+                self.bytes_encoded += size;
+                let frame_id = self.source.num_frames;
+                let pkt_size = self.config.pkt_size;
+                let num_pkts = size.div_ceil(pkt_size);
+                for i in 0..num_pkts {
+                    let pkt_len = std::cmp::min(pkt_size, size - i * pkt_size);
+                    self.pacer_queue.push_back((frame_id, pkt_len));
+                }
+                self.frames.insert(
+                    frame_id,
+                    FrameInfo {
+                        capture_time: now,
+                        num_pkts,
+                        num_arrived: 0,
+                        last_arrival: Time::ZERO,
+                    },
+                );
+                self.tracer.log(
+                    obj_id,
+                    now,
+                    TraceElem::MediaEncoderRate(self.source.bitrate as u64),
+                );
+
+                let next_frame = self.event_uid_map.new_event(MediaSenderEvent::Frame);
+                out.push((now + self.source.frame_interval(), obj_id, next_frame));
+                self.schedule_pacer(obj_id, now, out);
+            }
+            MediaSenderEvent::Pace => {
+                self.pace_scheduled = false;
+                // Don't wait for feedback to tell rate control about datagrams that timed out
+                let mut reports = Vec::new();
+                let num_lost = self.expire_in_flight(obj_id, now, &mut reports);
+                if num_lost > 0 {
+                    self.tracer
+                        .log(obj_id, now, TraceElem::MediaPktLost(num_lost));
+                    self.rc.on_feedback(now, &reports);
+                }
+
+                let (frame_id, size) = self.pacer_queue.pop_front().unwrap();
+                let seq_num = self.next_seq;
+                self.next_seq += 1;
+                let pkt = Packet {
+                    uid: out.new_pkt_id(),
+                    sent_time: now,
+                    size,
+                    dest: self.dest,
+                    src: self.addr,
+                    ptype: TransportHeader::Datagram { seq_num },
+                };
+                self.in_flight.insert(
+                    seq_num,
+                    SentDatagram {
+                        frame_id,
+                        size,
+                        sent_time: now,
+                    },
+                );
+                self.rc.on_send(now, seq_num, size);
+                self.last_tx_time = now + self.pacing_gap(size);
+
+                out.push((now, self.next, Action::Push(pkt)));
+                self.schedule_pacer(obj_id, now, out);
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&mut self) -> serde_json::Value {
+        serde_json::json!({
+            "rate": self.rc.get_rate(),
+            "next_seq": self.next_seq,
+            "num_in_flight": self.in_flight.len(),
+            "pacer_queue_pkts": self.pacer_queue.len(),
+            "bytes_encoded": self.bytes_encoded,
+        })
+    }
+}
+
+/// Receives datagrams and periodically sends feedback on when each of them arrived
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DatagramReceiver {
+    /// The next hop over which to send feedback
+    next: NetObjId,
+    /// The address of this receiver
+    addr: Addr,
+    /// The address of the sender, learnt from the datagrams
+    peer: Option<Addr>,
+    /// How often to send feedback (if datagrams arrived in the meantime)
+    feedback_interval: Time,
+    /// Arrival times of datagrams that haven't been reported yet
+    arrivals: BTreeMap<SeqNum, Time>,
+    /// All sequence numbers below this have been reported (either as arrived or as lost)
+    next_to_report: SeqNum,
+    /// Whether we have scheduled a feedback
+    feedback_scheduled: bool,
+}
+
+impl DatagramReceiver {
+    pub fn new(addr: Addr, next: NetObjId, feedback_interval: Time) -> Self {
+        Self {
+            next,
+            addr,
+            peer: None,
+            feedback_interval,
+            arrivals: BTreeMap::new(),
+            next_to_report: 0,
+            feedback_scheduled: false,
+        }
+    }
+}
+
+impl NetObj for DatagramReceiver {
+    fn init(&mut self, _: NetObjId, _: Time, _: &mut Actions) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        obj_id: NetObjId,
+        _from: NetObjId,
+        now: Time,
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        // Make sure this is the intended recipient
+        assert_eq!(self.addr, pkt.dest);
+        self.peer = Some(pkt.src);
+
+        let seq_num = if let TransportHeader::Datagram { seq_num } = pkt.ptype {
+            seq_num
+        } else {
+            unreachable!();
+        };
+        // If it arrived after we reported it lost, it is too late
+        if seq_num >= self.next_to_report {
+            self.arrivals.insert(seq_num, now);
+        }
+
+        if !self.feedback_scheduled {
+            self.feedback_scheduled = true;
+            out.push((now + self.feedback_interval, obj_id, Action::Event(0)));
+        }
+        Ok(())
+    }
+
+    fn event(
+        &mut self,
+        _obj_id: NetObjId,
+        _from: NetObjId,
+        now: Time,
+        uid: u64,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        assert_eq!(uid, 0);
+        self.feedback_scheduled = false;
+        let last = if let Some((last, _)) = self.arrivals.iter().next_back() {
+            *last
+        } else {
+            return Ok(());
+        };
+
+        // Report everything upto the latest arrival. Gaps are reported as lost
+        let arrivals: Vec<_> = (self.next_to_report..=last)
+            .map(|seq_num| (seq_num, self.arrivals.get(&seq_num).copied()))
+            .collect();
+        self.next_to_report = last + 1;
+        self.arrivals.clear();
+
+        let feedback = Packet {
+            uid: out.new_pkt_id(),
+            sent_time: now,
+            size: 40 + 2 * arrivals.len() as u64,
+            dest: self.peer.unwrap(),
+            src: self.addr,
+            ptype: TransportHeader::Feedback { arrivals },
+        };
+        out.push((now, self.next, Action::Push(feedback)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateControlConfig;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn config() -> Config {
+        serde_yaml::from_str(
+            "pkt_size: 1500
+sim_dur: null
+random_seed: 0
+log:
+  out_terminal: png
+  out_file: out.png
+  cwnd: Ignore
+  rtt: Ignore
+  sender_losses: Ignore
+  timeouts: Ignore
+  link_rates: Ignore
+  stats_intervals: [[0, ~]]
+  stats_file: null
+  link_bucket_size: 1s
+topo:
+  link:
+    Const: 1500000
+  bufsize: Infinite
+",
+        )
+        .unwrap()
+    }
+
+    /// Ten frames a second of one datagram each, with feedback every 50ms
+    fn media_config() -> MediaConfig {
+        MediaConfig {
+            fps: 10.,
+            keyframe_interval: 0,
+            keyframe_factor: 1.,
+            start_bitrate: 15_000.,
+            min_bitrate: 15_000.,
+            max_bitrate: 15_000.,
+            encoder_gain: 1.,
+            pacing_factor: 1.,
+            feedback_interval: Time::from_millis(50),
+            feedback_timeout: Some(Time::from_millis(200)),
+            rate_control: RateControlConfig::Const(15_000.),
+        }
+    }
+
+    /// Delays packets by 10ms, dropping the given datagrams and feedback packets (counted from 0)
+    #[derive(Clone)]
+    struct Lossy {
+        to_receiver: NetObjId,
+        to_sender: NetObjId,
+        drop_datagrams: Vec<SeqNum>,
+        drop_feedback: Vec<u64>,
+        num_feedback: u64,
+    }
+
+    impl SaveState for Lossy {}
+
+    impl NetObj for Lossy {
+        fn init(&mut self, _: NetObjId, _: Time, _: &mut Actions) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn push(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            now: Time,
+            pkt: Packet,
+            out: &mut Actions,
+        ) -> Result<(), Error> {
+            let to = match pkt.ptype {
+                TransportHeader::Datagram { seq_num } => {
+                    if self.drop_datagrams.contains(&seq_num) {
+                        return Ok(());
+                    }
+                    self.to_receiver
+                }
+                TransportHeader::Feedback { .. } => {
+                    self.num_feedback += 1;
+                    if self.drop_feedback.contains(&(self.num_feedback - 1)) {
+                        return Ok(());
+                    }
+                    self.to_sender
+                }
+                _ => unreachable!(),
+            };
+            out.push((now + Time::from_millis(10), to, Action::Push(pkt)));
+            Ok(())
+        }
+
+        fn event(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            _: Time,
+            _: u64,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            unreachable!()
+        }
+    }
+
+    /// Sends datagrams to the receiver at the given times and records the feedback it gets back
+    #[derive(Clone)]
+    struct Peer {
+        src: Addr,
+        dest: Addr,
+        receiver: NetObjId,
+        /// Format: (time, seq_num)
+        datagrams: Vec<(Time, SeqNum)>,
+        feedback: Rc<RefCell<Vec<Vec<(SeqNum, Option<Time>)>>>>,
+    }
+
+    impl SaveState for Peer {}
+
+    impl NetObj for Peer {
+        fn init(&mut self, obj_id: NetObjId, _: Time, out: &mut Actions) -> Result<(), Error> {
+            for (i, (time, _)) in self.datagrams.iter().enumerate() {
+                out.push((*time, obj_id, Action::Event(i as u64)));
+            }
+            Ok(())
+        }
+
+        fn push(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            _: Time,
+            pkt: Packet,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            if let TransportHeader::Feedback { arrivals } = pkt.ptype {
+                self.feedback.borrow_mut().push(arrivals);
+            }
+            Ok(())
+        }
+
+        fn event(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            now: Time,
+            uid: u64,
+            out: &mut Actions,
+        ) -> Result<(), Error> {
+            let pkt = Packet {
+                uid: out.new_pkt_id(),
+                sent_time: now,
+                size: 1500,
+                dest: self.dest,
+                src: self.src,
+                ptype: TransportHeader::Datagram {
+                    seq_num: self.datagrams[uid as usize].1,
+                },
+            };
+            out.push((now, self.receiver, Action::Push(pkt)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn receiver_reports_gaps_as_lost() {
+        let ms = Time::from_millis;
+        let mut sched = Scheduler::default();
+        let (src, dest) = (sched.next_addr(), sched.next_addr());
+        let feedback = Rc::new(RefCell::new(Vec::new()));
+        // Datagram 2 arrives after the first feedback reported it lost
+        sched.register_obj(Box::new(Peer {
+            src,
+            dest,
+            receiver: 1,
+            datagrams: vec![(ms(0), 0), (ms(1), 1), (ms(3), 3), (ms(60), 2), (ms(60), 4)],
+            feedback: feedback.clone(),
+        }));
+        sched.register_obj(Box::new(DatagramReceiver::new(dest, 0, ms(50))));
+        sched.simulate(Some(Time::from_secs(1))).unwrap();
+
+        assert_eq!(
+            *feedback.borrow(),
+            vec![
+                vec![
+                    (0, Some(ms(0))),
+                    (1, Some(ms(1))),
+                    (2, None),
+                    (3, Some(ms(3)))
+                ],
+                vec![(4, Some(ms(60)))],
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_are_accounted_for_when_feedback_is_lost() {
+        // Datagram 3 is lost, and so is the feedback on datagram 0. The sender gives up on datagram
+        // 0 after 200ms, so both frames are lost and the other eight delivered
+        let config = config();
+        let tracer = Tracer::new(&config);
+        let mut sched = Scheduler::default();
+        let (sender_addr, receiver_addr) = (sched.next_addr(), sched.next_addr());
+        let sender_id = sched.register_obj(Box::new(MediaSender::new(
+            1,
+            sender_addr,
+            receiver_addr,
+            ConstRate::new(15_000.),
+            media_config(),
+            Time::ZERO,
+            TcpSenderTxLength::Duration(Time::from_millis(950)),
+            &tracer,
+            &config,
+        )));
+        sched.register_obj(Box::new(Lossy {
+            to_receiver: 2,
+            to_sender: 0,
+            drop_datagrams: vec![3],
+            drop_feedback: vec![0],
+            num_feedback: 0,
+        }));
+        sched.register_obj(Box::new(DatagramReceiver::new(
+            receiver_addr,
+            1,
+            Time::from_millis(50),
+        )));
+        sched.simulate(Some(Time::from_secs(2))).unwrap();
+
+        assert_eq!(sched.obj_state(sender_id).unwrap()["num_in_flight"], 0);
+        let stats = &tracer.stats()["sender_stats"][sender_id.to_string()][0];
+        assert_eq!(stats["num_frames_delivered"], 8);
+        assert_eq!(stats["num_frames_lost"], 2);
+        assert_eq!(stats["num_lost"], 2);
+        assert_eq!(stats["num_media_delivered"], 8);
+        assert_eq!(stats["media_bytes_delivered"], 8 * 1500);
+    }
+}
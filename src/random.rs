@@ -1,35 +1,306 @@
-use rand::rngs::StdRng;
-use rand::SeedableRng;
-use rand_distr::{Distribution, Exp};
-use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use failure::{format_err, Error};
+use fnv::FnvHasher;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rand_distr::{Distribution, Exp, LogNormal, Normal, Pareto, Poisson, Weibull};
+use schemars::JsonSchema;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::hash::Hasher;
+use std::sync::Arc;
 
-thread_local! {
-    pub static RNG: RefCell<StdRng> = RefCell::new(SeedableRng::from_seed([0;32]));
+/// A deterministic stream of random numbers. Every random element of the simulation owns one. It
+/// produces the same numbers as `StdRng`, but unlike it, can be saved and restored. It is saved as
+/// its seed and how far into the stream it is
+#[derive(Clone, Debug)]
+pub struct RngStream {
+    seed: u64,
+    rng: ChaCha20Rng,
+    /// Whether any numbers have been drawn. `ChaCha20Rng` can't tell its position before that
+    used: bool,
 }
 
-pub fn seed(new_seed: u8) {
-    RNG.with(|rng| {
-        rng.replace(SeedableRng::from_seed([new_seed; 32]));
-    });
+impl RngStream {
+    pub fn seed_from_u64(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: ChaCha20Rng::seed_from_u64(seed),
+            used: false,
+        }
+    }
+}
+
+impl RngCore for RngStream {
+    fn next_u32(&mut self) -> u32 {
+        self.used = true;
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.used = true;
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.used = true;
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.used = true;
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// How `RngStream` is saved. `word_pos` is the number of 32-bit words drawn so far
+#[derive(Serialize, Deserialize)]
+struct RngStreamState {
+    seed: u64,
+    word_pos: u128,
+}
+
+impl Serialize for RngStream {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RngStreamState {
+            seed: self.seed,
+            word_pos: if self.used {
+                self.rng.get_word_pos()
+            } else {
+                0
+            },
+        }
+        .serialize(serializer)
+    }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+impl<'de> Deserialize<'de> for RngStream {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = RngStreamState::deserialize(deserializer)?;
+        let mut res = Self::seed_from_u64(state.seed);
+        if state.word_pos > 0 {
+            res.rng.set_word_pos(state.word_pos);
+            res.used = true;
+        }
+        Ok(res)
+    }
+}
+
+/// The stream of the random element with the given name (e.g. "aggregator3"), derived from the
+/// simulation's master seed. Streams with different names are independent, so adding a random
+/// element, or changing how often one samples, doesn't change what the others see
+pub fn rng_stream(master_seed: u64, name: &str) -> RngStream {
+    let mut hasher = FnvHasher::default();
+    hasher.write(&master_seed.to_le_bytes());
+    hasher.write(name.as_bytes());
+    RngStream::seed_from_u64(hasher.finish())
+}
+
+/// A distribution to draw random values from, e.g. for the time between transmissions of a link.
+/// When the values are times, negative samples are treated as 0
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum RandomVariable {
     /// Not really random. Returns the given value every time
     Const(f64),
     /// Exponential distribution with given rate (1 / mean)
     Exponential(f64),
+    /// Uniform between `min` and `max`
+    Uniform { min: f64, max: f64 },
+    /// Normal (Gaussian) distribution
+    Normal { mean: f64, std_dev: f64 },
+    /// Normal distribution conditioned on lying between `min` and `max`
+    TruncatedNormal {
+        mean: f64,
+        std_dev: f64,
+        min: f64,
+        max: f64,
+    },
+    /// `exp(X)`, where `X` is normally distributed with mean `mu` and standard deviation `sigma`
+    LogNormal { mu: f64, sigma: f64 },
+    /// Pareto distribution. Values are at least `scale`, and the tail is heavier for smaller
+    /// `shape`
+    Pareto { scale: f64, shape: f64 },
+    /// Poisson distribution with the given mean. Always returns whole numbers
+    Poisson(f64),
+    /// Weibull distribution
+    Weibull { scale: f64, shape: f64 },
+    /// Picks one of the given distributions with probability proportional to its weight, and
+    /// samples from it. E.g. `[[0.99, {Const: 0}], [0.01, {Const: 1}]]` is a Bernoulli
+    /// distribution with p = 0.01
+    Mixture(Vec<(f64, RandomVariable)>),
+    /// An empirical distribution read from a file. See `EmpiricalCdf` for the format
+    EmpiricalFile(#[schemars(with = "String")] EmpiricalCdf),
 }
 
 impl RandomVariable {
-    pub fn sample(&self) -> f64 {
-        RNG.with(|rng| {
-            let rng = &mut *rng.borrow_mut();
-            match self {
-                Self::Const(val) => *val,
-                Self::Exponential(lambda) => Exp::new(*lambda).unwrap().sample(rng),
+    /// Whether samples are never greater than 0. Continuous distributions are judged by where most
+    /// of their mass is
+    pub fn always_non_positive(&self) -> bool {
+        match self {
+            Self::Const(val) => *val <= 0.,
+            Self::Uniform { max, .. } | Self::TruncatedNormal { max, .. } => *max <= 0.,
+            Self::Normal { mean, .. } => *mean <= 0.,
+            Self::Mixture(components) => components
+                .iter()
+                .all(|(weight, var)| *weight <= 0. || var.always_non_positive()),
+            Self::EmpiricalFile(cdf) => cdf.points.iter().all(|(val, _)| *val <= 0.),
+            Self::Exponential(_)
+            | Self::LogNormal { .. }
+            | Self::Pareto { .. }
+            | Self::Poisson(_)
+            | Self::Weibull { .. } => false,
+        }
+    }
+
+    pub fn sample(&self, rng: &mut RngStream) -> f64 {
+        match self {
+            Self::Const(val) => *val,
+            Self::Exponential(lambda) => Exp::new(*lambda).unwrap().sample(rng),
+            Self::Uniform { min, max } => min + (max - min) * rng.gen::<f64>(),
+            Self::Normal { mean, std_dev } => Normal::new(*mean, *std_dev).unwrap().sample(rng),
+            Self::TruncatedNormal {
+                mean,
+                std_dev,
+                min,
+                max,
+            } => {
+                // Rejection sampling. If the range is very unlikely, give up and clamp rather than
+                // loop for a long time
+                let dist = Normal::new(*mean, *std_dev).unwrap();
+                for _ in 0..1000 {
+                    let val = dist.sample(rng);
+                    if val >= *min && val <= *max {
+                        return val;
+                    }
+                }
+                dist.sample(rng).max(*min).min(*max)
             }
+            Self::LogNormal { mu, sigma } => LogNormal::new(*mu, *sigma).unwrap().sample(rng),
+            Self::Pareto { scale, shape } => Pareto::new(*scale, *shape).unwrap().sample(rng),
+            Self::Poisson(mean) => Poisson::new(*mean).unwrap().sample(rng),
+            Self::Weibull { scale, shape } => Weibull::new(*scale, *shape).unwrap().sample(rng),
+            Self::Mixture(components) => {
+                let total: f64 = components.iter().map(|(weight, _)| weight).sum();
+                let mut choice = total * rng.gen::<f64>();
+                for (weight, var) in components {
+                    if choice < *weight {
+                        return var.sample(rng);
+                    }
+                    choice -= weight;
+                }
+                // Only reachable due to rounding errors
+                components.last().unwrap().1.sample(rng)
+            }
+            Self::EmpiricalFile(cdf) => cdf.sample(rng),
+        }
+    }
+}
+
+/// An empirical distribution, read from a file with one entry per line. Either each line is a
+/// sample, and values are drawn uniformly from those samples, or each line is a value and the
+/// cumulative probability of values up to it (e.g. "10.5 0.2"), with both non-decreasing and the
+/// last probability being 1. Values are then interpolated linearly between those points. In
+/// configs, it is given as the name of the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmpiricalCdf {
+    fname: String,
+    /// (value, cumulative probability) pairs, in increasing order
+    points: Arc<Vec<(f64, f64)>>,
+    /// Whether to interpolate between points, or to pick one of them
+    interpolate: bool,
+}
+
+impl EmpiricalCdf {
+    pub fn from_file(fname: &str) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(fname)?;
+        let mut points = Vec::new();
+        let mut interpolate = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cols = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|c| !c.is_empty())
+                .map(|c| c.parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format_err!("line {}: {}", i + 1, e))?;
+            let point = match cols[..] {
+                [val] => (val, f64::NAN),
+                [val, prob] => (val, prob),
+                _ => return Err(format_err!("line {}: expected 1 or 2 numbers", i + 1)),
+            };
+            if *interpolate.get_or_insert(cols.len() == 2) != (cols.len() == 2) {
+                return Err(format_err!(
+                    "line {}: all lines must have the same number of columns",
+                    i + 1
+                ));
+            }
+            if !point.0.is_finite() {
+                return Err(format_err!("line {}: value must be finite", i + 1));
+            }
+            points.push(point);
+        }
+        if points.is_empty() {
+            return Err(format_err!("no values in file"));
+        }
+
+        let interpolate = interpolate.unwrap();
+        if interpolate {
+            for (i, w) in points.windows(2).enumerate() {
+                if w[1].0 < w[0].0 || w[1].1 < w[0].1 {
+                    return Err(format_err!(
+                        "values and probabilities must be non-decreasing (entry {})",
+                        i + 2
+                    ));
+                }
+            }
+            if points[0].1 < 0. || points.last().unwrap().1 != 1. {
+                return Err(format_err!(
+                    "probabilities must be between 0 and 1, ending at 1"
+                ));
+            }
+        } else {
+            points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        }
+        Ok(Self {
+            fname: fname.to_string(),
+            points: Arc::new(points),
+            interpolate,
+        })
+    }
+
+    fn sample(&self, rng: &mut RngStream) -> f64 {
+        let u = rng.gen::<f64>();
+        if !self.interpolate {
+            return self.points[(u * self.points.len() as f64) as usize].0;
+        }
+        // First point whose cumulative probability is at least u
+        let i = self.points.partition_point(|(_, prob)| *prob < u);
+        if i == 0 {
+            return self.points[0].0;
+        }
+        let (v0, p0) = self.points[i - 1];
+        let (v1, p1) = self.points[i];
+        if p1 == p0 {
+            v1
+        } else {
+            v0 + (v1 - v0) * (u - p0) / (p1 - p0)
+        }
+    }
+}
+
+impl Serialize for EmpiricalCdf {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.fname)
+    }
+}
+
+impl<'de> Deserialize<'de> for EmpiricalCdf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fname = String::deserialize(deserializer)?;
+        Self::from_file(&fname).map_err(|e| {
+            de::Error::custom(format!("invalid empirical distribution '{}': {}", fname, e))
         })
     }
 }
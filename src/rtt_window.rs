@@ -1,8 +1,11 @@
 use crate::simulator::Time;
 
+use serde::{Deserialize, Serialize};
+
 use std;
 use std::collections::VecDeque;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RTTWindow {
     /// The tine window until which we maintain history
     hist_period: Time,
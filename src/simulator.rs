@@ -1,34 +1,23 @@
 //! Common, basic functionality for the simulator.
 
+use crate::config::PathSwitchConfig;
+use crate::event_queue::EventQueue;
+use crate::tracer::Tracer;
 use crate::transport::TransportHeader;
 
 use failure::{format_err, Error};
 use fnv::FnvHashMap;
 use serde::{Deserialize, Serialize};
-use std::cmp::Reverse;
-use std::collections::BinaryHeap;
 use std::fmt;
-use std::rc::Rc;
 
-/// Time in microseconds
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+/// Time in microseconds. In configs, it can also be given with a unit, e.g. "50ms" (see `units`)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct Time(u64);
 
 /// Unique packet ID
-#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct PktId(u64);
 
-/// Used to allocate fresh, uniqe ids to packets
-static mut NEXT_SEQ_NUM: u64 = 0;
-impl PktId {
-    pub fn next() -> Self {
-        unsafe {
-            NEXT_SEQ_NUM += 1;
-            Self(NEXT_SEQ_NUM)
-        }
-    }
-}
-
 /// TCP sequence number (in packets)
 pub type SeqNum = u64;
 
@@ -36,7 +25,7 @@ pub type SeqNum = u64;
 pub type NetObjId = usize;
 
 /// Address of a destination
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Addr(u64);
 
 impl fmt::Display for Addr {
@@ -91,7 +80,7 @@ impl Time {
     pub const MAX: Time = Time(std::u64::MAX);
     pub const ZERO: Time = Time(0);
 
-    pub fn from_micros(micros: u64) -> Self {
+    pub const fn from_micros(micros: u64) -> Self {
         Time(micros)
     }
 
@@ -116,7 +105,7 @@ impl Time {
     }
 }
 
-#[derive(Debug, Hash)]
+#[derive(Clone, Debug, Hash, Serialize, Deserialize)]
 pub struct Packet {
     /// Unique id for the packet
     pub uid: PktId,
@@ -130,6 +119,7 @@ pub struct Packet {
 }
 
 /// Convenience struct to map event uids to custom datatypes. Creates its own namespace of UIDs
+#[derive(Clone, Serialize, Deserialize)]
 pub struct EventUidMap<T> {
     /// Counter used to generate new UIDs
     next_uid: u64,
@@ -137,6 +127,12 @@ pub struct EventUidMap<T> {
     map: FnvHashMap<u64, T>,
 }
 
+impl<T> Default for EventUidMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> EventUidMap<T> {
     pub fn new() -> Self {
         Self {
@@ -152,6 +148,39 @@ impl<T> EventUidMap<T> {
         Action::Event(self.next_uid - 1)
     }
 
+    /// Like `new_event`, but the event can be cancelled or rescheduled before it fires. Stores the
+    /// handle in `timer`, cancelling whatever timer it held. Adds the actions for the scheduler to
+    /// `out`
+    pub fn set_timer(
+        &mut self,
+        timer: &mut Option<TimerHandle>,
+        when: Time,
+        obj_id: NetObjId,
+        dat: T,
+        out: &mut Actions,
+    ) {
+        self.cancel_timer(timer, obj_id, out);
+        self.map.insert(self.next_uid, dat);
+        *timer = Some(TimerHandle(self.next_uid));
+        out.push((when, obj_id, Action::Timer(self.next_uid)));
+        self.next_uid += 1;
+    }
+
+    /// Cancel the timer in `timer` if there is one, and forget its data. Adds the actions for the
+    /// scheduler to `out`
+    pub fn cancel_timer(
+        &mut self,
+        timer: &mut Option<TimerHandle>,
+        obj_id: NetObjId,
+        out: &mut Actions,
+    ) {
+        if let Some(TimerHandle(uid)) = timer.take() {
+            self.map.remove(&uid);
+            // The scheduler ignores the time of cancellations
+            out.push((Time::ZERO, obj_id, Action::Cancel(uid)));
+        }
+    }
+
     /// Retrieve event for the given UID (and delete it from own map to save memory). Returns None,
     /// if not found
     pub fn retrieve(&mut self, uid: u64) -> Option<T> {
@@ -165,20 +194,46 @@ impl<T> EventUidMap<T> {
     }
 }
 
+/// Actions a `NetObj` wants the scheduler to take. Also allocates packet ids, so that they are
+/// unique within a simulation without any global state
+#[derive(Clone, Default)]
+pub struct Actions {
+    /// Format: (when, to, action)
+    actions: Vec<(Time, NetObjId, Action)>,
+    /// Number of packet ids allocated so far
+    num_pkt_ids: u64,
+}
+
+impl Actions {
+    /// Schedule an action. Format: (when, to, action)
+    pub fn push(&mut self, action: (Time, NetObjId, Action)) {
+        self.actions.push(action);
+    }
+
+    /// Allocate a fresh, unique id for a packet
+    pub fn new_pkt_id(&mut self) -> PktId {
+        self.num_pkt_ids += 1;
+        PktId(self.num_pkt_ids)
+    }
+}
+
 /// An object in the network that can receive packets and events. They take object ids of
-/// themselves, so it is easy to schedule events on themselves.
-pub trait NetObj {
+/// themselves, so it is easy to schedule events on themselves. Actions they want to schedule are
+/// added to `out`, which the scheduler reuses across calls to avoid allocating. They must be
+/// `Clone`, so that simulations can be checkpointed, and implement `SaveState` for simulations to
+/// be saved (see `Scheduler::save`)
+pub trait NetObj: CloneNetObj + SaveState {
     /// Called when simulation starts. This is an opportunity to schedule any actions
-    fn init(&mut self, obj_id: NetObjId, now: Time)
-        -> Result<Vec<(Time, NetObjId, Action)>, Error>;
+    fn init(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) -> Result<(), Error>;
     /// Push a new packet into this object.
     fn push(
         &mut self,
         obj_id: NetObjId,
         from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error>;
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error>;
     /// Callback for when a scheduled event occurs. 'uid' is the one specified when scheduling the
     /// event. It may be used to identify and keep track of events.
     fn event(
@@ -187,33 +242,195 @@ pub trait NetObj {
         from: NetObjId,
         now: Time,
         uid: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error>;
+        out: &mut Actions,
+    ) -> Result<(), Error>;
+    /// A snapshot of this object's internal state, for tools that drive the simulation and want
+    /// to look inside it. Takes `&mut self` since, like `CongestionControl::get_cwnd`, computing
+    /// some of it may need mutable access
+    fn state(&mut self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+    /// Forward packets sent to `dest` to object `next`. Implemented by objects that route packets
+    /// (e.g. `Router`), so that flows can be added while the simulation runs
+    fn add_route_to(&mut self, dest: Addr, next: NetObjId) -> Result<(), Error> {
+        Err(format_err!(
+            "Cannot route {:?} to object {}: this object doesn't route packets",
+            dest,
+            next
+        ))
+    }
+    /// Switch to another path at `switch.time` (not before `now`), in addition to the switches
+    /// given in the config. Implemented by links, so that continuations forked from a
+    /// `Checkpoint` can change them
+    fn add_path_switch(
+        &mut self,
+        _obj_id: NetObjId,
+        _now: Time,
+        _switch: &PathSwitchConfig,
+        _out: &mut Actions,
+    ) -> Result<(), Error> {
+        Err(format_err!("This object doesn't have paths to switch"))
+    }
 }
 
+/// State of an object that can be saved, e.g. to disk (see `Scheduler::save`), and later loaded
+/// into an object created the same way (e.g. from the same config). Only what changes as the
+/// simulation runs needs to be saved, so objects can hold things that can't be, like references to
+/// the config. Objects that don't override the methods can't be saved
+pub trait SaveState {
+    fn save(&self) -> Result<serde_json::Value, Error> {
+        Err(format_err!("This object's state can't be saved"))
+    }
+
+    /// Replace the object's state with `state`, as returned by `save`
+    fn load(&mut self, _state: serde_json::Value) -> Result<(), Error> {
+        Err(format_err!("This object's state can't be loaded"))
+    }
+}
+
+impl<T: SaveState + ?Sized> SaveState for Box<T> {
+    fn save(&self) -> Result<serde_json::Value, Error> {
+        (**self).save()
+    }
+
+    fn load(&mut self, state: serde_json::Value) -> Result<(), Error> {
+        (**self).load(state)
+    }
+}
+
+impl<T: SaveState> SaveState for Option<T> {
+    fn save(&self) -> Result<serde_json::Value, Error> {
+        match self {
+            Some(x) => x.save(),
+            None => Ok(serde_json::Value::Null),
+        }
+    }
+
+    fn load(&mut self, state: serde_json::Value) -> Result<(), Error> {
+        match self {
+            Some(x) => x.load(state),
+            None if state.is_null() => Ok(()),
+            None => Err(format_err!("Saved state for something that doesn't exist")),
+        }
+    }
+}
+
+/// Implements `SaveState` for types that are `Serialize` and `Deserialize`, by saving all of them
+macro_rules! impl_save_state {
+    ($($ty:ty),* $(,)?) => {
+        $(impl crate::simulator::SaveState for $ty {
+            fn save(&self) -> Result<serde_json::Value, failure::Error> {
+                Ok(serde_json::to_value(self)?)
+            }
+
+            fn load(&mut self, state: serde_json::Value) -> Result<(), failure::Error> {
+                *self = serde_json::from_value(state)?;
+                Ok(())
+            }
+        })*
+    };
+}
+
+/// Implements the methods of `SaveState` by saving the given fields: those before the `;` with
+/// serde, and those after it with their own `SaveState` (e.g. congestion controllers, which are
+/// trait objects). Fields that aren't listed are left alone by `load`, so they must not change as
+/// the simulation runs (e.g. the config, or the object's address)
+macro_rules! save_fields {
+    ($($field:ident),* $(; $($nested:ident),*)?) => {
+        fn save(&self) -> Result<serde_json::Value, failure::Error> {
+            let mut state = serde_json::Map::new();
+            $(state.insert(stringify!($field).to_string(), serde_json::to_value(&self.$field)?);)*
+            $($(state.insert(
+                stringify!($nested).to_string(),
+                crate::simulator::SaveState::save(&self.$nested)?,
+            );)*)?
+            Ok(state.into())
+        }
+
+        fn load(&mut self, mut state: serde_json::Value) -> Result<(), failure::Error> {
+            $(self.$field = serde_json::from_value(state[stringify!($field)].take())?;)*
+            $($(crate::simulator::SaveState::load(
+                &mut self.$nested,
+                state[stringify!($nested)].take(),
+            )?;)*)?
+            Ok(())
+        }
+    };
+}
+
+pub(crate) use {impl_save_state, save_fields};
+
+/// Lets us clone `Box<dyn NetObj>`. Implemented for every `NetObj` that is `Clone`
+pub trait CloneNetObj {
+    fn clone_obj<'b>(&self) -> Box<dyn NetObj + 'b>
+    where
+        Self: 'b;
+}
+
+impl<T: NetObj + Clone> CloneNetObj for T {
+    fn clone_obj<'b>(&self) -> Box<dyn NetObj + 'b>
+    where
+        Self: 'b,
+    {
+        Box::new(self.clone())
+    }
+}
+
+impl<'a> Clone for Box<dyn NetObj + 'a> {
+    fn clone(&self) -> Self {
+        (**self).clone_obj()
+    }
+}
+
+/// Handle to a timer an object set on itself, which can be used to cancel it before it fires
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct TimerHandle(u64);
+
 /// A single action to be taken
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Action {
     /// Call `event` on the given object with the given uid
     Event(u64),
     /// Push the given packet onto the given object
-    Push(Rc<Packet>),
+    Push(Packet),
+    /// Like `Event`, but can be cancelled with `Cancel` until it fires. The uid must not belong to
+    /// another pending timer of the same object
+    Timer(u64),
+    /// Cancel the pending timer with this uid on the given object. Takes effect immediately
+    /// (the time it is scheduled for is ignored). Does nothing if the timer isn't pending
+    Cancel(u64),
+    /// Remove the given object from the simulation. Actions pending on it are dropped, as are
+    /// packets later pushed to it
+    Retire,
 }
 
 /// A calendar scheduler for a discrete event simulator. NetObjs may provide an event id that
 /// they internally keep track of to identify events. This is the central object fot the simulator
+#[derive(Clone)]
 pub struct Scheduler<'a> {
     /// Current time in simulation
     now: Time,
-    /// List of times at which we have scheduled actions in the future
-    action_times: BinaryHeap<Reverse<Time>>,
-    /// Maps times to the set of all actions that have been scheduled for that time. 'from'
-    /// indicates the object that scheduled this action. 'to' indicates the object we are scheduling
-    /// to. Format: (from, to, action)
-    actions: FnvHashMap<Time, Vec<(NetObjId, NetObjId, Action)>>,
-    /// The set of all objects that can schedule events on this scheduler
-    objs: Vec<Box<dyn NetObj + 'a>>,
+    /// All actions scheduled for now or the future. 'from' indicates the object that scheduled this
+    /// action. 'to' indicates the object we are scheduling to. Format: (from, to, action)
+    actions: EventQueue<(NetObjId, NetObjId, Action)>,
+    /// Sequence number in `actions` of each pending timer, indexed by (object, uid). Cancelled
+    /// timers stay in `actions` and are skipped when popped
+    timers: FnvHashMap<(NetObjId, u64), u64>,
+    /// The set of all objects that can schedule events on this scheduler. Retired objects are
+    /// `None`. Their ids are never reused
+    objs: Vec<Option<Box<dyn NetObj + 'a>>>,
+    /// Number of objects (starting from id 0) whose `init` has been called
+    num_inited: usize,
+    /// Names of objects, if they were given any with `SchedulerBuilder`
+    names: FnvHashMap<String, NetObjId>,
     /// For uniquely allocating addresses
     num_addr: u64,
+    /// Buffer objects add new actions to. Reused to avoid an allocation per action
+    buf: Actions,
+    /// Number of actions taken so far (not counting cancelled timers)
+    num_actions: u64,
+    /// Largest number of actions that were pending at once
+    max_pending: usize,
 }
 
 impl<'a> Default for Scheduler<'a> {
@@ -221,31 +438,82 @@ impl<'a> Default for Scheduler<'a> {
         Self {
             now: Time(0),
             actions: Default::default(),
-            action_times: Default::default(),
+            timers: Default::default(),
             objs: Default::default(),
+            num_inited: 0,
+            names: Default::default(),
             num_addr: 0,
+            buf: Default::default(),
+            num_actions: 0,
+            max_pending: 0,
         }
     }
 }
 
 impl<'a> Scheduler<'a> {
-    /// Get the object ID that will be allocated to the next object that will be registered. We
-    /// promise to start from zero and allocate in increments of 1.
-    pub fn next_obj_id(&self) -> NetObjId {
-        self.objs.len()
-    }
-
     /// Register an object for this scheduler. Only registered objects can register events. Returns
     /// a unique identifier that can be used to refer to this object later. We promise to allocate
-    /// in increments of 1.
+    /// in increments of 1. Objects may also be registered between calls to `simulate`, in which
+    /// case their `init` is called at the current time when the simulation resumes
     pub fn register_obj(&mut self, obj: Box<dyn NetObj + 'a>) -> NetObjId {
-        self.objs.push(obj);
+        self.objs.push(Some(obj));
         self.objs.len() - 1
     }
 
-    #[allow(dead_code)]
-    pub fn get_obj(&mut self, obj_id: NetObjId) -> &mut dyn NetObj {
-        self.objs[obj_id].as_mut()
+    /// Remove an object from the simulation. Same as the object being sent `Action::Retire` now
+    pub fn retire_obj(&mut self, obj_id: NetObjId) {
+        self.objs[obj_id] = None;
+        self.timers.retain(|(to, _), _| *to != obj_id);
+    }
+
+    /// Make object `obj_id` (e.g. the router) forward packets sent to `dest` to `next` (see
+    /// `NetObj::add_route_to`). Together with `register_obj` and `retire_obj`, this lets flows
+    /// arrive and leave between calls to `simulate`
+    pub fn add_route(&mut self, obj_id: NetObjId, dest: Addr, next: NetObjId) -> Result<(), Error> {
+        match self.get_obj(obj_id) {
+            Some(obj) => obj.add_route_to(dest, next),
+            None => Err(format_err!("Object {} has been retired", obj_id)),
+        }
+    }
+
+    /// Make link `obj_id` switch to another path (see `NetObj::add_path_switch`), e.g. to try
+    /// out a change in its rate in a continuation forked from a `Checkpoint`
+    pub fn add_path_switch(
+        &mut self,
+        obj_id: NetObjId,
+        switch: &PathSwitchConfig,
+    ) -> Result<(), Error> {
+        let obj = self.objs[obj_id]
+            .as_deref_mut()
+            .ok_or_else(|| format_err!("Object {} has been retired", obj_id))?;
+        obj.add_path_switch(obj_id, self.now, switch, &mut self.buf)?;
+        self.schedule_buf(obj_id)
+    }
+
+    /// Returns `None` if the object has been retired
+    pub fn get_obj(&mut self, obj_id: NetObjId) -> Option<&mut (dyn NetObj + 'a)> {
+        self.objs[obj_id].as_deref_mut()
+    }
+
+    /// Id of the object with the given name, if it was created by `SchedulerBuilder`
+    pub fn obj_id(&self, name: &str) -> Option<NetObjId> {
+        self.names.get(name).copied()
+    }
+
+    /// Number of objects ever registered, including retired ones. Ids are less than this
+    pub fn num_objs(&self) -> usize {
+        self.objs.len()
+    }
+
+    /// Snapshot of the internal state of an object (see `NetObj::state`). `None` if the object
+    /// has been retired
+    pub fn obj_state(&mut self, obj_id: NetObjId) -> Option<serde_json::Value> {
+        self.get_obj(obj_id).map(|obj| obj.state())
+    }
+
+    /// The current time in the simulation
+    pub fn now(&self) -> Time {
+        self.now
     }
 
     /// Allocate a new globally-unique address
@@ -255,6 +523,93 @@ impl<'a> Scheduler<'a> {
         Addr(res)
     }
 
+    /// Number of actions taken so far
+    pub fn num_actions(&self) -> u64 {
+        self.num_actions
+    }
+
+    /// Largest number of actions that were pending at once
+    pub fn max_pending(&self) -> usize {
+        self.max_pending
+    }
+
+    /// Copy the state of the simulation, so it can be resumed from here later. `tracer` must be the
+    /// one the simulation's objects log to. Typically taken after `simulate` returns at some `till`
+    pub fn checkpoint(&self, tracer: &Tracer<'a>) -> Checkpoint<'a> {
+        Checkpoint {
+            sched: self.clone(),
+            tracer: tracer.clone(),
+        }
+    }
+
+    /// Save the complete state of the simulation, so that it can be serialized (e.g. to disk) and
+    /// resumed later with `load`, possibly in another process. `tracer` must be the one the
+    /// simulation's objects log to. Fails if an object doesn't support saving (see `SaveState`)
+    pub fn save(&self, tracer: &Tracer) -> Result<SavedState, Error> {
+        let mut objs = Vec::with_capacity(self.objs.len());
+        for (obj_id, obj) in self.objs.iter().enumerate() {
+            objs.push(match obj {
+                Some(obj) => Some(
+                    obj.save()
+                        .map_err(|e| format_err!("Couldn't save object {}: {}", obj_id, e))?,
+                ),
+                None => None,
+            });
+        }
+        let mut timers: Vec<_> = self.timers.iter().map(|(k, v)| (*k, *v)).collect();
+        timers.sort_unstable();
+        Ok(SavedState {
+            now: self.now,
+            actions: self.actions.clone(),
+            timers,
+            objs,
+            num_inited: self.num_inited,
+            num_addr: self.num_addr,
+            num_pkt_ids: self.buf.num_pkt_ids,
+            num_actions: self.num_actions,
+            max_pending: self.max_pending,
+            tracer: tracer.save()?,
+        })
+    }
+
+    /// Continue from a simulation saved with `save`. The scheduler must have the same objects as
+    /// the saved one, created the same way: typically, it is created by `create_topology` from the
+    /// same config, and any objects that were added to the saved simulation as it ran are
+    /// registered again. Everything logged in `tracer` is replaced by what was logged then. If this
+    /// fails, the simulation is left in an unspecified state
+    pub fn load(&mut self, state: SavedState, tracer: &Tracer) -> Result<(), Error> {
+        if state.objs.len() != self.objs.len() {
+            return Err(format_err!(
+                "The saved simulation has {} objects, but this one has {}",
+                state.objs.len(),
+                self.objs.len()
+            ));
+        }
+        for (obj_id, saved) in state.objs.into_iter().enumerate() {
+            match (&mut self.objs[obj_id], saved) {
+                (Some(obj), Some(saved)) => obj
+                    .load(saved)
+                    .map_err(|e| format_err!("Couldn't load object {}: {}", obj_id, e))?,
+                (obj, None) => *obj = None,
+                (None, Some(_)) => {
+                    return Err(format_err!(
+                        "Object {} has been retired, but not in the saved simulation",
+                        obj_id
+                    ))
+                }
+            }
+        }
+        self.now = state.now;
+        self.actions = state.actions;
+        self.timers = state.timers.into_iter().collect();
+        self.num_inited = state.num_inited;
+        self.num_addr = state.num_addr;
+        self.buf.num_pkt_ids = state.num_pkt_ids;
+        self.num_actions = state.num_actions;
+        self.max_pending = state.max_pending;
+        tracer.load(state.tracer)
+    }
+
     /// Schedule the given action now or in the future from `from` to object `obj_id`.
     fn schedule(
         &mut self,
@@ -263,10 +618,15 @@ impl<'a> Scheduler<'a> {
         to: NetObjId,
         action: Action,
     ) -> Result<(), Error> {
-        // Check if event needs to be scheduled is in the past or right now. We cannot handle
-        // events that happen right now, since simulate takes an iterator to 'ActionSet::events', which
-        // won't update when things are pushed into the vector. In such cases, callers should just
-        // do the event instead of scheduling it.
+        // Cancellations don't need a spot on the calendar. The timer will be skipped when it is
+        // popped since it is no longer in `timers`
+        if let Action::Cancel(uid) = action {
+            self.timers.remove(&(to, uid));
+            return Ok(());
+        }
+
+        // Check if event needs to be scheduled is in the past. Events scheduled right now are
+        // run after the ones already scheduled for now
         if when < self.now {
             return Err(format_err!(
                 "Event to be scheduled at time {:?}, which is in the past. Current time is {:?}.",
@@ -275,63 +635,497 @@ impl<'a> Scheduler<'a> {
             ));
         }
 
-        // If an event has already been scheduled for this time, add to the same event
-        if let Some(actions) = self.actions.get_mut(&when) {
-            actions.push((from, to, action))
+        let timer_uid = if let Action::Timer(uid) = action {
+            if self.timers.contains_key(&(to, uid)) {
+                return Err(format_err!(
+                    "Object {} set timer {} while it was already pending",
+                    to,
+                    uid
+                ));
+            }
+            Some(uid)
         } else {
-            self.action_times.push(Reverse(when));
-            self.actions.insert(when, vec![(from, to, action)]);
+            None
+        };
+
+        let seq = self.actions.push(when, (from, to, action));
+        if let Some(uid) = timer_uid {
+            self.timers.insert((to, uid), seq);
         }
+        self.max_pending = std::cmp::max(self.max_pending, self.actions.len());
 
         Ok(())
     }
 
-    /// Start simulation. Loop till no more events are scheduled, or till time `till` if given
-    pub fn simulate(&mut self, till: Option<Time>) -> Result<(), Error> {
-        // We start from time 0
-        self.now = Time::from_micros(0);
-
-        // Get all the initial events that have been scheduled
-        let mut actions_to_sched = Vec::new();
-        for (obj_id, obj) in self.objs.iter_mut().enumerate() {
-            for (when, to, action) in obj.init(obj_id, self.now)? {
-                actions_to_sched.push((when, obj_id, to, action));
-            }
+    /// Schedule all actions in `self.buf`, which were returned by object `from`
+    fn schedule_buf(&mut self, from: NetObjId) -> Result<(), Error> {
+        let mut buf = std::mem::take(&mut self.buf.actions);
+        for (when, to, action) in buf.drain(..) {
+            self.schedule(when, from, to, action)?;
         }
-        for (when, to, to1, action) in actions_to_sched {
-            self.schedule(when, to, to1, action)?;
+        self.buf.actions = buf;
+        Ok(())
+    }
+
+    /// Call `init` on objects registered since we last ran
+    fn init_new_objs(&mut self) -> Result<(), Error> {
+        while self.num_inited < self.objs.len() {
+            let obj_id = self.num_inited;
+            self.num_inited += 1;
+            if let Some(obj) = &mut self.objs[obj_id] {
+                obj.init(obj_id, self.now, &mut self.buf)?;
+                self.schedule_buf(obj_id)?;
+            }
         }
+        Ok(())
+    }
 
-        while let Some(Reverse(when)) = self.action_times.pop() {
-            let actions = self.actions.remove(&when).unwrap();
+    /// Take the next action, unless it is due after `till`. Returns its time, or `None` if there
+    /// was nothing to do
+    fn step_till(&mut self, till: Option<Time>) -> Result<Option<Time>, Error> {
+        while let Some((when, seq, (from, to, action))) = self.actions.pop(till) {
             assert!(self.now <= when);
             self.now = when;
-            if let Some(till) = till {
-                if self.now > till {
-                    break;
-                }
-            }
 
-            // Gather all things to be scheduled next in a vec and schedle all of them together, so
-            // we can avoid having to modify `next.actions` in flight
-            let mut actions_to_sched = Vec::new();
-            for (from, to, action) in actions {
-                // Take the given action
-                let new_actions = match action {
-                    Action::Event(uid) => self.objs[to].event(to, from, self.now, uid)?,
-                    Action::Push(pkt) => self.objs[to].push(to, from, self.now, pkt)?,
-                };
-
-                for (when, to1, action) in new_actions {
-                    actions_to_sched.push((when, to, to1, action));
+            let obj = if let Some(obj) = &mut self.objs[to] {
+                obj
+            } else {
+                // The object has been retired
+                continue;
+            };
+            // Actions are scheduled as soon as they are returned, so cancellations take effect
+            // even on timers that are due right now
+            match action {
+                Action::Event(uid) => obj.event(to, from, self.now, uid, &mut self.buf)?,
+                Action::Push(pkt) => obj.push(to, from, self.now, pkt, &mut self.buf)?,
+                Action::Timer(uid) => {
+                    if self.timers.get(&(to, uid)) != Some(&seq) {
+                        // It was cancelled
+                        continue;
+                    }
+                    self.timers.remove(&(to, uid));
+                    obj.event(to, from, self.now, uid, &mut self.buf)?
                 }
+                Action::Retire => self.retire_obj(to),
+                Action::Cancel(_) => unreachable!(),
             }
+            self.num_actions += 1;
+            self.schedule_buf(to)?;
+            return Ok(Some(when));
+        }
+        Ok(None)
+    }
 
-            // Schedule any new actions returned in this time-step
-            for (when, to, to1, action) in actions_to_sched {
-                self.schedule(when, to, to1, action)?;
-            }
+    /// Take the next action (calling `init` first on any objects that are new). Returns the time
+    /// at which it happened, or `None` if nothing is scheduled, i.e. the simulation has ended
+    pub fn step(&mut self) -> Result<Option<Time>, Error> {
+        self.init_new_objs()?;
+        self.step_till(None)
+    }
+
+    /// Take all actions due at or before `till`, after which the current time is `till`
+    pub fn run_until(&mut self, till: Time) -> Result<(), Error> {
+        self.simulate(Some(till))
+    }
+
+    /// Run the simulation till no more events are scheduled, or till time `till` if given. Can
+    /// be called again to resume from where it stopped, possibly after registering or retiring
+    /// objects. Simulation starts at time 0
+    pub fn simulate(&mut self, till: Option<Time>) -> Result<(), Error> {
+        self.init_new_objs()?;
+        while self.step_till(till)?.is_some() {}
+
+        if let Some(till) = till {
+            // So that objects registered before we resume start from here
+            self.now = std::cmp::max(self.now, till);
         }
         Ok(())
     }
 }
+
+/// A copy of the complete state of a simulation: the scheduler with all its objects (including
+/// their random number streams) and pending actions, and everything logged so far. The simulation
+/// can be resumed from it any number of times, e.g. to compare several what-if continuations that
+/// start from an identical state. Each continuation can be changed before it resumes, e.g. with
+/// `Scheduler::add_path_switch` or by adding flows. Checkpoints live in memory. To save a
+/// simulation to disk, use `Scheduler::save` instead
+#[derive(Clone)]
+pub struct Checkpoint<'a> {
+    sched: Scheduler<'a>,
+    tracer: Tracer<'a>,
+}
+
+impl<'a> Checkpoint<'a> {
+    /// The time at which the checkpoint was taken
+    pub fn time(&self) -> Time {
+        self.sched.now
+    }
+
+    /// Put `tracer` back to how it was when the checkpoint was taken, and
+    /// return a scheduler that continues from there. `tracer` must be the one the simulation's
+    /// objects log to
+    pub fn restore(&self, tracer: &Tracer) -> Scheduler<'a> {
+        tracer.restore(&self.tracer);
+        self.sched.clone()
+    }
+}
+
+/// Everything needed to resume a simulation, as returned by `Scheduler::save`: pending actions,
+/// the state of every object (including congestion controllers and random number streams), the
+/// packet id counter and everything logged so far. It can be serialized with serde, e.g. to save
+/// it to disk
+#[derive(Serialize, Deserialize)]
+pub struct SavedState {
+    now: Time,
+    actions: EventQueue<(NetObjId, NetObjId, Action)>,
+    /// Format: ((object, uid), sequence number in `actions`)
+    timers: Vec<((NetObjId, u64), u64)>,
+    /// State of each object. `None` if it has been retired
+    objs: Vec<Option<serde_json::Value>>,
+    num_inited: usize,
+    num_addr: u64,
+    num_pkt_ids: u64,
+    num_actions: u64,
+    max_pending: usize,
+    /// Everything logged so far
+    tracer: serde_json::Value,
+}
+
+impl SavedState {
+    /// The time at which the simulation was saved
+    pub fn time(&self) -> Time {
+        self.now
+    }
+}
+
+/// Builds a `Scheduler`, letting objects be referred to by name. Since objects need the ids of the
+/// objects they send to when they are created, `id` hands out the id of a named object before it
+/// is added, and `add` fills it in later
+#[derive(Default)]
+pub struct SchedulerBuilder<'a> {
+    sched: Scheduler<'a>,
+    /// Objects added so far, indexed by id. `None` if the id was handed out but the object hasn't
+    /// been added yet
+    objs: Vec<Option<Box<dyn NetObj + 'a>>>,
+    /// Maps names to ids
+    names: FnvHashMap<String, NetObjId>,
+}
+
+impl<'a> SchedulerBuilder<'a> {
+    /// Id of the object with the given name, allocating one if it doesn't have one yet
+    pub fn id(&mut self, name: &str) -> NetObjId {
+        if let Some(id) = self.names.get(name) {
+            return *id;
+        }
+        self.objs.push(None);
+        self.names.insert(name.to_string(), self.objs.len() - 1);
+        self.objs.len() - 1
+    }
+
+    /// Add the object with the given name. Returns its id
+    pub fn add(&mut self, name: &str, obj: Box<dyn NetObj + 'a>) -> Result<NetObjId, Error> {
+        let id = self.id(name);
+        if self.objs[id].is_some() {
+            return Err(format_err!("Object '{}' added twice", name));
+        }
+        self.objs[id] = Some(obj);
+        Ok(id)
+    }
+
+    /// Allocate a new globally-unique address
+    pub fn next_addr(&mut self) -> Addr {
+        self.sched.next_addr()
+    }
+
+    /// Create the scheduler. Fails if an id was handed out for an object that was never added
+    pub fn build(mut self) -> Result<Scheduler<'a>, Error> {
+        let mut missing: Vec<_> = self
+            .names
+            .iter()
+            .filter(|(_, id)| self.objs[**id].is_none())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            missing.sort_unstable();
+            return Err(format_err!(
+                "Objects {:?} are referred to but were never added",
+                missing
+            ));
+        }
+        for obj in self.objs {
+            self.sched.register_obj(obj.unwrap());
+        }
+        self.sched.names = self.names;
+        Ok(self.sched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, LinkTraceConfig};
+    use crate::random::RandomVariable;
+    use crate::topology::create_topology;
+
+    /// Config with the given `topo` section
+    fn config_with_topo(topo: &str) -> Config {
+        serde_yaml::from_str(&format!(
+            "pkt_size: 1500
+sim_dur: null
+random_seed: 0
+log:
+  out_terminal: png
+  out_file: out.png
+  cwnd: Plot
+  rtt: Plot
+  sender_losses: Ignore
+  timeouts: Ignore
+  link_rates: Plot
+  stats_intervals: [[0, ~]]
+  stats_file: null
+  link_bucket_size: 100ms
+topo:
+{}",
+            topo
+        ))
+        .unwrap()
+    }
+
+    /// Two AIMD senders, with random gaps between packets, sharing a link whose rate is random
+    fn config() -> Config {
+        config_with_topo(
+            "  link:
+    Random: {Exponential: 0.001}
+  bufsize: {Finite: 50}
+  sender_groups:
+    - num_senders: 2
+      delay: 20ms
+      agg_intersend: {Exponential: 0.01}
+      cc: AIMD
+      start_time: 0
+      tx_length: Infinite
+",
+        )
+    }
+
+    /// At 1.5s, the link switches to a faster random rate and more delay
+    fn path_switch() -> PathSwitchConfig {
+        PathSwitchConfig {
+            time: Time::from_millis(1500),
+            link: Some(LinkTraceConfig::Random(RandomVariable::Exponential(0.002))),
+            delay: Some(Time::from_millis(10)),
+            interruption: Time::from_millis(100),
+        }
+    }
+
+    /// Stats and number of actions of an uninterrupted run of `config` till `till`
+    fn run(config: &Config, till: Time) -> (serde_json::Value, u64) {
+        let tracer = Tracer::new(config);
+        let mut sched = create_topology(config, &tracer).unwrap();
+        sched.simulate(Some(till)).unwrap();
+        (tracer.stats(), sched.num_actions())
+    }
+
+    /// Like `run`, but the simulation is saved to JSON at `save_at` and resumed from there in a
+    /// scheduler created afresh from the config, as if in another process
+    fn run_saved(config: &Config, save_at: Time, till: Time) -> (serde_json::Value, u64) {
+        let saved = {
+            let tracer = Tracer::new(config);
+            let mut sched = create_topology(config, &tracer).unwrap();
+            sched.run_until(save_at).unwrap();
+            serde_json::to_string(&sched.save(&tracer).unwrap()).unwrap()
+        };
+        let tracer = Tracer::new(config);
+        let mut sched = create_topology(config, &tracer).unwrap();
+        let saved: SavedState = serde_json::from_str(&saved).unwrap();
+        assert_eq!(saved.time(), save_at);
+        sched.load(saved, &tracer).unwrap();
+        sched.simulate(Some(till)).unwrap();
+        (tracer.stats(), sched.num_actions())
+    }
+
+    #[test]
+    fn restored_checkpoint_matches_uninterrupted_run() {
+        let config = config();
+        let expected = run(&config, Time::from_secs(2));
+
+        let tracer = Tracer::new(&config);
+        let mut sched = create_topology(&config, &tracer).unwrap();
+        sched.run_until(Time::from_secs(1)).unwrap();
+        let checkpoint = sched.checkpoint(&tracer);
+        // Running on from here must not affect the checkpoint
+        sched.run_until(Time::from_millis(1500)).unwrap();
+        for _ in 0..2 {
+            let mut sched = checkpoint.restore(&tracer);
+            sched.simulate(Some(Time::from_secs(2))).unwrap();
+            assert_eq!((tracer.stats(), sched.num_actions()), expected);
+        }
+    }
+
+    #[test]
+    fn forked_path_switch_matches_configured_one() {
+        let mut with_switch = config();
+        with_switch.topo.link_events.path_switches = vec![path_switch()];
+        let expected = run(&with_switch, Time::from_secs(2));
+
+        let config = config();
+        let tracer = Tracer::new(&config);
+        let mut sched = create_topology(&config, &tracer).unwrap();
+        sched.run_until(Time::from_secs(1)).unwrap();
+        let checkpoint = sched.checkpoint(&tracer);
+
+        let mut fork = checkpoint.restore(&tracer);
+        let link = fork.obj_id("link").unwrap();
+        fork.add_path_switch(link, &path_switch()).unwrap();
+        fork.simulate(Some(Time::from_secs(2))).unwrap();
+        assert_eq!((tracer.stats(), fork.num_actions()), expected);
+
+        // The other branch continues as if nothing happened
+        let mut sched = checkpoint.restore(&tracer);
+        sched.simulate(Some(Time::from_secs(2))).unwrap();
+        assert_eq!(
+            (tracer.stats(), sched.num_actions()),
+            run(&config, Time::from_secs(2))
+        );
+        assert_ne!(tracer.stats(), expected.0);
+
+        // Switches can't be added in the past
+        let mut switch = path_switch();
+        switch.time = Time::from_millis(500);
+        assert!(checkpoint
+            .restore(&tracer)
+            .add_path_switch(link, &switch)
+            .is_err());
+    }
+
+    #[test]
+    fn saved_simulation_resumes_like_uninterrupted_run() {
+        let mut config = config();
+        config.topo.link_events.path_switches = vec![path_switch()];
+        let expected = run(&config, Time::from_secs(2));
+        // Before and after the path switch
+        for save_at in [1000, 1700] {
+            assert_eq!(
+                run_saved(&config, Time::from_millis(save_at), Time::from_secs(2)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn every_kind_of_object_can_be_saved() {
+        let senders = |cc: &str, extra: &str| {
+            format!(
+                "    - num_senders: 1
+      delay: 20ms
+      agg_intersend: {{Const: 0}}
+      cc: {}
+      start_time: 100ms
+      tx_length: Infinite
+{}",
+                cc, extra
+            )
+        };
+        let video = |abr: &str| {
+            format!(
+                "      app:
+        Video:
+          bitrates: [37500, 93750, 150000]
+          chunk_dur: 1s
+          num_chunks: 20
+          max_buffer: 5s
+          startup_buffer: 2s
+          abr: {}
+",
+                abr
+            )
+        };
+        let media = |rate_control: &str| {
+            format!(
+                "      transport:
+        Media:
+          fps: 30
+          keyframe_interval: 60
+          keyframe_factor: 5
+          start_bitrate: 100000
+          min_bitrate: 20000
+          max_bitrate: 1000000
+          encoder_gain: 0.2
+          pacing_factor: 2.5
+          feedback_interval: 50ms
+          rate_control: {}
+",
+                rate_control
+            )
+        };
+        // Between them, these use every kind of link, congestion control, transport and app
+        let topos = [
+            format!(
+                "  link:
+    Cellular: {{}}
+  bufsize: {{Finite: 100}}
+  link_events:
+    random_outages:
+      interval: {{Exponential: 0.000002}}
+      duration: {{Const: 50000}}
+  wifi: {{}}
+  sender_groups:
+{}{}{}{}",
+                senders(
+                    "Copa",
+                    "      rcv_bufsize: {Finite: 30}
+      app_read_rate: 200000
+      loss_detection: {RackTlp: {reo_wnd: 0.25}}
+"
+                ),
+                senders("AIMD", &media("Gcc")),
+                senders("AIMD", &media("{Const: 50000}")),
+                senders(
+                    "AIMD",
+                    &video("{Mpc: {horizon: 3, rebuf_penalty: 150000, switch_penalty: 1}}")
+                ),
+            ),
+            format!(
+                "  link:
+    Markov:
+      states:
+        - {{rate: 1000000, transitions: [[1, 2]]}}
+        - {{rate: 300000, transitions: [[0, 1]]}}
+  bufsize: {{Finite: 100}}
+  link_events:
+    outages: [[500ms, 100ms]]
+    path_switches:
+      - time: 1200ms
+        link: {{Piecewise: [[1000000, 200ms], [500000, 300ms]]}}
+        delay: 5ms
+        interruption: 50ms
+      - time: 1800ms
+        link: {{Const: 800000}}
+        interruption: 0
+  sender_groups:
+{}{}{}{}{}{}{}{}",
+                senders("InstantCC", ""),
+                senders("{OscInstantCC: {k: 1, omega: 6}}", ""),
+                senders("{StableLinearCC: {alpha: 1, k: 1}}", ""),
+                senders("IncreaseBdpCC", ""),
+                senders("Copa2", ""),
+                senders("{Const: {cwnd: 10, intersend: 1ms}}", ""),
+                senders(
+                    "AIMD",
+                    &video("{BufferBased: {reservoir: 1s, cushion: 2s}}")
+                ),
+                senders("AIMD", &video("{RateBased: {window: 3, safety: 0.8}}")),
+            ),
+        ];
+        for topo in &topos {
+            let config = config_with_topo(topo);
+            let expected = run(&config, Time::from_millis(2500));
+            for save_at in [1000, 1500] {
+                assert_eq!(
+                    run_saved(&config, Time::from_millis(save_at), Time::from_millis(2500)),
+                    expected
+                );
+            }
+        }
+    }
+}
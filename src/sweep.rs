@@ -0,0 +1,266 @@
+//! Parameter sweeps: runs many variants of a simulation in parallel and aggregates their results
+//! over random seeds
+
+use crate::config::{Config, SenderGroupConfig, SweepConfig};
+use crate::topology::create_topology;
+use crate::tracer::Tracer;
+
+use failure::{format_err, Error};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// One simulation in the sweep
+#[derive(Serialize)]
+struct RunResult {
+    /// Identifies the combination of parameters, e.g. "cc1-delay0". Runs that differ only in their
+    /// seed share it
+    id: String,
+    /// Value of each swept parameter
+    params: BTreeMap<String, Value>,
+    seed: u64,
+    /// See `Tracer::summary`
+    summary: BTreeMap<String, f64>,
+    /// See `Tracer::stats`
+    stats: Value,
+}
+
+/// Mean of a metric over seeds, with the half-width of its 95% confidence interval
+#[derive(Serialize)]
+struct MetricAggregate {
+    mean: f64,
+    ci95: f64,
+    num_samples: usize,
+}
+
+/// Results of all runs with the same parameters
+#[derive(Serialize)]
+struct Aggregate {
+    id: String,
+    params: BTreeMap<String, Value>,
+    metrics: BTreeMap<String, MetricAggregate>,
+}
+
+#[derive(Serialize)]
+struct SweepResult {
+    aggregates: Vec<Aggregate>,
+    runs: Vec<RunResult>,
+}
+
+/// A simulation to run: the id and parameters of its combination, and the config to run
+struct Job {
+    id: String,
+    params: BTreeMap<String, Value>,
+    config: Config,
+}
+
+/// Two-sided 97.5th percentile of Student's t-distribution with `df` degrees of freedom
+fn t_critical(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+        2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+        2.052, 2.048, 2.045, 2.042,
+    ];
+    if df == 0 {
+        f64::NAN
+    } else if df <= TABLE.len() {
+        TABLE[df - 1]
+    } else {
+        1.96
+    }
+}
+
+fn aggregate(vals: &[f64]) -> MetricAggregate {
+    let n = vals.len();
+    let mean = vals.iter().sum::<f64>() / n as f64;
+    let ci95 = if n < 2 {
+        0.
+    } else {
+        let var = vals.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1) as f64;
+        t_critical(n - 1) * (var / n as f64).sqrt()
+    };
+    MetricAggregate {
+        mean,
+        ci95,
+        num_samples: n,
+    }
+}
+
+/// The sender groups in `config`, including the template of each series
+fn sender_groups(config: &mut Config) -> impl Iterator<Item = &mut SenderGroupConfig> {
+    let topo = &mut config.topo;
+    topo.sender_groups
+        .iter_mut()
+        .chain(topo.sender_group_series.iter_mut().map(|s| &mut s.group))
+}
+
+/// Expand the sweep into one job per combination of parameters and seed, grouped by combination
+fn make_jobs(sweep: &SweepConfig) -> Result<Vec<Job>, Error> {
+    let seeds = if sweep.seeds.is_empty() {
+        vec![sweep.base.random_seed]
+    } else {
+        sweep.seeds.clone()
+    };
+    let axis_lens = [
+        sweep.cc.len(),
+        sweep.link.len(),
+        sweep.delay.len(),
+        sweep.bufsize.len(),
+        sweep.num_senders.len(),
+    ];
+    let num_combos: usize = axis_lens.iter().map(|l| std::cmp::max(*l, 1)).product();
+
+    let mut jobs = Vec::new();
+    for combo in 0..num_combos {
+        // Index along each axis, treating `combo` as a mixed-radix number
+        let mut idx = [0; 5];
+        let mut rem = combo;
+        for (i, len) in axis_lens.iter().enumerate().rev() {
+            let len = std::cmp::max(*len, 1);
+            idx[i] = rem % len;
+            rem /= len;
+        }
+
+        let mut config = sweep.base.clone();
+        let mut params = BTreeMap::new();
+        let mut id = Vec::new();
+        if let Some(cc) = sweep.cc.get(idx[0]) {
+            for group in sender_groups(&mut config) {
+                group.cc = cc.clone();
+            }
+            params.insert("cc".to_string(), serde_json::to_value(cc)?);
+            id.push(format!("cc{}", idx[0]));
+        }
+        if let Some(link) = sweep.link.get(idx[1]) {
+            config.topo.link = link.clone();
+            params.insert("link".to_string(), serde_json::to_value(link)?);
+            id.push(format!("link{}", idx[1]));
+        }
+        if let Some(delay) = sweep.delay.get(idx[2]) {
+            for group in sender_groups(&mut config) {
+                group.delay = *delay;
+            }
+            params.insert("delay".to_string(), serde_json::to_value(delay)?);
+            id.push(format!("delay{}", idx[2]));
+        }
+        if let Some(bufsize) = sweep.bufsize.get(idx[3]) {
+            config.topo.bufsize = *bufsize;
+            params.insert("bufsize".to_string(), serde_json::to_value(bufsize)?);
+            id.push(format!("bufsize{}", idx[3]));
+        }
+        if let Some(num_senders) = sweep.num_senders.get(idx[4]) {
+            for group in sender_groups(&mut config) {
+                group.num_senders = *num_senders;
+            }
+            params.insert("num_senders".to_string(), (*num_senders).into());
+            id.push(format!("num_senders{}", idx[4]));
+        }
+        let id = if id.is_empty() {
+            "base".to_string()
+        } else {
+            id.join("-")
+        };
+
+        // Report bad combinations before running anything
+        config
+            .validate()
+            .map_err(|e| format_err!("In sweep combination {}: {}", id, e))?;
+
+        for seed in &seeds {
+            let mut config = config.clone();
+            config.random_seed = *seed;
+            jobs.push(Job {
+                id: id.clone(),
+                params: params.clone(),
+                config,
+            });
+        }
+    }
+    Ok(jobs)
+}
+
+/// Run a single simulation and collect its results
+fn run(job: &Job) -> Result<RunResult, Error> {
+    let tracer = Tracer::new(&job.config);
+    let mut sched = create_topology(&job.config, &tracer)?;
+    sched.simulate(job.config.sim_dur)?;
+    Ok(RunResult {
+        id: job.id.clone(),
+        params: job.params.clone(),
+        seed: job.config.random_seed,
+        summary: tracer.summary(),
+        stats: tracer.stats(),
+    })
+}
+
+/// Run all simulations in the sweep across threads, and write their results along with
+/// aggregates over seeds to `sweep.out_file`
+pub fn run_sweep(sweep: &SweepConfig) -> Result<(), Error> {
+    let jobs = make_jobs(sweep)?;
+    let num_threads = match sweep.num_threads {
+        Some(0) => return Err(format_err!("Sweep needs at least one thread")),
+        Some(num_threads) => num_threads,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+
+    // Threads take the next job that hasn't been taken yet
+    let next_job = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<RunResult, Error>>>> =
+        Mutex::new(jobs.iter().map(|_| None).collect());
+    std::thread::scope(|scope| {
+        for _ in 0..std::cmp::min(num_threads, jobs.len()) {
+            scope.spawn(|| loop {
+                let i = next_job.fetch_add(1, Ordering::Relaxed);
+                if i >= jobs.len() {
+                    break;
+                }
+                let res = run(&jobs[i]);
+                eprintln!("Finished run {} of {} ({})", i + 1, jobs.len(), jobs[i].id);
+                results.lock().unwrap()[i] = Some(res);
+            });
+        }
+    });
+
+    let mut runs = Vec::new();
+    for res in results.into_inner().unwrap() {
+        runs.push(res.unwrap()?);
+    }
+
+    // Jobs of the same combination are next to each other
+    let mut aggregates: Vec<Aggregate> = Vec::new();
+    let mut samples: Vec<BTreeMap<String, Vec<f64>>> = Vec::new();
+    for run in &runs {
+        if aggregates.last().map(|a| &a.id) != Some(&run.id) {
+            aggregates.push(Aggregate {
+                id: run.id.clone(),
+                params: run.params.clone(),
+                metrics: BTreeMap::new(),
+            });
+            samples.push(BTreeMap::new());
+        }
+        for (metric, val) in &run.summary {
+            samples
+                .last_mut()
+                .unwrap()
+                .entry(metric.clone())
+                .or_default()
+                .push(*val);
+        }
+    }
+    for (agg, samples) in aggregates.iter_mut().zip(samples) {
+        for (metric, vals) in samples {
+            agg.metrics.insert(metric, aggregate(&vals));
+        }
+    }
+
+    let res = SweepResult { aggregates, runs };
+    if let Some(ref fname) = sweep.out_file {
+        let writer = std::io::BufWriter::new(std::fs::File::create(fname)?);
+        serde_json::to_writer_pretty(writer, &res)?;
+    } else {
+        println!("{}", serde_json::to_string_pretty(&res)?);
+    }
+    Ok(())
+}
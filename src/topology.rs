@@ -1,8 +1,12 @@
+use crate::abr::VideoClient;
 use crate::base::*;
 use crate::cc;
-use crate::config::{CCConfig, Config};
+use crate::config::{AppConfig, CCConfig, Config, RateControlConfig, TransportConfig};
 use crate::copa;
 use crate::copa2;
+use crate::gcc;
+use crate::media::*;
+use crate::random::rng_stream;
 use crate::simulator::*;
 use crate::tracer::Tracer;
 use crate::transport::*;
@@ -11,34 +15,49 @@ use failure::Error;
 
 /// Creates topology specified in Config and returns a Scheduler (with appropriate NetObjects). The
 /// base topology is as follows (tcp_sender -> delay) -> link -> router --..--> ackers -->
-/// aggregator --> back to corresponding senders
+/// aggregator --> back to corresponding senders. Fails if the config is invalid (see
+/// `Config::validate`)
 pub fn create_topology<'a>(config: &'a Config, tracer: &'a Tracer) -> Result<Scheduler<'a>, Error> {
-    let mut sched = Scheduler::default();
+    config.validate()?;
 
-    let link_id = sched.next_obj_id();
-    let router_id = link_id + 1;
+    let mut builder = SchedulerBuilder::default();
 
-    // Create bottleneck
-    let link_trace = LinkTrace::from_config(&config.topo.link, config)?;
-    let link = Link::new(link_trace, config.topo.bufsize, router_id, &tracer, &config);
-    let mut router = Router::new(sched.next_addr());
+    let link_id = builder.id("link");
+    let router_id = builder.id("router");
 
-    // Register the core objects. Remember to do it in the same order as the ids
-    sched.register_obj(Box::new(link));
-    //sched.register_obj(Box::new(acker));
+    // If there is a Wi-Fi hop, it is after the bottleneck for data and after the receivers for
+    // acks
+    let mut wifi = config.topo.wifi.as_ref().map(|wifi_config| {
+        Wifi::new(
+            wifi_config.clone(),
+            rng_stream(config.random_seed, "wifi"),
+            router_id,
+            tracer,
+        )
+    });
+    let wifi_id = wifi.as_ref().map(|_| builder.id("wifi"));
 
-    // List of objects we need to register, in the order we should register them. Before
-    // registering these, we'll register router
-    let mut objs_to_reg = Vec::<Box<dyn NetObj + 'a>>::new();
+    // Create bottleneck
+    let link_trace = LinkTrace::from_config(&config.topo.link, "link", config)?;
+    let link_events = LinkEvents::from_config(&config.topo.link_events, "link", config)?;
+    let link = Link::new(
+        link_trace,
+        config.topo.bufsize,
+        wifi_id.unwrap_or(router_id),
+        &tracer,
+        &config,
+        link_events,
+    );
+    let mut router = Router::new(builder.next_addr());
+    builder.add("link", Box::new(link))?;
 
     // Now create the senders
-    for group_config in &config.topo.sender_groups {
+    let mut flow = 0;
+    for group_config in &config.topo.all_sender_groups() {
         for _ in 0..group_config.num_senders {
             // Create congestion control
             let ccalg: Box<dyn CongestionControl> = match group_config.cc {
-                CCConfig::Const { cwnd, intersend } => {
-                    Box::new(cc::Const::new(cwnd, Time::from_micros(intersend)))
-                }
+                CCConfig::Const { cwnd, intersend } => Box::new(cc::Const::new(cwnd, intersend)),
                 CCConfig::AIMD => Box::new(cc::AIMD::default()),
                 CCConfig::InstantCC => Box::new(cc::InstantCC::default()),
                 CCConfig::OscInstantCC { k, omega } => Box::new(cc::OscInstantCC::new(k, omega)),
@@ -50,52 +69,119 @@ pub fn create_topology<'a>(config: &'a Config, tracer: &'a Tracer) -> Result<Sch
                 CCConfig::Copa2 => Box::new(copa2::Copa2::new(group_config.delay)),
             };
 
-            // Decide everybody's ids
-            let tcp_sender_id = router_id + 1 + objs_to_reg.len();
-            let delay_id = tcp_sender_id + 1;
-            let agg_id = delay_id + 1;
-            let acker_id = agg_id + 1;
+            // Decide everybody's names
+            let sender_name = format!("sender{}", flow);
+            let delay_name = format!("delay{}", flow);
+            let agg_name = format!("aggregator{}", flow);
+            let receiver_name = format!("receiver{}", flow);
+            flow += 1;
+            let sender_id = builder.id(&sender_name);
+            let delay_id = builder.id(&delay_name);
+            let agg_id = builder.id(&agg_name);
+            let receiver_id = builder.id(&receiver_name);
 
-            let acker_addr = sched.next_addr();
+            let acker_addr = builder.next_addr();
 
             // Create the sender and its delay module
-            let sender_addr = sched.next_addr();
-            let tcp_sender = TcpSender::new(
-                delay_id,
-                sender_addr,
-                acker_addr,
-                ccalg,
-                group_config.start_time,
-                group_config.tx_length,
-                &tracer,
-                config,
-            );
+            let sender_addr = builder.next_addr();
             let delay = Delay::new(group_config.delay, link_id);
 
-            // Create the acker
-            let acker = Acker::new(acker_addr, agg_id);
+            // Where the receiver sends acks
+            let ack_next_id = match &mut wifi {
+                Some(wifi) => {
+                    wifi.add_ack_route(sender_addr, agg_id);
+                    wifi_id.unwrap()
+                }
+                None => agg_id,
+            };
+
+            // Create the sender and receiver for the transport this group uses
+            let (sender, receiver): (Box<dyn NetObj + 'a>, Box<dyn NetObj + 'a>) =
+                match &group_config.transport {
+                    TransportConfig::Tcp => {
+                        let app: Option<Box<dyn TcpApp>> = match &group_config.app {
+                            AppConfig::Bulk => None,
+                            AppConfig::Video(video_config) => Some(Box::new(VideoClient::new(
+                                video_config.clone(),
+                                config.pkt_size,
+                                tracer,
+                            )?)),
+                        };
+                        let tcp_sender = TcpSender::new(
+                            delay_id,
+                            sender_addr,
+                            acker_addr,
+                            ccalg,
+                            group_config.start_time,
+                            group_config.tx_length,
+                            group_config.loss_detection,
+                            group_config.rto,
+                            app,
+                            group_config.rcv_bufsize,
+                            &tracer,
+                            config,
+                        );
+                        let acker = Acker::new(
+                            acker_addr,
+                            ack_next_id,
+                            group_config.rcv_bufsize,
+                            group_config.app_read_rate,
+                            config,
+                        );
+                        (Box::new(tcp_sender), Box::new(acker))
+                    }
+                    TransportConfig::Media(media_config) => {
+                        let rc: Box<dyn RateControl> = match media_config.rate_control {
+                            RateControlConfig::Const(rate) => Box::new(ConstRate::new(rate)),
+                            RateControlConfig::Gcc => Box::new(gcc::Gcc::new(
+                                media_config.start_bitrate,
+                                media_config.min_bitrate,
+                                media_config.max_bitrate,
+                            )),
+                        };
+                        let media_sender = MediaSender::new(
+                            delay_id,
+                            sender_addr,
+                            acker_addr,
+                            rc,
+                            media_config.clone(),
+                            group_config.start_time,
+                            group_config.tx_length,
+                            tracer,
+                            config,
+                        );
+                        let receiver = DatagramReceiver::new(
+                            acker_addr,
+                            ack_next_id,
+                            media_config.feedback_interval,
+                        );
+                        (Box::new(media_sender), Box::new(receiver))
+                    }
+                };
 
             // Add the aggregator after the acker
-            let aggregator = Aggregator::new(group_config.agg_intersend, tcp_sender_id);
+            let aggregator = Aggregator::new(
+                group_config.agg_intersend.clone(),
+                rng_stream(config.random_seed, &agg_name),
+                sender_id,
+            );
 
             // Add routes
-            let port = router.add_port(acker_id);
+            let port = router.add_port(receiver_id);
             router.add_route(acker_addr, port);
 
-            objs_to_reg.push(Box::new(tcp_sender));
-            objs_to_reg.push(Box::new(delay));
-            objs_to_reg.push(Box::new(aggregator));
-            objs_to_reg.push(Box::new(acker));
+            builder.add(&sender_name, sender)?;
+            builder.add(&delay_name, Box::new(delay))?;
+            builder.add(&agg_name, Box::new(aggregator))?;
+            builder.add(&receiver_name, receiver)?;
         }
     }
 
-    // First register the router, which we couldn't register earlier since we were still adding
-    // routes
-    sched.register_obj(Box::new(router));
-    // Register all sender-side objects with the scheduler
-    for obj in objs_to_reg {
-        sched.register_obj(obj);
+    // Add the router and Wi-Fi hop last, since we were adding routes to them till now
+    builder.add("router", Box::new(router))?;
+    if let Some(wifi) = wifi {
+        builder.add("wifi", Box::new(wifi))?;
     }
 
-    Ok(sched)
+    builder.build()
 }
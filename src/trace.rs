@@ -0,0 +1,398 @@
+//! Link trace files: reading and writing them in several formats, and transforming them
+
+use crate::simulator::Time;
+
+use failure::{format_err, Error};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+/// Format of a link trace file. Files whose name ends in `.gz` are gzip-compressed. In every
+/// format, blank lines and lines starting with `#` are ignored, and times are in milliseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum TraceFormat {
+    /// Mahimahi's format: each line is a time at which a full-sized packet can be delivered.
+    /// Several packets at the same time are given by repeating it. The trace loops after the last
+    /// time, so when written in this format, a trace ends at its last opportunity
+    #[default]
+    Mahimahi,
+    /// Each line is `time,bytes`: this many bytes can be delivered at that time. The trace loops
+    /// after the last time
+    TimeBytes,
+    /// Each line is `time,rate`: the link has this rate (in bytes per second) from that time till
+    /// that of the next line. The last line marks the end of the trace, where it loops
+    TimeRate,
+}
+
+/// A link trace: the times at which a full-sized packet can be delivered, in order. It loops after
+/// `duration`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    pub opportunities: Vec<Time>,
+    pub duration: Time,
+}
+
+/// Parse a number in a trace file
+fn parse_num(s: &str) -> Result<f64, Error> {
+    let val = s
+        .parse::<f64>()
+        .map_err(|_| format_err!("'{}' is not a number", s))?;
+    if !val.is_finite() || val < 0. {
+        return Err(format_err!(
+            "'{}' must be a finite number that is at least 0",
+            s
+        ));
+    }
+    Ok(val)
+}
+
+impl Trace {
+    /// Read a trace file. `pkt_size` is the size of the packets the link delivers, which
+    /// determines the delivery opportunities in formats that give bytes or rates
+    pub fn from_file(fname: &str, format: TraceFormat, pkt_size: u64) -> Result<Self, Error> {
+        let file = std::fs::File::open(fname)?;
+        let reader: Box<dyn Read> = if fname.ends_with(".gz") {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        Self::read(BufReader::new(reader), format, pkt_size)
+    }
+
+    /// Read a trace in the given format. See `from_file`
+    pub fn read<R: BufRead>(reader: R, format: TraceFormat, pkt_size: u64) -> Result<Self, Error> {
+        if pkt_size == 0 {
+            return Err(format_err!("packet size must be greater than 0"));
+        }
+        let num_fields = if format == TraceFormat::Mahimahi {
+            1
+        } else {
+            2
+        };
+        let mut rows: Vec<Vec<f64>> = Vec::new();
+        let mut seen_header = false;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|f| !f.is_empty())
+                .collect();
+            // CSV files often start with a header, which has no numbers in it
+            if rows.is_empty()
+                && !seen_header
+                && num_fields > 1
+                && !fields.is_empty()
+                && fields.iter().all(|f| f.parse::<f64>().is_err())
+            {
+                seen_header = true;
+                continue;
+            }
+            let row = (|| {
+                if fields.is_empty() {
+                    return Err(format_err!("no values in line"));
+                }
+                if fields.len() != num_fields {
+                    return Err(format_err!(
+                        "expected {} value(s), found {}",
+                        num_fields,
+                        fields.len()
+                    ));
+                }
+                let row = fields
+                    .iter()
+                    .map(|f| parse_num(f))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(prev) = rows.last() {
+                    if row[0] < prev[0] {
+                        return Err(format_err!("times must be non-decreasing"));
+                    }
+                }
+                Ok(row)
+            })()
+            .map_err(|e: Error| format_err!("line {}: {}", i + 1, e))?;
+            rows.push(row);
+        }
+        if rows.is_empty() {
+            return Err(format_err!("trace is empty"));
+        }
+
+        let ms = |t: f64| Time::from_micros((t * 1000.) as u64);
+        let duration = ms(rows.last().unwrap()[0]);
+        if duration == Time::ZERO {
+            return Err(format_err!(
+                "trace must have a delivery opportunity and last longer than 0"
+            ));
+        }
+        let mut opportunities = Vec::new();
+        match format {
+            TraceFormat::Mahimahi => opportunities.extend(rows.iter().map(|r| ms(r[0]))),
+            TraceFormat::TimeBytes => {
+                // Bytes left over carry on to the next time
+                let mut bytes = 0.;
+                for row in &rows {
+                    bytes += row[1];
+                    while bytes >= pkt_size as f64 {
+                        opportunities.push(ms(row[0]));
+                        bytes -= pkt_size as f64;
+                    }
+                }
+            }
+            TraceFormat::TimeRate => {
+                // Bytes the link could have delivered since the last opportunity
+                let mut bytes = 0.;
+                for w in rows.windows(2) {
+                    let (start, end, rate) = (w[0][0] / 1000., w[1][0] / 1000., w[0][1]);
+                    let mut t = start;
+                    while rate > 0. && t + (pkt_size as f64 - bytes) / rate <= end {
+                        t += (pkt_size as f64 - bytes) / rate;
+                        bytes = 0.;
+                        opportunities.push(Time::from_micros((t * 1e6) as u64));
+                    }
+                    bytes += (end - t) * rate;
+                }
+            }
+        }
+        if opportunities.is_empty() {
+            return Err(format_err!(
+                "trace must have a delivery opportunity and last longer than 0"
+            ));
+        }
+        Ok(Self {
+            opportunities,
+            duration,
+        })
+    }
+
+    /// Write the trace to a file ("-" is stdout), gzip-compressed if its name ends in `.gz`.
+    /// `window` is the granularity of rates in the `TimeRate` format
+    pub fn to_file(
+        &self,
+        fname: &str,
+        format: TraceFormat,
+        pkt_size: u64,
+        window: Time,
+    ) -> Result<(), Error> {
+        let writer: Box<dyn Write> = if fname == "-" {
+            Box::new(std::io::stdout())
+        } else if fname.ends_with(".gz") {
+            Box::new(GzEncoder::new(
+                std::fs::File::create(fname)?,
+                flate2::Compression::default(),
+            ))
+        } else {
+            Box::new(std::fs::File::create(fname)?)
+        };
+        self.write(BufWriter::new(writer), format, pkt_size, window)
+    }
+
+    /// Write the trace in the given format. See `to_file`
+    pub fn write<W: Write>(
+        &self,
+        mut writer: W,
+        format: TraceFormat,
+        pkt_size: u64,
+        window: Time,
+    ) -> Result<(), Error> {
+        let ms = |t: Time| t.micros() as f64 / 1000.;
+        match format {
+            TraceFormat::Mahimahi => {
+                // Every line is an opportunity, so the trace ends with the last one
+                for t in &self.opportunities {
+                    writeln!(writer, "{}", ms(*t))?;
+                }
+            }
+            TraceFormat::TimeBytes => {
+                writeln!(writer, "time,bytes")?;
+                let mut i = 0;
+                while i < self.opportunities.len() {
+                    let t = self.opportunities[i];
+                    let num = self.opportunities[i..]
+                        .iter()
+                        .take_while(|x| **x == t)
+                        .count();
+                    writeln!(writer, "{},{}", ms(t), num as u64 * pkt_size)?;
+                    i += num;
+                }
+                if self.opportunities.last() != Some(&self.duration) {
+                    writeln!(writer, "{},0", ms(self.duration))?;
+                }
+            }
+            TraceFormat::TimeRate => {
+                if window == Time::ZERO {
+                    return Err(format_err!("window must be greater than 0"));
+                }
+                writeln!(writer, "time,rate")?;
+                let rates = self.window_rates(pkt_size, window);
+                for (i, rate) in rates.iter().enumerate() {
+                    writeln!(writer, "{},{}", ms(window * i as u64), rate)?;
+                }
+                writeln!(writer, "{},0", ms(self.duration))?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Link rate in bytes per second over consecutive windows of the given length. The last
+    /// window may be cut short by the end of the trace
+    pub fn window_rates(&self, pkt_size: u64, window: Time) -> Vec<f64> {
+        assert!(window > Time::ZERO);
+        let num_windows =
+            std::cmp::max(self.duration.micros().div_ceil(window.micros()), 1) as usize;
+        let mut bytes = vec![0; num_windows];
+        for t in &self.opportunities {
+            let id = std::cmp::min((t.micros() / window.micros()) as usize, num_windows - 1);
+            bytes[id] += pkt_size;
+        }
+        bytes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| {
+                let start = window * i as u64;
+                let len = std::cmp::min(self.duration, start + window) - start;
+                if len == Time::ZERO {
+                    0.
+                } else {
+                    *b as f64 / len.secs()
+                }
+            })
+            .collect()
+    }
+
+    /// Mean rate in bytes per second
+    pub fn mean_rate(&self, pkt_size: u64) -> f64 {
+        (self.opportunities.len() as u64 * pkt_size) as f64 / self.duration.secs()
+    }
+
+    /// Multiply the link rate by `rate` and stretch time by `time`, which also divides the rate
+    /// by it
+    pub fn scale(&self, rate: f64, time: f64) -> Self {
+        assert!(rate >= 0. && time > 0.);
+        let stretch = |t: Time| Time::from_micros((t.micros() as f64 * time) as u64);
+        let mut opportunities = Vec::new();
+        // Fractional opportunities carry on to the next one
+        let mut credit = 0.;
+        for t in &self.opportunities {
+            credit += rate;
+            while credit >= 1. {
+                opportunities.push(stretch(*t));
+                credit -= 1.;
+            }
+        }
+        Self {
+            opportunities,
+            duration: stretch(self.duration),
+        }
+    }
+
+    /// The traces one after the other
+    pub fn splice(traces: &[Trace]) -> Self {
+        let mut opportunities = Vec::new();
+        let mut offset = Time::ZERO;
+        for trace in traces {
+            opportunities.extend(trace.opportunities.iter().map(|t| offset + *t));
+            offset = offset + trace.duration;
+        }
+        Self {
+            opportunities,
+            duration: offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(s: &str, format: TraceFormat) -> Result<Trace, Error> {
+        Trace::read(s.as_bytes(), format, 1000)
+    }
+
+    fn millis(times: &[u64]) -> Vec<Time> {
+        times.iter().map(|t| Time::from_millis(*t)).collect()
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let trace = read(
+            "# a comment\n\n1\n  \n# another\n1\n3.5\n",
+            TraceFormat::Mahimahi,
+        )
+        .unwrap();
+        assert_eq!(
+            trace.opportunities,
+            vec![
+                Time::from_millis(1),
+                Time::from_millis(1),
+                Time::from_micros(3500)
+            ]
+        );
+        assert_eq!(trace.duration, Time::from_micros(3500));
+    }
+
+    #[test]
+    fn skips_one_header() {
+        let trace = read("time,bytes\n1,2000\n4,500\n", TraceFormat::TimeBytes).unwrap();
+        assert_eq!(trace.opportunities, millis(&[1, 1]));
+        assert_eq!(trace.duration, Time::from_millis(4));
+        // Only a line without any numbers is a header
+        assert!(read("time,1000\n1,2000\n", TraceFormat::TimeBytes).is_err());
+        assert!(read("time,bytes\ntime,bytes\n1,2000\n", TraceFormat::TimeBytes).is_err());
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        let cases = [
+            ("1\n,\n2\n", TraceFormat::Mahimahi, "line 2"),
+            ("0,1000\n,\n2,0\n", TraceFormat::TimeRate, "line 2"),
+            ("1\nx\n", TraceFormat::Mahimahi, "line 2"),
+            ("1\n2 3\n", TraceFormat::Mahimahi, "line 2"),
+            ("time,rate\n0,1000\n2\n", TraceFormat::TimeRate, "line 3"),
+            ("0,1000\n2,-1\n", TraceFormat::TimeRate, "line 2"),
+            ("0,1000\n2,inf\n", TraceFormat::TimeRate, "line 2"),
+            ("2\n1\n", TraceFormat::Mahimahi, "line 2"),
+        ];
+        for (file, format, line) in cases {
+            let err = read(file, format).unwrap_err().to_string();
+            assert!(err.starts_with(line), "{:?}: {}", file, err);
+        }
+    }
+
+    #[test]
+    fn single_entry() {
+        let trace = read("5\n", TraceFormat::Mahimahi).unwrap();
+        assert_eq!(trace.opportunities, millis(&[5]));
+        assert_eq!(trace.duration, Time::from_millis(5));
+        // Such a trace would have no duration, or no capacity
+        assert!(read("0\n", TraceFormat::Mahimahi).is_err());
+        assert!(read("5,1000\n", TraceFormat::TimeRate).is_err());
+        assert!(read("", TraceFormat::Mahimahi).is_err());
+        assert!(read("# only a comment\n", TraceFormat::Mahimahi).is_err());
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let trace = Trace {
+            opportunities: millis(&[0, 0, 3, 7]),
+            duration: Time::from_millis(10),
+        };
+        let fname =
+            std::env::temp_dir().join(format!("cc_sim_trace_{}.csv.gz", std::process::id()));
+        let fname = fname.to_str().unwrap();
+        trace
+            .to_file(fname, TraceFormat::TimeBytes, 1000, Time::from_millis(1))
+            .unwrap();
+        // The file really is compressed
+        let raw = std::fs::read(fname).unwrap();
+        assert_eq!(raw[..2], [0x1f, 0x8b]);
+        let read = Trace::from_file(fname, TraceFormat::TimeBytes, 1000);
+        std::fs::remove_file(fname).unwrap();
+        assert_eq!(read.unwrap(), trace);
+    }
+}
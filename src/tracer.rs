@@ -3,12 +3,13 @@
 use crate::config::Config;
 use crate::simulator::*;
 
+use failure::Error;
 use gnuplot::AxesCommon;
 use histogram::Histogram;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::default::Default;
 
 /// One 'row' of a trace. Multiple objects can
@@ -24,6 +25,34 @@ pub enum TraceElem {
     TcpSenderLoss(u64),
     /// Just the time when a timeout was detected
     TcpSenderTimeout,
+    /// The sender wanted to transmit, but its congestion window was full for this long
+    TcpSenderCwndLimited(Time),
+    /// The sender's congestion window permitted a transmission, but the receive window advertised
+    /// by the receiver didn't, for this long
+    TcpSenderRwndLimited(Time),
+    /// The given number of packets were acked after being marked as lost
+    TcpSenderSpuriousRtx(u64),
+    /// The sender sent a tail loss probe
+    TcpSenderTailLossProbe,
+    /// The given number of datagrams sent by a `MediaSender`, with this many bytes in total, were
+    /// reported as received
+    MediaPktDelivered(u64, u64),
+    /// The given number of datagrams sent by a `MediaSender` were reported as lost
+    MediaPktLost(u64),
+    /// All datagrams of a frame arrived. Gives the time from capture to the last arrival
+    MediaFrameDelivered(Time),
+    /// Some datagram of a frame was lost, so it can't be decoded
+    MediaFrameLost,
+    /// The encoder's target bitrate (in bytes per second) when it produced a frame
+    MediaEncoderRate(u64),
+    /// A video chunk of the given bitrate (in bytes per second) finished downloading
+    VideoChunk(u64),
+    /// The video client picked a different bitrate than for the previous chunk
+    VideoBitrateSwitch,
+    /// Video playback resumed after stalling for this long
+    VideoRebuffer(Time),
+    /// Video playback started this long after the session started
+    VideoStartup(Time),
     /// When the given link had a transmission opportunity
     LinkTxOpportunity,
     /// A packet arrived at the link at this time. Format: (time, source addr, pkt size)
@@ -41,6 +70,19 @@ impl TraceElem {
             Self::TcpSenderRtt(_) => false,
             Self::TcpSenderLoss(_) => false,
             Self::TcpSenderTimeout => false,
+            Self::TcpSenderCwndLimited(_) => false,
+            Self::TcpSenderRwndLimited(_) => false,
+            Self::TcpSenderSpuriousRtx(_) => false,
+            Self::TcpSenderTailLossProbe => false,
+            Self::MediaPktDelivered(_, _) => false,
+            Self::MediaPktLost(_) => false,
+            Self::MediaFrameDelivered(_) => false,
+            Self::MediaFrameLost => false,
+            Self::MediaEncoderRate(_) => false,
+            Self::VideoChunk(_) => false,
+            Self::VideoBitrateSwitch => false,
+            Self::VideoRebuffer(_) => false,
+            Self::VideoStartup(_) => false,
             Self::LinkTxOpportunity => true,
             Self::LinkIngress(_, _) => true,
             Self::LinkEgress(_) => true,
@@ -55,6 +97,19 @@ impl TraceElem {
             Self::TcpSenderRtt(_) => true,
             Self::TcpSenderLoss(_) => true,
             Self::TcpSenderTimeout => true,
+            Self::TcpSenderCwndLimited(_) => true,
+            Self::TcpSenderRwndLimited(_) => true,
+            Self::TcpSenderSpuriousRtx(_) => true,
+            Self::TcpSenderTailLossProbe => true,
+            Self::MediaPktDelivered(_, _) => true,
+            Self::MediaPktLost(_) => true,
+            Self::MediaFrameDelivered(_) => true,
+            Self::MediaFrameLost => true,
+            Self::MediaEncoderRate(_) => true,
+            Self::VideoChunk(_) => true,
+            Self::VideoBitrateSwitch => true,
+            Self::VideoRebuffer(_) => true,
+            Self::VideoStartup(_) => true,
             Self::LinkTxOpportunity => false,
             Self::LinkIngress(_, _) => false,
             Self::LinkEgress(_) => false,
@@ -64,6 +119,7 @@ impl TraceElem {
 
 /// Contains data about transmission opportunities and ingress and egress rates in a given a bucket
 /// of time. Each bucket lasts for ConfigLog::link_bucket_size.
+#[derive(Clone, Serialize, Deserialize)]
 struct LinkBucket {
     /// The time when this bucket started
     start_time: Time,
@@ -87,6 +143,7 @@ impl LinkBucket {
 }
 
 /// Per-flow statistics about a period of time
+#[derive(Clone, Serialize, Deserialize)]
 struct SenderStats {
     /// The configured time for which we are collecting statistics. If ending time is `None`, it is
     /// infinity
@@ -95,10 +152,77 @@ struct SenderStats {
     /// last event reported by the sender in the time period specified in the config}
     pkt_period: Option<(Time, Time)>,
     num_cum_acked: u64,
+    /// Number of media datagrams reported as received, and the bytes in them. Unlike TCP packets,
+    /// they are not all `pkt_size` bytes long
+    num_media_delivered: u64,
+    media_bytes_delivered: u64,
     num_lost: u64,
     num_timeouts: u64,
+    /// Time for which the sender couldn't transmit because of the congestion window
+    cwnd_limited_time: Time,
+    /// Time for which the sender couldn't transmit because of the receive window
+    rwnd_limited_time: Time,
+    /// Number of packets that were acked after being marked as lost
+    num_spurious_rtx: u64,
+    num_tail_loss_probes: u64,
+    /// Number of media frames that were fully delivered
+    num_frames_delivered: u64,
+    /// Number of media frames that lost at least one packet
+    num_frames_lost: u64,
+    /// Number of video chunks downloaded
+    num_video_chunks: u64,
+    num_bitrate_switches: u64,
+    /// Number of times video playback stalled, and for how long in total
+    num_rebuffers: u64,
+    rebuffer_time: Time,
+    /// Time for video playback to start
+    startup_delay: Option<Time>,
+    #[serde(with = "hist_buckets")]
     rtt: Histogram,
+    #[serde(with = "hist_buckets")]
     cwnd: Histogram,
+    /// Time from capture to delivery of media frames
+    #[serde(with = "hist_buckets")]
+    frame_delay: Histogram,
+    #[serde(with = "hist_buckets")]
+    encoder_rate: Histogram,
+    /// Bitrates of the downloaded video chunks
+    #[serde(with = "hist_buckets")]
+    video_bitrate: Histogram,
+}
+
+/// Saves a `Histogram` created with `Histogram::new` as the value and count of each of its
+/// non-empty buckets, since it isn't `Serialize` itself
+mod hist_buckets {
+    use histogram::Histogram;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::{Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(hist: &Histogram, serializer: S) -> Result<S::Ok, S::Error> {
+        let buckets: Vec<(u64, u64)> = hist
+            .into_iter()
+            .filter(|bucket| bucket.count() > 0)
+            // Past the linear buckets, `value` is where the next bucket starts, so step back into
+            // this one
+            .map(|bucket| {
+                let value = if bucket.value() == bucket.id() {
+                    bucket.value()
+                } else {
+                    bucket.value() - 1
+                };
+                (value, bucket.count())
+            })
+            .collect();
+        buckets.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Histogram, D::Error> {
+        let mut hist = Histogram::new();
+        for (value, count) in Vec::<(u64, u64)>::deserialize(deserializer)? {
+            hist.increment_by(value, count).map_err(de::Error::custom)?;
+        }
+        Ok(hist)
+    }
 }
 
 /// The data we serialize from `histogram::Histogram`
@@ -114,16 +238,32 @@ struct HistSerialize {
     p100: u64,
 }
 
-/// The data we serialize from `SenderStats`
+/// The data we serialize from `SenderStats`. Times are in microseconds, like the RTTs in `rtt`
 #[derive(Serialize)]
 struct SenderStatsSerialize {
-    config_period: (Time, Option<Time>),
-    pkt_period: (Time, Time),
+    config_period: (u64, Option<u64>),
+    pkt_period: (u64, u64),
     num_cum_acked: u64,
+    num_media_delivered: u64,
+    media_bytes_delivered: u64,
     num_lost: u64,
     num_timeouts: u64,
+    cwnd_limited_time: u64,
+    rwnd_limited_time: u64,
+    num_spurious_rtx: u64,
+    num_tail_loss_probes: u64,
+    num_frames_delivered: u64,
+    num_frames_lost: u64,
+    num_video_chunks: u64,
+    num_bitrate_switches: u64,
+    num_rebuffers: u64,
+    rebuffer_time: u64,
+    startup_delay: Option<u64>,
     rtt: Option<HistSerialize>,
     cwnd: Option<HistSerialize>,
+    frame_delay: Option<HistSerialize>,
+    encoder_rate: Option<HistSerialize>,
+    video_bitrate: Option<HistSerialize>,
 }
 
 impl SenderStats {
@@ -132,10 +272,26 @@ impl SenderStats {
             config_period,
             pkt_period: None,
             num_cum_acked: 0,
+            num_media_delivered: 0,
+            media_bytes_delivered: 0,
             num_lost: 0,
             num_timeouts: 0,
+            cwnd_limited_time: Time::ZERO,
+            rwnd_limited_time: Time::ZERO,
+            num_spurious_rtx: 0,
+            num_tail_loss_probes: 0,
+            num_frames_delivered: 0,
+            num_frames_lost: 0,
+            num_video_chunks: 0,
+            num_bitrate_switches: 0,
+            num_rebuffers: 0,
+            rebuffer_time: Time::ZERO,
+            startup_delay: None,
             rtt: Histogram::new(),
             cwnd: Histogram::new(),
+            frame_delay: Histogram::new(),
+            encoder_rate: Histogram::new(),
+            video_bitrate: Histogram::new(),
         }
     }
 
@@ -163,6 +319,35 @@ impl SenderStats {
             TraceElem::TcpSenderRtt(rtt) => self.rtt.increment(rtt.micros()).unwrap(),
             TraceElem::TcpSenderLoss(num_lost) => self.num_lost += num_lost,
             TraceElem::TcpSenderTimeout => self.num_timeouts += 1,
+            TraceElem::TcpSenderCwndLimited(dur) => {
+                self.cwnd_limited_time = self.cwnd_limited_time + *dur
+            }
+            TraceElem::TcpSenderRwndLimited(dur) => {
+                self.rwnd_limited_time = self.rwnd_limited_time + *dur
+            }
+            TraceElem::TcpSenderSpuriousRtx(num) => self.num_spurious_rtx += num,
+            TraceElem::TcpSenderTailLossProbe => self.num_tail_loss_probes += 1,
+            TraceElem::MediaPktDelivered(num, bytes) => {
+                self.num_media_delivered += num;
+                self.media_bytes_delivered += bytes;
+            }
+            TraceElem::MediaPktLost(num) => self.num_lost += num,
+            TraceElem::MediaFrameDelivered(delay) => {
+                self.num_frames_delivered += 1;
+                self.frame_delay.increment(delay.micros()).unwrap()
+            }
+            TraceElem::MediaFrameLost => self.num_frames_lost += 1,
+            TraceElem::MediaEncoderRate(rate) => self.encoder_rate.increment(*rate).unwrap(),
+            TraceElem::VideoChunk(bitrate) => {
+                self.num_video_chunks += 1;
+                self.video_bitrate.increment(*bitrate).unwrap()
+            }
+            TraceElem::VideoBitrateSwitch => self.num_bitrate_switches += 1,
+            TraceElem::VideoRebuffer(dur) => {
+                self.num_rebuffers += 1;
+                self.rebuffer_time = self.rebuffer_time + *dur;
+            }
+            TraceElem::VideoStartup(delay) => self.startup_delay = Some(*delay),
             TraceElem::LinkTxOpportunity => {}
             TraceElem::LinkIngress(_, _) => {}
             TraceElem::LinkEgress(_) => {}
@@ -188,13 +373,32 @@ impl SenderStats {
         }
         if let Some(pkt_period) = self.pkt_period {
             Some(SenderStatsSerialize {
-                config_period: self.config_period,
-                pkt_period,
+                config_period: (
+                    self.config_period.0.micros(),
+                    self.config_period.1.map(Time::micros),
+                ),
+                pkt_period: (pkt_period.0.micros(), pkt_period.1.micros()),
                 num_cum_acked: self.num_cum_acked,
+                num_media_delivered: self.num_media_delivered,
+                media_bytes_delivered: self.media_bytes_delivered,
                 num_lost: self.num_lost,
                 num_timeouts: self.num_timeouts,
+                cwnd_limited_time: self.cwnd_limited_time.micros(),
+                rwnd_limited_time: self.rwnd_limited_time.micros(),
+                num_spurious_rtx: self.num_spurious_rtx,
+                num_tail_loss_probes: self.num_tail_loss_probes,
+                num_frames_delivered: self.num_frames_delivered,
+                num_frames_lost: self.num_frames_lost,
+                num_video_chunks: self.num_video_chunks,
+                num_bitrate_switches: self.num_bitrate_switches,
+                num_rebuffers: self.num_rebuffers,
+                rebuffer_time: self.rebuffer_time.micros(),
+                startup_delay: self.startup_delay.map(Time::micros),
                 rtt: hist_to_json(&self.rtt),
                 cwnd: hist_to_json(&self.cwnd),
+                frame_delay: hist_to_json(&self.frame_delay),
+                encoder_rate: hist_to_json(&self.encoder_rate),
+                video_bitrate: hist_to_json(&self.video_bitrate),
             })
         } else {
             None
@@ -202,6 +406,7 @@ impl SenderStats {
     }
 }
 
+#[derive(Clone)]
 pub struct Tracer<'a> {
     config: &'a Config,
     /// Drain to pass values to
@@ -216,6 +421,18 @@ pub struct Tracer<'a> {
     link_tx_ops: RefCell<HashMap<NetObjId, Vec<u64>>>,
 }
 
+/// What `Tracer` saves. Same as its fields
+#[derive(Serialize, Deserialize)]
+struct TracerState {
+    cwnds: HashMap<NetObjId, Vec<(Time, u64)>>,
+    rtts: HashMap<NetObjId, Vec<(Time, Time)>>,
+    losses: HashMap<NetObjId, Vec<(Time, u64)>>,
+    timeouts: HashMap<NetObjId, Vec<Time>>,
+    link_stats: HashMap<NetObjId, Vec<LinkBucket>>,
+    sender_stats: HashMap<NetObjId, Vec<SenderStats>>,
+    link_tx_ops: HashMap<NetObjId, Vec<u64>>,
+}
+
 impl<'a> Tracer<'a> {
     pub fn new(config: &'a Config) -> Self {
         Self {
@@ -230,6 +447,45 @@ impl<'a> Tracer<'a> {
         }
     }
 
+    /// Replace everything logged so far with what was logged in `other`
+    pub fn restore(&self, other: &Tracer) {
+        self.cwnds.replace(other.cwnds.borrow().clone());
+        self.rtts.replace(other.rtts.borrow().clone());
+        self.losses.replace(other.losses.borrow().clone());
+        self.timeouts.replace(other.timeouts.borrow().clone());
+        self.link_stats.replace(other.link_stats.borrow().clone());
+        self.sender_stats
+            .replace(other.sender_stats.borrow().clone());
+        self.link_tx_ops.replace(other.link_tx_ops.borrow().clone());
+    }
+
+    /// Everything logged so far, so that it can be saved along with the simulation (see
+    /// `Scheduler::save`)
+    pub fn save(&self) -> Result<serde_json::Value, Error> {
+        Ok(serde_json::to_value(TracerState {
+            cwnds: self.cwnds.borrow().clone(),
+            rtts: self.rtts.borrow().clone(),
+            losses: self.losses.borrow().clone(),
+            timeouts: self.timeouts.borrow().clone(),
+            link_stats: self.link_stats.borrow().clone(),
+            sender_stats: self.sender_stats.borrow().clone(),
+            link_tx_ops: self.link_tx_ops.borrow().clone(),
+        })?)
+    }
+
+    /// Replace everything logged so far with `state`, as returned by `save`
+    pub fn load(&self, state: serde_json::Value) -> Result<(), Error> {
+        let state: TracerState = serde_json::from_value(state)?;
+        self.cwnds.replace(state.cwnds);
+        self.rtts.replace(state.rtts);
+        self.losses.replace(state.losses);
+        self.timeouts.replace(state.timeouts);
+        self.link_stats.replace(state.link_stats);
+        self.sender_stats.replace(state.sender_stats);
+        self.link_tx_ops.replace(state.link_tx_ops);
+        Ok(())
+    }
+
     /// Log this event. Will take action according to the configuration. NOTE: Logging to file is
     /// not yet implemented
     pub fn log(&self, from: NetObjId, now: Time, elem: TraceElem) {
@@ -290,6 +546,23 @@ impl<'a> Tracer<'a> {
                     insert(from, now, &self.timeouts)
                 }
             }
+            TraceElem::TcpSenderCwndLimited(_) => {}
+            TraceElem::TcpSenderRwndLimited(_) => {}
+            TraceElem::TcpSenderSpuriousRtx(_) => {}
+            TraceElem::TcpSenderTailLossProbe => {}
+            TraceElem::MediaPktDelivered(_, _) => {}
+            TraceElem::MediaPktLost(num) => {
+                if num > 0 && self.config.log.sender_losses.plot() {
+                    insert(from, (now, num), &self.losses)
+                }
+            }
+            TraceElem::MediaFrameDelivered(_) => {}
+            TraceElem::MediaFrameLost => {}
+            TraceElem::MediaEncoderRate(_) => {}
+            TraceElem::VideoChunk(_) => {}
+            TraceElem::VideoBitrateSwitch => {}
+            TraceElem::VideoRebuffer(_) => {}
+            TraceElem::VideoStartup(_) => {}
             TraceElem::LinkTxOpportunity => {
                 if self.config.log.link_rates.plot() {
                     bucket.as_mut().unwrap().num_tx_opps += 1500;
@@ -466,7 +739,87 @@ impl<'a> Tracer<'a> {
             }
         }
 
-        // Output sender statistics
+        // Output wherever we are asked to
+        let stats_ser = self.stats_serializable();
+        if let Some(ref fname) = self.config.log.stats_file {
+            let writer = std::io::BufWriter::new(std::fs::File::create(fname)?);
+            serde_json::to_writer(writer, &stats_ser)?;
+        } else {
+            println!("{}", serde_json::to_string_pretty(&stats_ser)?);
+        };
+
+        Ok(())
+    }
+
+    /// The sender and link statistics `finalize` outputs
+    pub fn stats(&self) -> serde_json::Value {
+        serde_json::to_value(self.stats_serializable()).unwrap()
+    }
+
+    /// A few headline metrics of the run, computed over all senders in the first of
+    /// `ConfigLog::stats_intervals`: total throughput (bytes/s), Jain's fairness index of the
+    /// senders' throughputs, the fraction of packets lost, the mean and 95th percentile RTT (ms,
+    /// averaged over senders) and the number of timeouts
+    pub fn summary(&self) -> BTreeMap<String, f64> {
+        let mut tpts = Vec::new();
+        let (mut num_acked, mut num_lost, mut num_timeouts) = (0, 0, 0);
+        let (mut rtt_means, mut rtt_p95s) = (Vec::new(), Vec::new());
+        for stats in self.sender_stats.borrow().values() {
+            let stats = if let Some(stats) = stats.first() {
+                stats
+            } else {
+                continue;
+            };
+            let (start, end) = if let Some(pkt_period) = stats.pkt_period {
+                pkt_period
+            } else {
+                continue;
+            };
+            if end > start {
+                let bytes =
+                    stats.num_cum_acked * self.config.pkt_size + stats.media_bytes_delivered;
+                tpts.push(bytes as f64 / (end - start).secs());
+            }
+            num_acked += stats.num_cum_acked + stats.num_media_delivered;
+            num_lost += stats.num_lost;
+            num_timeouts += stats.num_timeouts;
+            if stats.rtt.entries() > 0 {
+                rtt_means.push(stats.rtt.mean().unwrap() as f64 / 1e3);
+                rtt_p95s.push(stats.rtt.percentile(95.).unwrap() as f64 / 1e3);
+            }
+        }
+
+        fn mean(vals: &[f64]) -> f64 {
+            vals.iter().sum::<f64>() / vals.len() as f64
+        }
+        let mut res = BTreeMap::new();
+        if !tpts.is_empty() {
+            let sum: f64 = tpts.iter().sum();
+            let sum_sq: f64 = tpts.iter().map(|x| x * x).sum();
+            res.insert("throughput".to_string(), sum);
+            if sum_sq > 0. {
+                res.insert(
+                    "jain_fairness".to_string(),
+                    sum * sum / (tpts.len() as f64 * sum_sq),
+                );
+            }
+        }
+        if num_acked + num_lost > 0 {
+            res.insert(
+                "loss_rate".to_string(),
+                num_lost as f64 / (num_acked + num_lost) as f64,
+            );
+        }
+        if !rtt_means.is_empty() {
+            res.insert("mean_rtt_ms".to_string(), mean(&rtt_means));
+            res.insert("p95_rtt_ms".to_string(), mean(&rtt_p95s));
+        }
+        res.insert("num_timeouts".to_string(), num_timeouts as f64);
+        res
+    }
+
+    /// Sender and link statistics in the form we output them
+    fn stats_serializable(&self) -> impl Serialize {
         // The sender object we'll finally serialize
         let mut sender_stats_ser = HashMap::<NetObjId, Vec<SenderStatsSerialize>>::new();
         for (from, stats_intervals) in self.sender_stats.borrow().iter() {
@@ -487,19 +840,9 @@ impl<'a> Tracer<'a> {
             link_tx_ops: HashMap<NetObjId, Vec<u64>>,
             sender_stats: HashMap<NetObjId, Vec<SenderStatsSerialize>>,
         }
-        let stats_ser = LinkTraceSerialize {
+        LinkTraceSerialize {
             link_tx_ops: self.link_tx_ops.borrow().clone(),
             sender_stats: sender_stats_ser,
-        };
-
-        // Output wherever we are asked to
-        if let Some(ref fname) = self.config.log.stats_file {
-            let writer = std::io::BufWriter::new(std::fs::File::create(fname)?);
-            serde_json::to_writer(writer, &stats_ser)?;
-        } else {
-            println!("{}", serde_json::to_string_pretty(&stats_ser)?);
-        };
-
-        Ok(())
+        }
     }
 }
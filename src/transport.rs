@@ -1,14 +1,15 @@
-use crate::config::Config;
+use crate::base::BufferSize;
+use crate::config::{Config, LossDetectionConfig, RtoConfig};
 use crate::simulator::*;
 use crate::tracer::{TraceElem, Tracer};
 
 use failure::Error;
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use std::rc::Rc;
+use std::collections::{BTreeMap, VecDeque};
 
-pub trait CongestionControl {
+pub trait CongestionControl: CloneCongestionControl + SaveState {
     /// Called each time an ack arrives. `loss` denotes the number of in-flight packets that are
     /// believed to be lost, estimated using timeouts and sacks. Due to reordering, this estimate may
     /// be wrong. `rtt` can be estimated with packet UIDs alone, but is provided for convenience.
@@ -21,6 +22,63 @@ pub trait CongestionControl {
     fn get_cwnd(&mut self) -> u64;
     /// Returns the minimum interval between any two transmitted packets
     fn get_intersend_time(&mut self) -> Time;
+    /// Called when `num_spurious` packets that were believed to be lost (and reported as such in
+    /// `on_ack` or `on_timeout`) turned out to have been received after all
+    fn on_spurious_retransmit(&mut self, _now: Time, _num_spurious: u64) {}
+}
+
+/// Lets us clone `Box<dyn CongestionControl>`. Implemented for every `CongestionControl` that is
+/// `Clone`
+pub trait CloneCongestionControl {
+    fn clone_box(&self) -> Box<dyn CongestionControl>;
+}
+
+impl<T: CongestionControl + Clone + 'static> CloneCongestionControl for T {
+    fn clone_box(&self) -> Box<dyn CongestionControl> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn CongestionControl> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
+}
+
+/// An application that decides what data a `TcpSender` sends. Without one, the sender always has
+/// data to send (until `TcpSenderTxLength` is reached)
+pub trait TcpApp: CloneTcpApp + SaveState {
+    /// Number of packets the application has handed to the sender so far. The sender may only
+    /// send fresh packets with sequence numbers less than this
+    fn send_limit(&self) -> SeqNum;
+    /// Called when all packets with sequence numbers less than `cum_ack` have been delivered.
+    /// Returns a time at which `on_wakeup` should be called, if any
+    fn on_delivered(&mut self, obj_id: NetObjId, now: Time, cum_ack: SeqNum) -> Option<Time>;
+    /// Called when the flow starts and at times requested by `on_delivered` or `on_wakeup`. Only
+    /// the latest request is honored. Returns a time at which it should be called again, if any
+    fn on_wakeup(&mut self, obj_id: NetObjId, now: Time) -> Option<Time>;
+}
+
+/// Lets us clone `Box<dyn TcpApp>`. Implemented for every `TcpApp` that is `Clone`
+pub trait CloneTcpApp {
+    fn clone_box<'b>(&self) -> Box<dyn TcpApp + 'b>
+    where
+        Self: 'b;
+}
+
+impl<T: TcpApp + Clone> CloneTcpApp for T {
+    fn clone_box<'b>(&self) -> Box<dyn TcpApp + 'b>
+    where
+        Self: 'b,
+    {
+        Box::new(self.clone())
+    }
+}
+
+impl<'a> Clone for Box<dyn TcpApp + 'a> {
+    fn clone(&self) -> Self {
+        (**self).clone_box()
+    }
 }
 
 impl CongestionControl for Box<dyn CongestionControl> {
@@ -42,9 +100,12 @@ impl CongestionControl for Box<dyn CongestionControl> {
     fn get_intersend_time(&mut self) -> Time {
         (**self).get_intersend_time()
     }
+    fn on_spurious_retransmit(&mut self, now: Time, num_spurious: u64) {
+        (**self).on_spurious_retransmit(now, num_spurious)
+    }
 }
 
-#[derive(Debug, Hash)]
+#[derive(Clone, Debug, Hash, Serialize, Deserialize)]
 pub enum TransportHeader {
     Data {
         /// Sequence number of the packet
@@ -56,6 +117,8 @@ pub enum TransportHeader {
         /// Cumulative ack (i.e. largest sequence number received so far)
         /// UID for the packet being acked
         ack_uid: PktId,
+        /// Sequence number of the packet being acked
+        ack_seq: SeqNum,
         /// Cumulative ack: all packets upto (but not including) this sequence number have been
         /// received
         cum_ack: SeqNum,
@@ -63,31 +126,61 @@ pub enum TransportHeader {
         /// right_edge) of the block being acked, where the limits are (inclusive, exclusive),
         /// similar to  IETF RFC 2018
         sack: Vec<(SeqNum, SeqNum)>,
+        /// Advertised receive window (in packets): the sender may send sequence numbers upto (but
+        /// not including) `cum_ack + rwnd`. `u64::MAX` if the receiver's buffer is infinite
+        rwnd: u64,
+    },
+    /// Sent by the receiver when its window opens up without a data packet to ack, e.g. because
+    /// the application read data from the buffer
+    WindowUpdate {
+        /// Same as in `Ack`
+        cum_ack: SeqNum,
+        /// Same as in `Ack`
+        rwnd: u64,
+    },
+    /// An unreliable datagram, e.g. carrying real-time media
+    Datagram {
+        /// Sequence number of the datagram. Feedback refers to datagrams by this
+        seq_num: SeqNum,
+    },
+    /// Per-packet arrival feedback for datagrams, similar to RTCP transport-wide congestion
+    /// control feedback
+    Feedback {
+        /// For each sequence number in the reported range (in increasing order), when it arrived
+        /// at the receiver. `None` if it hadn't arrived when the feedback was generated
+        arrivals: Vec<(SeqNum, Option<Time>)>,
     },
 }
 
 /// How long the TcpSender should send packets
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
 pub enum TcpSenderTxLength {
     Duration(Time),
-    Bytes(u64),
+    Bytes(
+        #[serde(with = "crate::units::bytes")]
+        #[schemars(with = "crate::units::ByteSize")]
+        u64,
+    ),
     Infinite,
 }
 
 /// To track status in TrackRxPackets. Packets that haven't been received, follow the following
-/// state machine:
+/// state machine (`n` is the dupack threshold, 3 by default). With RACK, the sender marks packets
+/// as lost by itself instead of counting dupacks:
 ///
+/// ```text
 ///                      NotReceived(0)
 ///                          |
-///                          |  3 dupacks
+///                          |  n dupacks
 ///                         \ /
 ///                 ------> Lost
 ///                 |        |
-///       3 dupacks |        |  when retransmitted
+///       n dupacks |        |  when retransmitted
 ///                 |       \ /
 ///                 --- Retransmitted(0)
-#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+/// ```
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Serialize, Deserialize)]
 enum PktStatus {
     Received,
     /// Packet hasn't been received. Counts number of dupacks
@@ -100,7 +193,7 @@ enum PktStatus {
 }
 
 /// Track which packets have been received. This logic is useful for both TCP sender and receiver
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct TrackRxPackets {
     /// The range of sequence numbers that we currently need to track (left included, right
     /// excluded). Left is the smallest sequence number
@@ -111,15 +204,19 @@ struct TrackRxPackets {
     num_lost: u64,
     /// Number of packets that have been marked as lost but haven't been reported to the CC yet
     num_unreported_lost: u64,
+    /// Mark a packet as lost after these many dupacks. If `None`, packets are never marked lost
+    /// automatically
+    dupack_thresh: Option<u64>,
 }
 
 impl TrackRxPackets {
-    fn new() -> Self {
+    fn new(dupack_thresh: Option<u64>) -> Self {
         Self {
             range: (0, 0),
             status: VecDeque::new(),
             num_lost: 0,
             num_unreported_lost: 0,
+            dupack_thresh,
         }
     }
 
@@ -142,13 +239,13 @@ impl TrackRxPackets {
     /// Mark the status of a packet. By default, a packet is assumed to be `PktStatus::NotReceived`
     /// Both sender and receiver mark packets as received. Sender marks them as not received when
     /// sending packets and as lost on timeout. This module automatically marks them as lost if it
-    /// sees 3 dupacks
-    fn mark_pkt(&mut self, seq_num: SeqNum, received: PktStatus) {
+    /// sees `dupack_thresh` dupacks. Returns the previous status of the packet
+    fn mark_pkt(&mut self, seq_num: SeqNum, received: PktStatus) -> PktStatus {
         assert!(self.status.len() == (self.range.1 - self.range.0) as usize);
         // We should never have to extend it at the left
         if seq_num < self.range.0 {
             // Probably due to a retransmission
-            return;
+            return PktStatus::Received;
         }
 
         // Extend our range at the right if necessary
@@ -168,24 +265,27 @@ impl TrackRxPackets {
         }
 
         // If this was a received packet, mark dupacks
-        if received == PktStatus::Received && prev_status != PktStatus::Received {
+        let dupack_thresh = self.dupack_thresh.unwrap_or(0);
+        if self.dupack_thresh.is_some()
+            && received == PktStatus::Received
+            && prev_status != PktStatus::Received
+        {
             for id in 0..pkt_id {
                 self.status[id] = match self.status[id] {
                     PktStatus::Received => PktStatus::Received,
                     PktStatus::NotReceived(i) => {
-                        if i == 2 {
+                        if i + 1 >= dupack_thresh {
                             self.num_unreported_lost += 1;
                             self.num_lost += 1;
                             PktStatus::Lost
                         } else {
-                            assert!(i < 2);
                             PktStatus::NotReceived(i + 1)
                         }
                     }
                     PktStatus::Lost => PktStatus::Lost,
                     PktStatus::Retransmitted(i, rtx_seq) => {
                         if seq_num > rtx_seq {
-                            if i == 2 {
+                            if i + 1 >= dupack_thresh {
                                 self.num_unreported_lost += 1;
                                 self.num_lost += 1;
                                 PktStatus::Lost
@@ -207,6 +307,20 @@ impl TrackRxPackets {
         }
 
         assert!(self.range.1 >= self.range.0);
+        prev_status
+    }
+
+    /// Mark a packet that hasn't been received as lost, e.g. when RACK or a tail loss probe
+    /// decides it is. Does nothing if the packet was received or is already marked lost
+    fn mark_lost(&mut self, seq_num: SeqNum) {
+        match self.get_pkt_status(seq_num) {
+            PktStatus::Received | PktStatus::Lost => {}
+            PktStatus::NotReceived(_) | PktStatus::Retransmitted(_, _) => {
+                self.status[(seq_num - self.range.0) as usize] = PktStatus::Lost;
+                self.num_lost += 1;
+                self.num_unreported_lost += 1;
+            }
+        }
     }
 
     /// Any non-received packets are marked as lost
@@ -249,10 +363,21 @@ impl TrackRxPackets {
         self.range.0
     }
 
+    /// The largest sequence number that hasn't been received, if any
+    fn highest_unreceived(&self) -> Option<SeqNum> {
+        self.status
+            .iter()
+            .rposition(|x| *x != PktStatus::Received)
+            .map(|i| self.range.0 + i as u64)
+    }
+
     /// Get the status of the packet with the given sequence number
     fn get_pkt_status(&self, seq_num: SeqNum) -> PktStatus {
         if self.range.0 > seq_num {
             PktStatus::Received
+        } else if seq_num >= self.range.1 {
+            // We haven't heard of it yet
+            PktStatus::NotReceived(0)
         } else {
             self.status[(seq_num - self.range.0) as usize]
         }
@@ -310,49 +435,52 @@ impl TrackRxPackets {
 }
 
 /// Helper struct to calculate retransmission timeout (RTO), in *rough* compliance with RFC 6298
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct TcpRto {
-    /// Smoothed RTT (for computing timeout)
-    srtt: Time,
+    /// Smoothed RTT (for computing timeout). `None` until the first RTT sample
+    srtt: Option<Time>,
     /// Variation of RTT (for computing timeout)
     rttvar: Time,
     /// Multiplier for backoff
     backoff: u64,
+    /// Limits on the RTO, the RTO before any RTT sample and the backoff
+    config: RtoConfig,
 }
 
 impl TcpRto {
-    fn new() -> Self {
+    fn new(config: RtoConfig) -> Self {
         Self {
-            srtt: Time::from_secs(1),
+            srtt: None,
             rttvar: Time::from_secs(0),
             backoff: 1,
+            config,
         }
     }
 
     /// Must be called whenever a new RTT estimate is available. This is needed to calculate
     /// RTO
     fn report_rtt(&mut self, rtt: Time) {
-        // Update srtt and rttvar
-        self.rttvar = Time::from_micros(
-            ((1. - 1. / 4.) * self.rttvar.micros() as f64
-                + 1. / 4. * (self.srtt.micros() as f64 - rtt.micros() as f64).abs())
-                as u64,
-        );
-        self.srtt = if self.srtt == Time::from_secs(1) {
+        self.srtt = Some(if let Some(srtt) = self.srtt {
+            // Update srtt and rttvar
+            self.rttvar = Time::from_micros(
+                ((1. - 1. / 4.) * self.rttvar.micros() as f64
+                    + 1. / 4. * (srtt.micros() as f64 - rtt.micros() as f64).abs())
+                    as u64,
+            );
+            Time::from_micros(
+                ((1. - 1. / 8.) * srtt.micros() as f64 + rtt.micros() as f64 / 8.) as u64,
+            )
+        } else {
             // First measurement
             // The RFC asks us to set rttvar thusly
             self.rttvar = Time::from_micros(rtt.micros() / 2);
             rtt
-        } else {
-            Time::from_micros(
-                ((1. - 1. / 8.) * self.srtt.micros() as f64 + rtt.micros() as f64 / 8.) as u64,
-            )
-        };
+        });
     }
 
     /// Must be called when a timeout is triggered, so we can do some backoff
     fn report_timeout(&mut self) {
-        self.backoff = std::cmp::min(256, 2 * self.backoff);
+        self.backoff = std::cmp::min(self.config.max_backoff, 2 * self.backoff);
     }
 
     /// Must be called when a newly transmitted packet is acked, so we may reset the backoff
@@ -362,26 +490,114 @@ impl TcpRto {
 
     /// Returns the current RTO to use
     fn rto(&self) -> Time {
-        // We are multiplying by two here, which may be different from what the RFC asks us to do
-        let rto = self.srtt + Time::from_micros(self.rttvar.micros() * 4);
-        let rto = rto.micros() * self.backoff;
-        // Cap the RTO at 60 seconds
-        let rto = std::cmp::min(60_000_000, rto);
-        // RTO should be at least 1 second
-        let rto = std::cmp::max(1_000_000, rto);
+        let rto = if let Some(srtt) = self.srtt {
+            // We are multiplying by two here, which may be different from what the RFC asks us to
+            // do
+            srtt + Time::from_micros(self.rttvar.micros() * 4)
+        } else {
+            self.config.initial
+        };
+        let rto = rto.micros().saturating_mul(self.backoff);
+        // Cap the RTO
+        let rto = std::cmp::min(self.config.max.micros(), rto);
+        // The RTO should be at least the minimum
+        let rto = std::cmp::max(self.config.min.micros(), rto);
         Time::from_micros(rto)
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Time-based loss detection with tail loss probes, in *rough* compliance with RFC 8985
+/// (RACK-TLP)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RackTlp {
+    /// Reordering window, as a fraction of the minimum RTT
+    reo_wnd_frac: f64,
+    /// Send time of the most recently sent packet that has been acked (RACK.xmit_ts)
+    xmit_ts: Time,
+    /// RTT measured from that packet (RACK.rtt)
+    rtt: Time,
+    /// Minimum RTT seen so far
+    min_rtt: Time,
+    /// Whether a tail loss probe has been sent that hasn't been followed by an ack yet
+    probe_outstanding: bool,
+}
+
+impl RackTlp {
+    fn new(reo_wnd_frac: f64) -> Self {
+        Self {
+            reo_wnd_frac,
+            xmit_ts: Time::ZERO,
+            rtt: Time::ZERO,
+            min_rtt: Time::MAX,
+            probe_outstanding: false,
+        }
+    }
+
+    /// Must be called on every ack with the time when the packet being acked was sent. Since
+    /// every packet has a unique id, this is unambiguous even for retransmissions
+    fn on_ack(&mut self, now: Time, sent_time: Time) {
+        let rtt = now - sent_time;
+        if sent_time >= self.xmit_ts {
+            self.xmit_ts = sent_time;
+            self.rtt = rtt;
+        }
+        self.min_rtt = std::cmp::min(self.min_rtt, rtt);
+        self.probe_outstanding = false;
+    }
+
+    /// Mark packets that were sent sufficiently earlier than the most recently acked packet as
+    /// lost. `tx_times` has the latest transmission time of every packet that hasn't been acked.
+    /// If some packets may be declared lost later, returns when the reordering timer should expire
+    fn detect_loss(
+        &mut self,
+        now: Time,
+        track_rx: &mut TrackRxPackets,
+        tx_times: &BTreeMap<SeqNum, Time>,
+    ) -> Option<Time> {
+        let reo_wnd = Time::from_micros((self.min_rtt.micros() as f64 * self.reo_wnd_frac) as u64);
+        let mut timeout = None;
+        for (seq_num, tx_time) in tx_times {
+            match track_rx.get_pkt_status(*seq_num) {
+                PktStatus::Received | PktStatus::Lost => continue,
+                PktStatus::NotReceived(_) | PktStatus::Retransmitted(_, _) => {}
+            }
+            if *tx_time >= self.xmit_ts {
+                // Sent after the most recently acked packet. No information on this one yet
+                continue;
+            }
+            let deadline = *tx_time + self.rtt + reo_wnd;
+            if deadline <= now {
+                track_rx.mark_lost(*seq_num);
+            } else {
+                timeout = Some(std::cmp::max(timeout.unwrap_or(Time::ZERO), deadline));
+            }
+        }
+        timeout
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 enum TcpSenderEvent {
     Transmit,
-    /// A timeout event is set for each sent packet and on each ack arrival. It includes a time. If
-    /// no packet has been acked after that time, timeout should be triggered
-    Timeout(Time),
+    /// The retransmission timer expired
+    Timeout,
+    /// RACK's reordering timer expired
+    ReorderTimeout,
+    /// Time to send a tail loss probe
+    ProbeTimeout,
+    /// The application asked to be woken up
+    AppWakeup,
+}
+
+/// What stopped a sender from transmitting when it had data to send
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum SendLimit {
+    Cwnd,
+    Rwnd,
 }
 
 /// A sender which sends a given amount of data using congestion control
+#[derive(Clone)]
 pub struct TcpSender<'a, C: CongestionControl + 'static> {
     /// The hop on which to send packets
     next: NetObjId,
@@ -395,17 +611,35 @@ pub struct TcpSender<'a, C: CongestionControl + 'static> {
     next_pkt: SeqNum,
     /// Track which sent packets have been acked
     track_rx: TrackRxPackets,
+    /// The latest transmission time of every packet that hasn't been acked yet
+    tx_times: BTreeMap<SeqNum, Time>,
+    /// Time-based loss detection, if enabled. Else, we rely on dupacks
+    rack: Option<RackTlp>,
     /// Last time we transmitted a packet
     last_tx_time: Time,
-    /// Time when the last ack was received. Used for deciding when a scheduled timeout was valid
-    last_ack_time: Time,
     /// Whether a transmission is currently scheduled
     tx_scheduled: bool,
+    /// If the sender was unable to transmit the last time it tried, what limited it and the time
+    /// up to which that has been logged
+    limited: Option<(SendLimit, Time)>,
+    /// Right edge of the receive window advertised by the receiver. We may only send fresh
+    /// packets with sequence numbers less than this. Until the first ack arrives, it is the size
+    /// of the receiver's buffer
+    rwnd_edge: SeqNum,
     rto: TcpRto,
     /// Time when the flow should start and end
     start_time: Time,
     /// How much should it transmit
     tx_length: TcpSenderTxLength,
+    /// The application generating data, if any
+    app: Option<Box<dyn TcpApp + 'a>>,
+    /// Retransmission timer. Restarted on every ack and stopped when nothing is in flight
+    rto_timer: Option<TimerHandle>,
+    /// RACK's reordering timer and the tail loss probe timer
+    reo_timer: Option<TimerHandle>,
+    pto_timer: Option<TimerHandle>,
+    /// Wakeup the application asked for
+    app_timer: Option<TimerHandle>,
     /// Map event uids to events
     event_uid_map: EventUidMap<TcpSenderEvent>,
     /// Tracer for events and measurements
@@ -416,7 +650,11 @@ pub struct TcpSender<'a, C: CongestionControl + 'static> {
 impl<'a, C: CongestionControl + 'static> TcpSender<'a, C> {
     /// `next` is the next hop to which packets should be forwarded. `dest` is the destination the
     /// packet should be sent to.  `addr` is our (the sender's) address `start_time` is the time at
-    /// which the flow should start. `tx_length` is the duration/size of the flow
+    /// which the flow should start. `tx_length` is the duration/size of the flow.
+    /// `loss_detection` decides how lost packets are detected (in addition to timeouts) and
+    /// `rto_config` how the retransmission timeout is computed. If `app` is given, it decides how
+    /// much data is available to send. `rcv_bufsize` is that of the receiver (in packets), which
+    /// bounds how much we send before hearing from it
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         next: NetObjId,
@@ -425,22 +663,38 @@ impl<'a, C: CongestionControl + 'static> TcpSender<'a, C> {
         cc: C,
         start_time: Time,
         tx_length: TcpSenderTxLength,
+        loss_detection: LossDetectionConfig,
+        rto_config: RtoConfig,
+        app: Option<Box<dyn TcpApp + 'a>>,
+        rcv_bufsize: BufferSize,
         tracer: &'a Tracer,
         config: &'a Config,
     ) -> Self {
+        let (dupack_thresh, rack) = match loss_detection {
+            LossDetectionConfig::DupAck(thresh) => (Some(thresh), None),
+            LossDetectionConfig::RackTlp { reo_wnd } => (None, Some(RackTlp::new(reo_wnd))),
+        };
         Self {
             next,
             addr,
             dest,
             cc,
             next_pkt: 0,
-            track_rx: TrackRxPackets::new(),
+            track_rx: TrackRxPackets::new(dupack_thresh),
+            tx_times: BTreeMap::new(),
+            rack,
             last_tx_time: Time::from_micros(0),
-            last_ack_time: Time::from_micros(0),
             tx_scheduled: true,
-            rto: TcpRto::new(),
+            limited: None,
+            rwnd_edge: initial_rwnd(rcv_bufsize),
+            rto: TcpRto::new(rto_config),
             start_time,
             tx_length,
+            app,
+            rto_timer: None,
+            reo_timer: None,
+            pto_timer: None,
+            app_timer: None,
             event_uid_map: EventUidMap::new(),
             tracer,
             config,
@@ -467,8 +721,29 @@ impl<'a, C: CongestionControl + 'static> TcpSender<'a, C> {
         }
     }
 
-    /// Transmit a packet now by returning an event that pushes a packet
-    fn tx_packet(&mut self, _obj_id: NetObjId, now: Time) -> Vec<(Time, NetObjId, Action)> {
+    /// Whether the application has handed us data we haven't sent yet
+    fn app_has_data(&self) -> bool {
+        match &self.app {
+            Some(app) => self.next_pkt < app.send_limit(),
+            None => true,
+        }
+    }
+
+    /// Schedule a wakeup for the application if it asked for one
+    fn schedule_app_wakeup(&mut self, obj_id: NetObjId, wakeup: Option<Time>, out: &mut Actions) {
+        if let Some(wakeup) = wakeup {
+            self.event_uid_map.set_timer(
+                &mut self.app_timer,
+                wakeup,
+                obj_id,
+                TcpSenderEvent::AppWakeup,
+                out,
+            );
+        }
+    }
+
+    /// Transmit a packet now by scheduling an action that pushes a packet
+    fn tx_packet(&mut self, now: Time, out: &mut Actions) {
         // Which packet should we transmit next?
         let seq_num = if let Some(seq_num) = self.track_rx.lost_packets().1 {
             // Retransmit
@@ -482,9 +757,15 @@ impl<'a, C: CongestionControl + 'static> TcpSender<'a, C> {
             self.next_pkt += 1;
             self.next_pkt - 1
         };
+        self.tx_seq(seq_num, now, out)
+    }
 
+    /// Transmit the packet with the given sequence number now. Its status must already have been
+    /// marked in `track_rx`
+    fn tx_seq(&mut self, seq_num: SeqNum, now: Time, out: &mut Actions) {
+        self.tx_times.insert(seq_num, now);
         let pkt = Packet {
-            uid: PktId::next(),
+            uid: out.new_pkt_id(),
             sent_time: now,
             size: self.config.pkt_size,
             dest: self.dest,
@@ -492,63 +773,164 @@ impl<'a, C: CongestionControl + 'static> TcpSender<'a, C> {
             ptype: TransportHeader::Data { seq_num },
         };
         self.cc.on_send(now, seq_num, pkt.uid);
-        vec![(now, self.next, Action::Push(Rc::new(pkt)))]
+        out.push((now, self.next, Action::Push(pkt)));
     }
 
-    /// Schedule a transmission if appropriate
-    fn schedule_tx(&mut self, obj_id: NetObjId, now: Time) -> Vec<(Time, NetObjId, Action)> {
-        // Every time this is called we need to schedule a retransmission timeout. This includes
-        // whenever there is an ACK and when we respond to a timeout
-        let rto_event = (
-            now + self.rto.rto(),
-            obj_id,
-            self.event_uid_map.new_event(TcpSenderEvent::Timeout(now)),
-        );
+    /// Whether there are packets that have been sent, but not acked
+    fn in_flight(&self) -> bool {
+        self.next_pkt > self.track_rx.num_pkts_received()
+    }
 
-        // See if we should transmit packets
-        if !self.tx_scheduled && !self.sent_all(now) {
-            let cwnd = self.cc.get_cwnd();
-            if cwnd
-                > self.next_pkt - self.track_rx.num_pkts_received() - self.track_rx.lost_packets().0
-            {
-                // See if we should transmit now, or schedule an event later
-                let intersend_time = self.cc.get_intersend_time();
-                let time_to_send = self.last_tx_time + intersend_time;
-                let time_to_send = if time_to_send < now {
-                    // Transmit now
-                    now
-                } else {
-                    // Schedule a transmission for later
-                    time_to_send
-                };
-                // Update it here so the next packet gets transmitted an intersend time later
-                self.last_tx_time = time_to_send;
-                self.tx_scheduled = true;
-                let event_id = self.event_uid_map.new_event(TcpSenderEvent::Transmit);
-                vec![(time_to_send, obj_id, event_id), rto_event]
+    /// (Re-)arm the probe timeout if we are using RACK-TLP, packets are in flight and we aren't
+    /// already waiting for a probe to be acked
+    fn arm_pto(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) {
+        let in_flight = self.in_flight();
+        if let Some(rack) = &mut self.rack {
+            if in_flight && !rack.probe_outstanding {
+                // RFC 8985 asks for 2 * SRTT, but never more than the RTO
+                let rto = self.rto.rto();
+                let pto = self
+                    .rto
+                    .srtt
+                    .map_or(rto, |srtt| std::cmp::min(srtt * 2, rto));
+                self.event_uid_map.set_timer(
+                    &mut self.pto_timer,
+                    now + pto,
+                    obj_id,
+                    TcpSenderEvent::ProbeTimeout,
+                    out,
+                );
+            }
+        }
+    }
+
+    /// Start the retransmission timer if packets are in flight and it isn't running already (or
+    /// if `restart` is true). Stop it if nothing is in flight
+    fn arm_rto(&mut self, obj_id: NetObjId, now: Time, restart: bool, out: &mut Actions) {
+        if !self.in_flight() {
+            self.event_uid_map
+                .cancel_timer(&mut self.rto_timer, obj_id, out);
+        } else if restart || self.rto_timer.is_none() {
+            self.event_uid_map.set_timer(
+                &mut self.rto_timer,
+                now + self.rto.rto(),
+                obj_id,
+                TcpSenderEvent::Timeout,
+                out,
+            );
+        }
+    }
+
+    /// Run RACK loss detection (if enabled), and arm the reordering timer if needed
+    fn rack_detect_loss(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) {
+        if let Some(rack) = &mut self.rack {
+            if let Some(timeout) = rack.detect_loss(now, &mut self.track_rx, &self.tx_times) {
+                self.event_uid_map.set_timer(
+                    &mut self.reo_timer,
+                    timeout,
+                    obj_id,
+                    TcpSenderEvent::ReorderTimeout,
+                    out,
+                );
             } else {
-                vec![rto_event]
+                self.event_uid_map
+                    .cancel_timer(&mut self.reo_timer, obj_id, out);
             }
+        }
+    }
+
+    /// Log the time spent limited since we last checked, and record the new limit (if any).
+    /// Limits can only change when we try to transmit, so they are checked then
+    fn update_limit(&mut self, obj_id: NetObjId, now: Time, limit: Option<SendLimit>) {
+        if let Some((limit, since)) = self.limited {
+            let elem = match limit {
+                SendLimit::Cwnd => TraceElem::TcpSenderCwndLimited(now - since),
+                SendLimit::Rwnd => TraceElem::TcpSenderRwndLimited(now - since),
+            };
+            self.tracer.log(obj_id, now, elem);
+        }
+        self.limited = limit.map(|limit| (limit, now));
+    }
+
+    /// Schedule a transmission if appropriate
+    fn schedule_tx(&mut self, obj_id: NetObjId, now: Time, out: &mut Actions) {
+        // See if we should transmit packets
+        if self.tx_scheduled || self.sent_all(now) {
+            self.update_limit(obj_id, now, None);
+            return;
+        }
+        let cwnd = self.cc.get_cwnd();
+        let (num_lost, next_lost) = self.track_rx.lost_packets();
+        let cwnd_allows = cwnd > self.next_pkt - self.track_rx.num_pkts_received() - num_lost;
+        // Retransmissions have data to send, and the receiver accepts them even if its window is
+        // closed (see `Acker::push`)
+        let rwnd_allows = next_lost.is_some() || self.next_pkt < self.rwnd_edge;
+        let app_allows = next_lost.is_some() || self.app_has_data();
+        let limit = if !app_allows {
+            // Nothing to send. The application will give us more data when it wants to
+            None
+        } else if !cwnd_allows {
+            Some(SendLimit::Cwnd)
+        } else if !rwnd_allows {
+            Some(SendLimit::Rwnd)
         } else {
-            vec![rto_event]
+            None
+        };
+        self.update_limit(obj_id, now, limit);
+        if cwnd_allows && rwnd_allows && app_allows {
+            // See if we should transmit now, or schedule an event later
+            let intersend_time = self.cc.get_intersend_time();
+            let time_to_send = self.last_tx_time + intersend_time;
+            let time_to_send = if time_to_send < now {
+                // Transmit now
+                now
+            } else {
+                // Schedule a transmission for later
+                time_to_send
+            };
+            // Update it here so the next packet gets transmitted an intersend time later
+            self.last_tx_time = time_to_send;
+            self.tx_scheduled = true;
+            let event_id = self.event_uid_map.new_event(TcpSenderEvent::Transmit);
+            out.push((time_to_send, obj_id, event_id));
         }
     }
 }
 
-impl<'a, C: CongestionControl + 'static> NetObj for TcpSender<'a, C> {
-    fn init(
-        &mut self,
-        obj_id: NetObjId,
-        now: Time,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        // Schedule a transmission and a timeout
+impl<'a, C: CongestionControl + 'static> SaveState for TcpSender<'a, C> {
+    save_fields!(
+        next_pkt,
+        track_rx,
+        tx_times,
+        rack,
+        last_tx_time,
+        tx_scheduled,
+        limited,
+        rwnd_edge,
+        rto,
+        rto_timer,
+        reo_timer,
+        pto_timer,
+        app_timer,
+        event_uid_map;
+        cc,
+        app
+    );
+}
+
+impl<'a, C: CongestionControl + Clone + 'static> NetObj for TcpSender<'a, C> {
+    fn init(&mut self, obj_id: NetObjId, _now: Time, out: &mut Actions) -> Result<(), Error> {
+        // If there is an application, it decides when to start sending
+        if self.app.is_some() {
+            self.tx_scheduled = false;
+            self.schedule_app_wakeup(obj_id, Some(self.start_time), out);
+            return Ok(());
+        }
+        // Schedule a transmission. The retransmission timer starts when it happens
         self.tx_scheduled = true;
         let tx_event = self.event_uid_map.new_event(TcpSenderEvent::Transmit);
-        let rto_event = self.event_uid_map.new_event(TcpSenderEvent::Timeout(now));
-        Ok(vec![
-            (self.start_time, obj_id, tx_event),
-            (self.start_time + self.rto.rto(), obj_id, rto_event),
-        ])
+        out.push((self.start_time, obj_id, tx_event));
+        Ok(())
     }
 
     fn push(
@@ -556,33 +938,62 @@ impl<'a, C: CongestionControl + 'static> NetObj for TcpSender<'a, C> {
         obj_id: NetObjId,
         _from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
         assert_eq!(pkt.dest, self.addr);
+        // The receiver's window opened up. See if we can transmit more
+        if let TransportHeader::WindowUpdate { cum_ack, rwnd } = pkt.ptype {
+            if self.has_ended(now) {
+                return Ok(());
+            }
+            self.rwnd_edge = cum_ack.saturating_add(rwnd);
+            self.schedule_tx(obj_id, now, out);
+            return Ok(());
+        }
         // Must be an ack. Check this
         if let TransportHeader::Ack {
             sent_time,
             cum_ack,
             sack,
             ack_uid,
+            ack_seq,
+            rwnd,
         } = &pkt.ptype
         {
             assert!(self.next_pkt >= self.track_rx.received_till());
             assert!(*cum_ack <= self.next_pkt);
             if self.has_ended(now) {
-                return Ok(Vec::new());
+                return Ok(());
             }
 
-            self.last_ack_time = now;
+            self.rwnd_edge = cum_ack.saturating_add(*rwnd);
 
-            // Mark all cumulatively acked packets are received
+            // If the packet being acked was retransmitted, but this ack is for an earlier
+            // transmission, the retransmission was spurious
+            let mut num_spurious = 0;
+            let ack_retransmitted =
+                if let PktStatus::Retransmitted(_, _) = self.track_rx.get_pkt_status(*ack_seq) {
+                    if *sent_time < self.tx_times[ack_seq] {
+                        num_spurious += 1;
+                    }
+                    true
+                } else {
+                    false
+                };
+
+            // Mark all cumulatively acked packets are received. Packets that were marked lost, but
+            // hadn't been retransmitted yet were spuriously marked lost
             let received_till = self.track_rx.received_till();
             for i in received_till..*cum_ack {
                 // received_till may have been updated, e.g. if a retransmitted packet was acked
                 if i < self.track_rx.received_till() {
                     continue;
                 }
-                self.track_rx.mark_pkt(i, PktStatus::Received);
+                if self.track_rx.mark_pkt(i, PktStatus::Received) == PktStatus::Lost {
+                    num_spurious += 1;
+                }
+                self.tx_times.remove(&i);
             }
             // Process the SACK blocks and mark all sacked packets as received
             for (left, right) in sack {
@@ -592,20 +1003,40 @@ impl<'a, C: CongestionControl + 'static> NetObj for TcpSender<'a, C> {
                     if i < self.track_rx.received_till() {
                         continue;
                     }
-                    self.track_rx.mark_pkt(i, PktStatus::Received);
+                    if self.track_rx.mark_pkt(i, PktStatus::Received) == PktStatus::Lost {
+                        num_spurious += 1;
+                    }
+                    self.tx_times.remove(&i);
                 }
             }
 
+            // Time-based loss detection
+            if let Some(rack) = &mut self.rack {
+                rack.on_ack(now, *sent_time);
+            }
+            self.rack_detect_loss(obj_id, now, out);
+            self.arm_pto(obj_id, now, out);
+            self.arm_rto(obj_id, now, true, out);
+
+            // Tell the application what was delivered
+            let cum_acked = self.track_rx.received_till();
+            let wakeup = self
+                .app
+                .as_mut()
+                .and_then(|app| app.on_delivered(obj_id, now, cum_acked));
+            self.schedule_app_wakeup(obj_id, wakeup, out);
+
             let rtt = now - *sent_time;
             let num_lost = self.track_rx.num_unreported_lost();
 
-            // NOTE: TCP can only estimate RTT from non-retransmitted segments, whereas QUIC can
-            // estimate it from all segments. Add a condition here to emulate standard TCP. This is
-            // currently QUIC-like, where every packet has a unique id
-            self.rto.report_rtt(rtt);
-            // Our estimate of when a new packet is acked
-            if PktStatus::NotReceived(0) == self.track_rx.get_pkt_status(*cum_ack - 1) {
-                self.rto.report_fresh_ack();
+            // TCP can only estimate RTT from non-retransmitted segments (Karn's algorithm), since
+            // it can't tell which transmission is being acked. QUIC can estimate it from all
+            // packets, since every packet has a unique id
+            if !(self.rto.config.karn && ack_retransmitted) {
+                self.rto.report_rtt(rtt);
+                if self.rto.config.reset_backoff {
+                    self.rto.report_fresh_ack();
+                }
             }
 
             self.tracer.log(
@@ -618,18 +1049,23 @@ impl<'a, C: CongestionControl + 'static> NetObj for TcpSender<'a, C> {
             self.tracer.log(obj_id, now, TraceElem::TcpSenderRtt(rtt));
             self.tracer
                 .log(obj_id, now, TraceElem::TcpSenderLoss(num_lost));
+            if num_spurious > 0 {
+                self.tracer
+                    .log(obj_id, now, TraceElem::TcpSenderSpuriousRtx(num_spurious));
+                self.cc.on_spurious_retransmit(now, num_spurious);
+            }
 
             if num_lost > 0 {
                 // If we've detected a loss, we should schedule a retransmission before the CC
                 // reduces its cwnd. This emulates a fast retransmit
-                let res = self.schedule_tx(obj_id, now);
+                self.schedule_tx(obj_id, now, out);
                 self.cc.on_ack(now, *cum_ack, *ack_uid, rtt, num_lost);
-                Ok(res)
             } else {
                 // This is business as usual
                 self.cc.on_ack(now, *cum_ack, *ack_uid, rtt, num_lost);
-                Ok(self.schedule_tx(obj_id, now))
+                self.schedule_tx(obj_id, now, out);
             }
+            Ok(())
         } else {
             unreachable!()
         }
@@ -641,10 +1077,11 @@ impl<'a, C: CongestionControl + 'static> NetObj for TcpSender<'a, C> {
         from: NetObjId,
         now: Time,
         uid: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        out: &mut Actions,
+    ) -> Result<(), Error> {
         assert_eq!(obj_id, from);
         if self.has_ended(now) {
-            return Ok(Vec::new());
+            return Ok(());
         }
 
         let event = self.event_uid_map.retrieve(uid).unwrap();
@@ -653,102 +1090,439 @@ impl<'a, C: CongestionControl + 'static> NetObj for TcpSender<'a, C> {
             TcpSenderEvent::Transmit => {
                 // A transmission was scheduled. So transmit the packet
                 self.tx_scheduled = false;
-                let mut res = self.tx_packet(obj_id, now);
-                res.append(&mut self.schedule_tx(obj_id, now));
-                Ok(res)
+                self.tx_packet(now, out);
+                self.schedule_tx(obj_id, now, out);
+                self.arm_pto(obj_id, now, out);
+                self.arm_rto(obj_id, now, false, out);
             }
-            TcpSenderEvent::Timeout(start_time) => {
-                if self.last_ack_time <= start_time {
-                    // Mark all inflight packets as lost
-                    self.track_rx.mark_all_as_lost();
-                    self.rto.report_timeout();
-
-                    // It was a timeout
-                    self.cc.on_timeout();
-
-                    // Trace new cwnd and timeout event
-                    self.tracer
-                        .log(obj_id, now, TraceElem::TcpSenderCwnd(self.cc.get_cwnd()));
-                    self.tracer.log(obj_id, now, TraceElem::TcpSenderTimeout);
-
-                    let res = self.schedule_tx(obj_id, now);
-                    Ok(res)
+            TcpSenderEvent::ReorderTimeout => {
+                self.reo_timer = None;
+                self.rack_detect_loss(obj_id, now, out);
+                self.schedule_tx(obj_id, now, out);
+            }
+            TcpSenderEvent::ProbeTimeout => {
+                self.pto_timer = None;
+                if !self.in_flight() {
+                    return Ok(());
+                }
+                self.rack.as_mut().unwrap().probe_outstanding = true;
+
+                // Send new data if we can. Else retransmit the last unacked packet
+                let can_send_new = !self.sent_all(now)
+                    && self.app_has_data()
+                    && self.next_pkt < self.rwnd_edge
+                    && self.track_rx.lost_packets().1.is_none();
+                if can_send_new {
+                    self.tx_packet(now, out);
                 } else {
-                    // There was an ack since then. All is well
-                    Ok(Vec::new())
+                    let seq_num = self.track_rx.highest_unreceived().unwrap();
+                    self.track_rx
+                        .mark_pkt(seq_num, PktStatus::Retransmitted(0, self.next_pkt));
+                    self.tx_seq(seq_num, now, out);
                 }
+                self.tracer
+                    .log(obj_id, now, TraceElem::TcpSenderTailLossProbe);
+                self.schedule_tx(obj_id, now, out);
+                self.arm_rto(obj_id, now, false, out);
+            }
+            TcpSenderEvent::AppWakeup => {
+                self.app_timer = None;
+                let wakeup = self.app.as_mut().unwrap().on_wakeup(obj_id, now);
+                // The application may have given us more data
+                self.schedule_app_wakeup(obj_id, wakeup, out);
+                self.schedule_tx(obj_id, now, out);
+            }
+            TcpSenderEvent::Timeout => {
+                // The timer is stopped whenever an ack arrives, so this is a real timeout
+                self.rto_timer = None;
+                // Mark all inflight packets as lost
+                self.track_rx.mark_all_as_lost();
+                self.rto.report_timeout();
+
+                // It was a timeout
+                self.cc.on_timeout();
+
+                // Trace new cwnd and timeout event
+                self.tracer
+                    .log(obj_id, now, TraceElem::TcpSenderCwnd(self.cc.get_cwnd()));
+                self.tracer.log(obj_id, now, TraceElem::TcpSenderTimeout);
+
+                // Restart the timer with the backed-off RTO
+                self.schedule_tx(obj_id, now, out);
+                self.arm_rto(obj_id, now, true, out);
             }
         }
+        Ok(())
+    }
+
+    fn state(&mut self) -> serde_json::Value {
+        serde_json::json!({
+            "next_pkt": self.next_pkt,
+            "cum_ack": self.track_rx.received_till(),
+            "num_received": self.track_rx.num_pkts_received(),
+            "cwnd": self.cc.get_cwnd(),
+            "intersend_time": self.cc.get_intersend_time(),
+            "srtt": self.rto.srtt,
+            "rto": self.rto.rto(),
+            "rwnd_edge": self.rwnd_edge,
+        })
+    }
+}
+
+/// Receive window (in packets) before the receiver has advertised one: its whole buffer
+fn initial_rwnd(bufsize: BufferSize) -> SeqNum {
+    match bufsize {
+        BufferSize::Finite(size) => size as SeqNum,
+        BufferSize::Infinite => SeqNum::MAX,
     }
 }
 
-/// Acks every packet it receives to the sender via the given next-hop
-pub struct Acker {
+/// Acks every packet it receives to the sender via the given next-hop. Also models the
+/// receiver's buffer: packets that arrived out-of-order, or that the application hasn't read yet,
+/// occupy the buffer. The free space is advertised to the sender as the receive window
+#[derive(Clone)]
+pub struct Acker<'a> {
     /// The next hop over which to send all acks
     next: NetObjId,
     /// The address of this acker
     addr: Addr,
     /// Track packets so we can generate cumulative acks and SACKs
     track_rx: TrackRxPackets,
+    /// Size of the receive buffer (in packets)
+    bufsize: BufferSize,
+    /// Rate (in bytes per second) at which the application reads in-order data from the buffer.
+    /// If `None`, data is read as soon as it is available
+    app_read_rate: Option<f64>,
+    /// Number of in-order packets that the application hasn't read yet
+    num_unread: u64,
+    /// Whether an application read is currently scheduled
+    read_scheduled: bool,
+    /// The address of the sender, learnt from the data packets. Used to send window updates
+    peer: Option<Addr>,
+    /// The right edge of the window (`cum_ack + rwnd`) we last advertised
+    last_adv_edge: SeqNum,
+    config: &'a Config,
 }
 
-impl Acker {
-    pub fn new(addr: Addr, next: NetObjId) -> Self {
+impl<'a> Acker<'a> {
+    /// `bufsize` is the size of the receive buffer (in packets). `app_read_rate` is the rate (in
+    /// bytes per second) at which the application reads data. If `None`, the application reads
+    /// data instantly
+    pub fn new(
+        addr: Addr,
+        next: NetObjId,
+        bufsize: BufferSize,
+        app_read_rate: Option<f64>,
+        config: &'a Config,
+    ) -> Self {
         Self {
             next,
             addr,
-            track_rx: TrackRxPackets::new(),
+            track_rx: TrackRxPackets::new(None),
+            bufsize,
+            app_read_rate,
+            num_unread: 0,
+            read_scheduled: false,
+            peer: None,
+            last_adv_edge: initial_rwnd(bufsize),
+            config,
+        }
+    }
+
+    /// Number of packets currently occupying the receive buffer
+    fn buffer_occupancy(&self) -> u64 {
+        let out_of_order = self.track_rx.num_pkts_received() - self.track_rx.received_till();
+        self.num_unread + out_of_order
+    }
+
+    /// The receive window to advertise (in packets)
+    fn rwnd(&self) -> u64 {
+        match self.bufsize {
+            BufferSize::Finite(size) => (size as u64).saturating_sub(self.buffer_occupancy()),
+            BufferSize::Infinite => u64::MAX,
         }
     }
+
+    /// Time the application takes to read one packet from the buffer
+    fn read_time(&self, rate: f64) -> Time {
+        Time::from_micros((1e6 * self.config.pkt_size as f64 / rate) as u64)
+    }
+}
+
+impl<'a> SaveState for Acker<'a> {
+    save_fields!(track_rx, num_unread, read_scheduled, peer, last_adv_edge);
 }
 
-impl NetObj for Acker {
-    fn init(&mut self, _: NetObjId, _: Time) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        Ok(Vec::new())
+impl<'a> NetObj for Acker<'a> {
+    fn init(&mut self, _: NetObjId, _: Time, _: &mut Actions) -> Result<(), Error> {
+        Ok(())
     }
 
     fn push(
         &mut self,
-        _obj_id: NetObjId,
+        obj_id: NetObjId,
         _from: NetObjId,
         now: Time,
-        pkt: Rc<Packet>,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
+        pkt: Packet,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
         // Make sure this is the intended recipient
         assert_eq!(self.addr, pkt.dest);
+        self.peer = Some(pkt.src);
 
         // Ensure this is a data packet
-        let ack = if let TransportHeader::Data { seq_num } = pkt.ptype {
-            // Track the received packets
-            self.track_rx.mark_pkt(seq_num, PktStatus::Received);
-
-            Packet {
-                uid: PktId::next(),
-                sent_time: now,
-                size: 40,
-                dest: pkt.src,
-                src: self.addr,
-                ptype: TransportHeader::Ack {
-                    sent_time: pkt.sent_time,
-                    ack_uid: pkt.uid,
-                    cum_ack: self.track_rx.received_till(),
-                    sack: self.track_rx.generate_sack(3),
-                },
-            }
+        let seq_num = if let TransportHeader::Data { seq_num } = pkt.ptype {
+            seq_num
         } else {
             unreachable!();
         };
 
-        Ok(vec![(now, self.next, Action::Push(Rc::new(ack)))])
+        // Drop new packets that don't fit in the buffer. A well-behaved sender never sends them.
+        // We always accept the packet we are waiting for, which may be a retransmission that fills
+        // a hole, and those within the window we advertised
+        if self.rwnd() == 0
+            && seq_num > self.track_rx.received_till()
+            && seq_num >= self.last_adv_edge
+            && self.track_rx.get_pkt_status(seq_num) != PktStatus::Received
+        {
+            return Ok(());
+        }
+
+        // Track the received packets
+        let received_till = self.track_rx.received_till();
+        self.track_rx.mark_pkt(seq_num, PktStatus::Received);
+
+        // Hand over newly in-order packets to the application
+        if let Some(rate) = self.app_read_rate {
+            self.num_unread += self.track_rx.received_till() - received_till;
+            if !self.read_scheduled && self.num_unread > 0 {
+                self.read_scheduled = true;
+                out.push((now + self.read_time(rate), obj_id, Action::Event(0)));
+            }
+        }
+
+        let cum_ack = self.track_rx.received_till();
+        let rwnd = self.rwnd();
+        self.last_adv_edge = cum_ack.saturating_add(rwnd);
+        let ack = Packet {
+            uid: out.new_pkt_id(),
+            sent_time: now,
+            size: 40,
+            dest: pkt.src,
+            src: self.addr,
+            ptype: TransportHeader::Ack {
+                sent_time: pkt.sent_time,
+                ack_uid: pkt.uid,
+                ack_seq: seq_num,
+                cum_ack,
+                sack: self.track_rx.generate_sack(3),
+                rwnd,
+            },
+        };
+
+        out.push((now, self.next, Action::Push(ack)));
+        Ok(())
     }
 
     fn event(
         &mut self,
-        _obj_id: NetObjId,
+        obj_id: NetObjId,
         _from: NetObjId,
-        _now: Time,
-        _uid: u64,
-    ) -> Result<Vec<(Time, NetObjId, Action)>, Error> {
-        unreachable!()
+        now: Time,
+        uid: u64,
+        out: &mut Actions,
+    ) -> Result<(), Error> {
+        assert_eq!(uid, 0);
+        // The application read a packet
+        let rate = self.app_read_rate.unwrap();
+        assert!(self.num_unread > 0);
+        self.num_unread -= 1;
+
+        if self.num_unread > 0 {
+            out.push((now + self.read_time(rate), obj_id, Action::Event(0)));
+        } else {
+            self.read_scheduled = false;
+        }
+
+        // Send a window update if the window opened up substantially since we last advertised it
+        // (receiver-side silly window syndrome avoidance, as in RFC 1122)
+        if let BufferSize::Finite(size) = self.bufsize {
+            let cum_ack = self.track_rx.received_till();
+            let rwnd = self.rwnd();
+            let edge = cum_ack.saturating_add(rwnd);
+            let threshold = std::cmp::max(1, size as u64 / 2);
+            if edge >= self.last_adv_edge + threshold {
+                self.last_adv_edge = edge;
+                let update = Packet {
+                    uid: out.new_pkt_id(),
+                    sent_time: now,
+                    size: 40,
+                    dest: self.peer.unwrap(),
+                    src: self.addr,
+                    ptype: TransportHeader::WindowUpdate { cum_ack, rwnd },
+                };
+                out.push((now, self.next, Action::Push(update)));
+            }
+        }
+        Ok(())
+    }
+
+    fn state(&mut self) -> serde_json::Value {
+        serde_json::json!({
+            "cum_ack": self.track_rx.received_till(),
+            "num_received": self.track_rx.num_pkts_received(),
+            "buffer_occupancy": self.buffer_occupancy(),
+            "rwnd": self.rwnd(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cc;
+
+    fn config() -> Config {
+        serde_yaml::from_str(
+            "pkt_size: 1500
+sim_dur: null
+random_seed: 0
+log:
+  out_terminal: png
+  out_file: out.png
+  cwnd: Ignore
+  rtt: Ignore
+  sender_losses: Ignore
+  timeouts: Ignore
+  link_rates: Ignore
+  stats_intervals: []
+  stats_file: null
+  link_bucket_size: 1s
+topo:
+  link:
+    Const: 1500000
+  bufsize: Infinite
+",
+        )
+        .unwrap()
+    }
+
+    fn data(seq_num: SeqNum, src: Addr, dest: Addr, out: &mut Actions) -> Packet {
+        Packet {
+            uid: out.new_pkt_id(),
+            sent_time: Time::ZERO,
+            size: 1500,
+            dest,
+            src,
+            ptype: TransportHeader::Data { seq_num },
+        }
+    }
+
+    /// Delays packets by 10ms, and drops the first transmission of packet 0
+    #[derive(Clone)]
+    struct DropHead {
+        to: NetObjId,
+        dropped: bool,
+    }
+
+    impl SaveState for DropHead {}
+
+    impl NetObj for DropHead {
+        fn init(&mut self, _: NetObjId, _: Time, _: &mut Actions) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn push(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            now: Time,
+            pkt: Packet,
+            out: &mut Actions,
+        ) -> Result<(), Error> {
+            if matches!(pkt.ptype, TransportHeader::Data { seq_num: 0 }) && !self.dropped {
+                self.dropped = true;
+                return Ok(());
+            }
+            out.push((now + Time::from_millis(10), self.to, Action::Push(pkt)));
+            Ok(())
+        }
+
+        fn event(
+            &mut self,
+            _: NetObjId,
+            _: NetObjId,
+            _: Time,
+            _: u64,
+            _: &mut Actions,
+        ) -> Result<(), Error> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn recovers_lost_head_with_small_receive_buffer() {
+        // The congestion window is larger than the receive buffer. If the sender ignored the
+        // buffer before the first ack, it would fill it with packets after the lost one, leaving
+        // no room for the retransmission
+        let config = config();
+        let tracer = Tracer::new(&config);
+        let mut sched = Scheduler::default();
+        let (sender_addr, acker_addr) = (sched.next_addr(), sched.next_addr());
+        let rcv_bufsize = BufferSize::Finite(4);
+        sched.register_obj(Box::new(TcpSender::new(
+            1,
+            sender_addr,
+            acker_addr,
+            cc::Const::new(10, Time::ZERO),
+            Time::ZERO,
+            TcpSenderTxLength::Bytes(20 * 1500),
+            Default::default(),
+            Default::default(),
+            None,
+            rcv_bufsize,
+            &tracer,
+            &config,
+        )));
+        sched.register_obj(Box::new(DropHead {
+            to: 2,
+            dropped: false,
+        }));
+        let acker = Acker::new(acker_addr, 0, rcv_bufsize, None, &config);
+        sched.register_obj(Box::new(acker));
+        sched.simulate(Some(Time::from_secs(10))).unwrap();
+        assert_eq!(sched.obj_state(2).unwrap()["cum_ack"], 20);
+    }
+
+    #[test]
+    fn full_receiver_accepts_packet_it_waits_for() {
+        let config = config();
+        let mut sched = Scheduler::default();
+        let (src, dest) = (sched.next_addr(), sched.next_addr());
+        let mut acker = Acker::new(dest, 0, BufferSize::Finite(4), None, &config);
+        let mut out = Actions::default();
+        for seq_num in 1..=4 {
+            acker
+                .push(
+                    1,
+                    0,
+                    Time::ZERO,
+                    data(seq_num, src, dest, &mut out),
+                    &mut out,
+                )
+                .unwrap();
+        }
+        assert_eq!(acker.rwnd(), 0);
+        // Beyond the window: dropped
+        acker
+            .push(1, 0, Time::ZERO, data(5, src, dest, &mut out), &mut out)
+            .unwrap();
+        assert_eq!(acker.track_rx.num_pkts_received(), 4);
+        // Fills the hole
+        acker
+            .push(1, 0, Time::ZERO, data(0, src, dest, &mut out), &mut out)
+            .unwrap();
+        assert_eq!(acker.track_rx.received_till(), 5);
+        assert_eq!(acker.rwnd(), 4);
     }
 }
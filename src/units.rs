@@ -0,0 +1,390 @@
+//! Human-friendly units for values in configs. Times can be given as `"100s"` or `"50ms"`, rates
+//! as `"12Mbps"` and sizes as `"1.5MB"`. Plain numbers are still accepted, and are interpreted in
+//! the base unit: microseconds, bytes per second and bytes respectively. When writing configs
+//! (see `with_units`), values are serialized in the largest unit that represents them exactly.
+//! Everywhere else, e.g. in stats and object states, they are plain numbers in the base unit
+
+use crate::simulator::Time;
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{
+    InstanceType, Metadata, NumberValidation, Schema, SchemaObject, StringValidation,
+    SubschemaValidation,
+};
+use schemars::JsonSchema;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+thread_local! {
+    /// Whether values are currently serialized with units. See `with_units`
+    static WITH_UNITS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f`, serializing times, rates and sizes with units (e.g. "50ms") while it runs. Used to
+/// write configs
+pub fn with_units<T>(f: impl FnOnce() -> T) -> T {
+    let prev = WITH_UNITS.with(|w| w.replace(true));
+    let res = f();
+    WITH_UNITS.with(|w| w.set(prev));
+    res
+}
+
+/// Split a string like "1.5 MB" into its number and unit
+fn split_unit(s: &str) -> Result<(f64, &str), String> {
+    let s = s.trim();
+    let idx = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == 'e'))
+        .unwrap_or(s.len());
+    // 'e' is also the start of units we don't know about, but that is caught below
+    let (num, unit) = s.split_at(idx);
+    let num = num
+        .parse::<f64>()
+        .map_err(|_| format!("'{}' does not start with a number", s))?;
+    if !num.is_finite() || num < 0. {
+        return Err(format!("'{}' must be a non-negative number", s));
+    }
+    Ok((num, unit.trim()))
+}
+
+/// Find `unit` in a list of (unit, multiplier) pairs
+fn multiplier(unit: &str, units: &[(&str, f64)], what: &str) -> Result<f64, String> {
+    units
+        .iter()
+        .find(|(u, _)| *u == unit)
+        .map(|(_, m)| *m)
+        .ok_or_else(|| {
+            let names: Vec<_> = units
+                .iter()
+                .map(|(u, _)| *u)
+                .filter(|u| !u.is_empty())
+                .collect();
+            format!(
+                "unknown {} unit '{}', expected one of {}",
+                what,
+                unit,
+                names.join(", ")
+            )
+        })
+}
+
+/// Write `val` (in base units) as a whole number of the largest unit that can represent it.
+/// `units` is in decreasing order of size. Returns `None` if no unit can
+fn with_unit(val: f64, units: &[(&str, f64)]) -> Option<String> {
+    units.iter().find_map(|(unit, mult)| {
+        let scaled = val / mult;
+        if scaled >= 1. && scaled.fract() == 0. && scaled * mult == val {
+            Some(format!("{}{}", scaled, unit))
+        } else {
+            None
+        }
+    })
+}
+
+const TIME_UNITS: [(&str, f64); 5] = [
+    ("h", 3_600_000_000.),
+    ("min", 60_000_000.),
+    ("s", 1_000_000.),
+    ("ms", 1_000.),
+    ("us", 1.),
+];
+
+const RATE_UNITS: [(&str, f64); 8] = [
+    ("Gbps", 1e9 / 8.),
+    ("Mbps", 1e6 / 8.),
+    ("Kbps", 1e3 / 8.),
+    ("bps", 1. / 8.),
+    ("GBps", 1e9),
+    ("MBps", 1e6),
+    ("KBps", 1e3),
+    ("Bps", 1.),
+];
+
+const SIZE_UNITS: [(&str, f64); 7] = [
+    ("GiB", 1_073_741_824.),
+    ("GB", 1e9),
+    ("MiB", 1_048_576.),
+    ("MB", 1e6),
+    ("KiB", 1024.),
+    ("KB", 1e3),
+    ("B", 1.),
+];
+
+/// Other ways of writing units, and the unit they mean
+const ALIASES: [(&str, &str); 4] = [
+    ("µs", "us"),
+    ("kbps", "Kbps"),
+    ("kBps", "KBps"),
+    ("kB", "KB"),
+];
+
+/// Parse a string with a unit from `units` (or none, meaning the base unit) into base units
+fn parse(s: &str, units: &[(&str, f64)], what: &str) -> Result<f64, String> {
+    let (num, unit) = split_unit(s)?;
+    if unit.is_empty() {
+        return Ok(num);
+    }
+    let unit = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == unit)
+        .map_or(unit, |(_, unit)| unit);
+    Ok(num * multiplier(unit, units, what)?)
+}
+
+/// Schema of a value that is either a non-negative number in the base unit, or a string with one
+/// of `units` (or their aliases)
+fn unit_schema(description: &str, units: &[(&str, f64)]) -> Schema {
+    let names: Vec<&str> = units
+        .iter()
+        .map(|(u, _)| *u)
+        .chain(
+            ALIASES
+                .iter()
+                .filter(|(_, u)| units.iter().any(|(unit, _)| unit == u))
+                .map(|(alias, _)| *alias),
+        )
+        .collect();
+    let number = SchemaObject {
+        instance_type: Some(InstanceType::Number.into()),
+        number: Some(Box::new(NumberValidation {
+            minimum: Some(0.),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    let string = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(format!(
+                r"^\s*[0-9.]+([eE][+-]?[0-9]+)?\s*({})?\s*$",
+                names.join("|")
+            )),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.to_string()),
+            ..Default::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![number.into(), string.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+impl FromStr for Time {
+    type Err = String;
+
+    /// Parses "50ms", "1.5s" etc. A number without a unit is in microseconds
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(Time::from_micros(
+            parse(s, &TIME_UNITS, "time")?.round() as u64
+        ))
+    }
+}
+
+impl Serialize for Time {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.micros() == 0 || !WITH_UNITS.with(Cell::get) {
+            return serializer.serialize_u64(self.micros());
+        }
+        // Times are whole microseconds, so this always finds a unit
+        let s = with_unit(self.micros() as f64, &TIME_UNITS)
+            .unwrap_or_else(|| format!("{}us", self.micros()));
+        serializer.serialize_str(&s)
+    }
+}
+
+impl JsonSchema for Time {
+    fn schema_name() -> String {
+        "Time".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        unit_schema(
+            "Time in microseconds, or a string with a unit such as \"50ms\"",
+            &TIME_UNITS,
+        )
+    }
+}
+
+/// Accepts non-negative numbers in the base unit, or strings with units. `parse` converts strings
+/// to the base unit
+struct UnitVisitor {
+    expecting: &'static str,
+    parse: fn(&str) -> Result<f64, String>,
+}
+
+impl<'de> Visitor<'de> for UnitVisitor {
+    type Value = f64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<f64, E> {
+        Ok(v as f64)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<f64, E> {
+        if v < 0 {
+            return Err(E::custom(format!("{} must not be negative", v)));
+        }
+        Ok(v as f64)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<f64, E> {
+        Ok(v)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<f64, E> {
+        (self.parse)(v).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Time {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let micros = deserializer.deserialize_any(UnitVisitor {
+            expecting: "a time in microseconds or a string like \"50ms\" or \"100s\"",
+            parse: |s| parse(s, &TIME_UNITS, "time"),
+        })?;
+        if !micros.is_finite() || micros < 0. {
+            return Err(de::Error::custom(format!("invalid time {}", micros)));
+        }
+        Ok(Time::from_micros(micros.round() as u64))
+    }
+}
+
+/// Use with `#[serde(with = "crate::units::rate")]` on rates stored as an `f64` in bytes per
+/// second
+pub mod rate {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(val: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match with_unit(*val, &RATE_UNITS) {
+            Some(s) if *val > 0. && WITH_UNITS.with(Cell::get) => serializer.serialize_str(&s),
+            _ => serializer.serialize_f64(*val),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        deserializer.deserialize_any(UnitVisitor {
+            expecting: "a rate in bytes per second or a string like \"12Mbps\" or \"1.5MBps\"",
+            parse: |s| parse(s, &RATE_UNITS, "rate"),
+        })
+    }
+}
+
+/// Use with `#[serde(with = "crate::units::bytes")]` on sizes stored as a `u64` in bytes
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(val: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        match with_unit(*val as f64, &SIZE_UNITS) {
+            Some(s) if *val > 0 && WITH_UNITS.with(Cell::get) => serializer.serialize_str(&s),
+            _ => serializer.serialize_u64(*val),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let val = deserializer.deserialize_any(UnitVisitor {
+            expecting: "a size in bytes or a string like \"1500B\" or \"1.5MB\"",
+            parse: |s| parse(s, &SIZE_UNITS, "size"),
+        })?;
+        Ok(val.round() as u64)
+    }
+}
+
+/// A rate in bytes per second, serialized like `rate`. Use it inside containers, or as
+/// `#[schemars(with = "crate::units::Rate")]` to describe fields that use `rate`
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Rate(#[serde(with = "rate")] pub f64);
+
+impl JsonSchema for Rate {
+    fn schema_name() -> String {
+        "Rate".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        unit_schema(
+            "Rate in bytes per second, or a string with a unit such as \"12Mbps\"",
+            &RATE_UNITS,
+        )
+    }
+}
+
+/// A size in bytes, serialized like `bytes`. Use it as
+/// `#[schemars(with = "crate::units::ByteSize")]` to describe fields that use `bytes`
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ByteSize(#[serde(with = "bytes")] pub u64);
+
+impl JsonSchema for ByteSize {
+    fn schema_name() -> String {
+        "ByteSize".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        unit_schema(
+            "Size in bytes, or a string with a unit such as \"1.5MB\"",
+            &SIZE_UNITS,
+        )
+    }
+}
+
+/// Like `rate`, for `Option<f64>`
+pub mod rate_opt {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(val: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+        val.map(Rate).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f64>, D::Error> {
+        Ok(Option::<Rate>::deserialize(deserializer)?.map(|r| r.0))
+    }
+}
+
+/// Like `rate`, for `Vec<f64>`
+pub mod rate_vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(val: &[f64], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(val.iter().map(|r| Rate(*r)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f64>, D::Error> {
+        Ok(Vec::<Rate>::deserialize(deserializer)?
+            .into_iter()
+            .map(|r| r.0)
+            .collect())
+    }
+}
+
+/// Like `rate`, for a list of (rate, duration) pairs
+pub mod rate_schedule {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(val: &[(f64, Time)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(val.iter().map(|(r, t)| (Rate(*r), *t)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(f64, Time)>, D::Error> {
+        Ok(Vec::<(Rate, Time)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(r, t)| (r.0, t))
+            .collect())
+    }
+}
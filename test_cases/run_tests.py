@@ -40,9 +40,7 @@ class Config:
             "sender_groups": [
               {
                 "num_senders": 1,
-                "delay": {
-                  "Const": 50000
-                },
+                "delay": 50000,
                 "agg_intersend": {
                   "Const": 0
                 },
@@ -192,8 +190,8 @@ class Aggregated(Experiment):
         if randint(0, 1):
             agg_rv = {"Const": randint(1000, 100000)}
         else:
-            agg_rv = {"Poisson": randint(1000, 100000)}
-        config.config["topo"]["sender_groups"][0]["agg_delay"] = agg_rv
+            agg_rv = {"Exponential": 1 / randint(1000, 100000)}
+        config.config["topo"]["sender_groups"][0]["agg_intersend"] = agg_rv
         return config
 
 
@@ -215,7 +213,7 @@ def run_simulation(config: Config) -> SimStats:
     # We need the output to be in stdout
 
     print(config.config)
-    sim_res = subprocess.run(["cargo", "run", "--release", "--", "stdin"],
+    sim_res = subprocess.run(["cargo", "run", "--release", "--", "run", "-"],
                              capture_output=True, text=True,
                              input=json.dumps(config.config))
 